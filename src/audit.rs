@@ -0,0 +1,450 @@
+use crate::auth::{AuthMethod, Caller};
+use crate::config::Config;
+use crate::privacy::MaskPhoneNumbers;
+use crate::twilio::{AlertResult, DialNumberResult, OverallResult};
+use crate::{AlertInfo, AlertTarget};
+use axum::extract::ConnectInfo;
+use axum::http::HeaderMap;
+use serde::Serialize;
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+
+/// Target of the tracing events that mirror the audit log, so that audit events can be filtered
+/// and shipped separately from the rest of the logs
+pub static AUDIT_TRACING_TARGET: &str = "audit";
+
+static FORWARDED_FOR_HEADER: &str = "x-forwarded-for";
+/// Shown as caller of requests that were rejected because of missing or invalid credentials
+static UNAUTHENTICATED_CALLER: &str = "unauthenticated";
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum AuditAction {
+    Lookup,
+    Alert,
+    /// Start of an escalation, the calls of the individual steps are separate `alert` events
+    Escalate,
+    Acknowledge,
+    /// Closing an OpsGenie alert
+    Close,
+    CreateOverride,
+    DeleteOverride,
+    /// A request that was rejected because the caller couldn't be authenticated
+    Authenticate,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum AuditOutcome {
+    Success,
+    PartialSuccess,
+    Failure,
+}
+
+impl From<&OverallResult> for AuditOutcome {
+    fn from(result: &OverallResult) -> Self {
+        match result {
+            OverallResult::Success => AuditOutcome::Success,
+            OverallResult::PartialSuccess => AuditOutcome::PartialSuccess,
+            OverallResult::Failure => AuditOutcome::Failure,
+        }
+    }
+}
+
+/// One line in the audit log, describing a single request from start to finish
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AuditEvent {
+    timestamp: String,
+    action: AuditAction,
+    caller: String,
+    auth_method: AuthMethod,
+    /// Address of the peer that opened the connection, usually a proxy or load balancer
+    peer_address: Option<String>,
+    /// Raw `X-Forwarded-For` header, this is set by the client or proxies and can't be trusted
+    /// any more than they can
+    forwarded_for: Option<String>,
+    /// Not set when acting on an OpsGenie alert by its alias, as the alert doesn't tell who it
+    /// was for
+    #[serde(skip_serializing_if = "Option::is_none")]
+    target: Option<AlertTarget>,
+    /// Only set for requests that were rejected before they reached an endpoint
+    #[serde(skip_serializing_if = "Option::is_none")]
+    path: Option<String>,
+    /// Identifies the escalation this event belongs to, see [`crate::escalation`]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    escalation_key: Option<String>,
+    /// Index of the escalation step that was called
+    #[serde(skip_serializing_if = "Option::is_none")]
+    escalation_step: Option<usize>,
+    /// Seconds the call was delayed by the notification rules of the people called, see
+    /// [`crate::notification`]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    delayed_seconds: Option<u64>,
+    /// Alias of the OpsGenie alert that was created or changed, see [`crate::incident`]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    opsgenie_alias: Option<String>,
+    /// Names of the people that were found to be on call
+    resolved_users: Vec<String>,
+    /// The numbers that were rung and whether that worked, only set for alerts
+    dialed_numbers: Vec<DialNumberResult>,
+    outcome: AuditOutcome,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl AuditEvent {
+    pub fn new(
+        action: AuditAction,
+        caller: &Caller,
+        connect_info: Option<ConnectInfo<SocketAddr>>,
+        headers: &HeaderMap,
+        target: &AlertTarget,
+    ) -> Self {
+        Self::with_target(action, caller, connect_info, headers, Some(target.clone()))
+    }
+
+    /// An event about an OpsGenie alert that is only known by its alias
+    pub fn for_opsgenie_alert(
+        action: AuditAction,
+        caller: &Caller,
+        connect_info: Option<ConnectInfo<SocketAddr>>,
+        headers: &HeaderMap,
+        alias: &str,
+    ) -> Self {
+        let mut event = Self::with_target(action, caller, connect_info, headers, None);
+        event.opsgenie_alert(alias);
+        event
+    }
+
+    /// An event about an escalation that is only known by its key, e.g. because it is over
+    pub fn for_escalation(
+        action: AuditAction,
+        caller: &Caller,
+        connect_info: Option<ConnectInfo<SocketAddr>>,
+        headers: &HeaderMap,
+        key: &str,
+    ) -> Self {
+        let mut event = Self::with_target(action, caller, connect_info, headers, None);
+        event.escalation(key);
+        event
+    }
+
+    /// An event about a request whose caller couldn't be authenticated, `auth_method` is the
+    /// method they attempted
+    pub fn unauthenticated(
+        auth_method: AuthMethod,
+        connect_info: Option<ConnectInfo<SocketAddr>>,
+        headers: &HeaderMap,
+        path: &str,
+    ) -> Self {
+        let caller = Caller {
+            name: UNAUTHENTICATED_CALLER.to_string(),
+            method: auth_method,
+            scopes: HashSet::new(),
+        };
+        let mut event = Self::with_target(
+            AuditAction::Authenticate,
+            &caller,
+            connect_info,
+            headers,
+            None,
+        );
+        event.path = Some(path.to_string());
+        event
+    }
+
+    fn with_target(
+        action: AuditAction,
+        caller: &Caller,
+        connect_info: Option<ConnectInfo<SocketAddr>>,
+        headers: &HeaderMap,
+        target: Option<AlertTarget>,
+    ) -> Self {
+        AuditEvent {
+            timestamp: chrono::Utc::now().to_rfc3339(),
+            action,
+            caller: caller.name.clone(),
+            auth_method: caller.method,
+            peer_address: connect_info.map(|ConnectInfo(address)| address.to_string()),
+            forwarded_for: headers
+                .get(FORWARDED_FOR_HEADER)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string),
+            target,
+            path: None,
+            escalation_key: None,
+            escalation_step: None,
+            delayed_seconds: None,
+            opsgenie_alias: None,
+            resolved_users: Vec::new(),
+            dialed_numbers: Vec::new(),
+            outcome: AuditOutcome::Failure,
+            error: None,
+        }
+    }
+
+    pub fn escalation(&mut self, key: &str) {
+        self.escalation_key = Some(key.to_string());
+    }
+
+    pub fn opsgenie_alert(&mut self, alias: &str) {
+        self.opsgenie_alias = Some(alias.to_string());
+    }
+
+    /// Turn the event of the request that started an escalation into the event for calling one
+    /// of its steps
+    pub fn escalation_step(&mut self, step: usize) {
+        self.timestamp = chrono::Utc::now().to_rfc3339();
+        self.action = AuditAction::Alert;
+        self.escalation_step = Some(step);
+    }
+
+    /// Turn the event of the request that triggered an alert into the event for a call that was
+    /// delayed by notification rules
+    pub fn delayed_call(&mut self, after_seconds: u64) {
+        self.timestamp = chrono::Utc::now().to_rfc3339();
+        self.action = AuditAction::Alert;
+        self.delayed_seconds = Some(after_seconds);
+    }
+
+    pub fn resolved(&mut self, alert_info: &AlertInfo) {
+        self.resolved_users = alert_info
+            .full_information
+            .iter()
+            .map(|person| person.name.clone())
+            .collect();
+    }
+
+    pub fn dialed(&mut self, alert_result: &AlertResult) {
+        self.dialed_numbers = alert_result.detailed_result.clone();
+        self.outcome = AuditOutcome::from(&alert_result.overall_result);
+    }
+
+    /// Set the outcome from the final result of the request, for alerts the outcome of the calls
+    /// recorded by [`AuditEvent::dialed`] is kept
+    pub fn finish<T, E: std::error::Error>(&mut self, result: &Result<T, E>) {
+        match result {
+            Ok(_) if !self.dialed_numbers.is_empty() => {}
+            Ok(_) => self.outcome = AuditOutcome::Success,
+            Err(e) => {
+                self.outcome = AuditOutcome::Failure;
+                self.error = Some(e.to_string());
+            }
+        }
+    }
+}
+
+impl MaskPhoneNumbers for AuditEvent {
+    fn mask_phone_numbers(&mut self) {
+        self.dialed_numbers
+            .iter_mut()
+            .for_each(MaskPhoneNumbers::mask_phone_numbers);
+    }
+}
+
+/// Appends [`AuditEvent`]s to the file configured in `WYGC_AUDIT_LOG_FILE` and mirrors them to
+/// the [`AUDIT_TRACING_TARGET`] tracing target.
+///
+/// The file is opened lazily and reopened if the path changes on a config reload.
+#[derive(Debug, Clone, Default)]
+pub struct AuditLog {
+    file: Arc<Mutex<Option<OpenAuditFile>>>,
+}
+
+#[derive(Debug)]
+struct OpenAuditFile {
+    path: PathBuf,
+    file: File,
+}
+
+impl AuditLog {
+    /// Record an event.
+    ///
+    /// Failing to write the audit log doesn't fail the request, refusing to ring someone because
+    /// we couldn't write down that we did would be the wrong way around. Failures are logged
+    /// instead.
+    pub async fn record(&self, mut event: AuditEvent, config: &Config) {
+        // Phone numbers follow the same rules as in all other logs, the resolved users are enough
+        // to tell who was notified
+        if config.mask_phone_numbers {
+            event.mask_phone_numbers();
+        }
+
+        let line = match serde_json::to_string(&event) {
+            Ok(line) => line,
+            Err(e) => {
+                tracing::error!(
+                    error = &e as &dyn std::error::Error,
+                    ?event,
+                    "Failed to serialize audit event"
+                );
+                return;
+            }
+        };
+        tracing::info!(target: AUDIT_TRACING_TARGET, event = line, "Audit event");
+
+        if let Some(path) = &config.audit_log_file {
+            if let Err(e) = self.append(path, &line).await {
+                tracing::error!(
+                    error = &e as &dyn std::error::Error,
+                    path = %path.display(),
+                    event = line,
+                    "Failed to write audit log"
+                );
+            }
+        }
+    }
+
+    async fn append(&self, path: &Path, line: &str) -> std::io::Result<()> {
+        let mut open_file = self.file.lock().await;
+        if open_file.as_ref().map(|open_file| open_file.path.as_path()) != Some(path) {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .await?;
+            *open_file = Some(OpenAuditFile {
+                path: path.to_path_buf(),
+                file,
+            });
+        }
+
+        let file = &mut open_file
+            .as_mut()
+            .expect("audit file was opened above")
+            .file;
+        // A single write per event, so that lines are never interleaved
+        let result = async {
+            file.write_all(format!("{line}\n").as_bytes()).await?;
+            file.flush().await
+        }
+        .await;
+        if result.is_err() {
+            // Open the file again on the next event, in case it was rotated away underneath us
+            *open_file = None;
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{AuditAction, AuditEvent};
+    use crate::auth::{AuthMethod, Caller};
+    use crate::opsgenie::{Contact, ContactMethod, ContactSource, UserContacts};
+    use crate::privacy::MaskPhoneNumbers;
+    use crate::twilio::{AlertResult, DialNumberResult, OverallResult};
+    use crate::{AlertInfo, AlertTarget, Schedule, ScheduleRequestByName};
+    use axum::http::HeaderMap;
+    use serde_json::json;
+    use std::collections::HashSet;
+
+    fn event() -> AuditEvent {
+        let caller = Caller {
+            name: "grafana".to_string(),
+            method: AuthMethod::StaticToken,
+            scopes: HashSet::new(),
+        };
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", "192.0.2.1".parse().unwrap());
+        let target = AlertTarget::Schedule(Schedule::ScheduleByName(ScheduleRequestByName {
+            name: "ops".to_string(),
+        }));
+        AuditEvent::new(AuditAction::Alert, &caller, None, &headers, &target)
+    }
+
+    #[test]
+    fn test_alert_event() {
+        let mut event = event();
+        event.resolved(&AlertInfo {
+            username: "jane".to_string(),
+            phone_number: "+49123456123".to_string(),
+            full_information: vec![UserContacts {
+                name: "jane".to_string(),
+                contacts: vec![Contact {
+                    method: ContactMethod::Voice,
+                    address: "+49123456123".to_string(),
+                    enabled: true,
+                    source: ContactSource::Opsgenie,
+                    error: None,
+                    quiet: false,
+                    send_after_seconds: 0,
+                }],
+                notification_rule: None,
+            }],
+            shift_start: None,
+            shift_end: None,
+            stale: false,
+            failed_lookups: Vec::new(),
+        });
+        event.dialed(&AlertResult {
+            overall_result: OverallResult::Success,
+            detailed_result: vec![DialNumberResult::Success {
+                number: "+49123456123".to_string(),
+            }],
+        });
+        event.finish::<_, std::io::Error>(&Ok(()));
+        event.mask_phone_numbers();
+
+        let mut serialized = serde_json::to_value(&event).unwrap();
+        serialized.as_object_mut().unwrap().remove("timestamp");
+        assert_eq!(
+            serialized,
+            json!({
+                "action": "alert",
+                "caller": "grafana",
+                "authMethod": "staticToken",
+                "peerAddress": null,
+                "forwardedFor": "192.0.2.1",
+                "target": {"schedule": {"name": "ops"}},
+                "resolvedUsers": ["jane"],
+                "dialedNumbers": [{"success": {"number": "+49******123"}}],
+                "outcome": "success",
+            })
+        );
+    }
+
+    #[test]
+    fn test_failed_event() {
+        let mut event = event();
+        event.finish::<(), _>(&Err(std::io::Error::other("OpsGenie is down")));
+
+        let serialized = serde_json::to_value(&event).unwrap();
+        assert_eq!(serialized["outcome"], "failure");
+        assert_eq!(serialized["error"], "OpsGenie is down");
+        assert_eq!(serialized["resolvedUsers"], json!([]));
+    }
+
+    #[test]
+    fn test_unauthenticated_event() {
+        let mut event =
+            AuditEvent::unauthenticated(AuthMethod::StaticToken, None, &HeaderMap::new(), "/alert");
+        event.finish::<(), _>(&Err(std::io::Error::other(
+            "the provided bearer token is not valid",
+        )));
+
+        let mut serialized = serde_json::to_value(&event).unwrap();
+        serialized.as_object_mut().unwrap().remove("timestamp");
+        assert_eq!(
+            serialized,
+            json!({
+                "action": "authenticate",
+                "caller": "unauthenticated",
+                "authMethod": "staticToken",
+                "peerAddress": null,
+                "forwardedFor": null,
+                "path": "/alert",
+                "resolvedUsers": [],
+                "dialedNumbers": [],
+                "outcome": "failure",
+                "error": "the provided bearer token is not valid",
+            })
+        );
+    }
+}
@@ -0,0 +1,487 @@
+use crate::audit::AuditEvent;
+use crate::auth::error::{
+    InvalidSignatureSnafu, InvalidTimestampSnafu, InvalidTokenSnafu, JwtSnafu,
+    MalformedHeaderSnafu, MissingCredentialsSnafu, MissingScopeSnafu, ReadBodySnafu,
+    SignatureExpiredSnafu, UnknownKeySnafu,
+};
+use crate::config::{ApiCredential, AuthConfig};
+use crate::oidc::{validate_jwt, JwtIdentity};
+use crate::{http_error, oidc, AppState};
+use axum::body::{to_bytes, Body};
+use axum::extract::{ConnectInfo, Request, State};
+use axum::http::request::Parts;
+use axum::http::{HeaderMap, HeaderName, StatusCode};
+use axum::middleware::Next;
+use axum::response::Response;
+use hmac::{Hmac, Mac};
+use hyper::header::AUTHORIZATION;
+use secrecy::ExposeSecret;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use snafu::{OptionExt, ResultExt, Snafu};
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+use subtle::ConstantTimeEq;
+
+pub static HMAC_KEY_ID_HEADER: HeaderName = HeaderName::from_static("x-wygc-key-id");
+pub static HMAC_TIMESTAMP_HEADER: HeaderName = HeaderName::from_static("x-wygc-timestamp");
+pub static HMAC_SIGNATURE_HEADER: HeaderName = HeaderName::from_static("x-wygc-signature");
+
+// Signed requests are rejected if their timestamp is further than this away from our clock, so
+// captured requests can't be replayed later on
+static HMAC_MAX_CLOCK_SKEW_SECONDS: u64 = 300;
+
+// Requests we receive are tiny, this only protects against someone sending huge bodies to make us
+// buffer them for signature verification
+static MAX_SIGNED_BODY_BYTES: usize = 1024 * 1024;
+
+/// What an authenticated caller is allowed to do
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Scope {
+    /// Look up who is on call, `/whosoncall`
+    ReadOnCall,
+    /// Ring people, `/alert`
+    TriggerAlert,
+    /// See full phone numbers in responses even if they are masked for everyone else
+    ReadPhoneNumbers,
+    /// Administrative endpoints that expose internals of the service
+    Admin,
+    /// Create and delete overrides of schedules, `/schedules/<schedule>/overrides`
+    ManageOverrides,
+}
+
+impl Scope {
+    pub fn all() -> HashSet<Scope> {
+        HashSet::from([
+            Scope::ReadOnCall,
+            Scope::TriggerAlert,
+            Scope::ReadPhoneNumbers,
+            Scope::Admin,
+            Scope::ManageOverrides,
+        ])
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum AuthMethod {
+    Anonymous,
+    StaticToken,
+    Hmac,
+    Jwt,
+}
+
+/// The authenticated identity of whoever sent the current request.
+///
+/// Added to the request extensions by [`authenticate`], handlers check the scope they need via
+/// [`Caller::require`].
+#[derive(Debug, Clone)]
+pub struct Caller {
+    pub name: String,
+    pub method: AuthMethod,
+    pub scopes: HashSet<Scope>,
+}
+
+impl Caller {
+    fn anonymous() -> Self {
+        Caller {
+            name: "anonymous".to_string(),
+            method: AuthMethod::Anonymous,
+            scopes: Scope::all(),
+        }
+    }
+
+    fn from_credential(credential: &ApiCredential, method: AuthMethod) -> Self {
+        Caller {
+            name: credential.name.clone(),
+            method,
+            scopes: credential.scopes.clone(),
+        }
+    }
+
+    fn from_jwt_identity(identity: JwtIdentity) -> Self {
+        Caller {
+            name: identity.username,
+            method: AuthMethod::Jwt,
+            scopes: identity.scopes,
+        }
+    }
+
+    pub fn require(&self, scope: Scope) -> Result<(), Error> {
+        if self.scopes.contains(&scope) {
+            Ok(())
+        } else {
+            MissingScopeSnafu {
+                caller: &self.name,
+                scope,
+            }
+            .fail()
+        }
+    }
+}
+
+#[derive(Snafu, Debug)]
+#[snafu(module)]
+pub enum Error {
+    #[snafu(display("request carries no credentials"))]
+    MissingCredentials {},
+
+    #[snafu(display("the provided bearer token is not valid"))]
+    InvalidToken {},
+
+    #[snafu(display("header [{header}] is malformed"))]
+    MalformedHeader { header: String },
+
+    #[snafu(display("unknown signing key [{key_id}]"))]
+    UnknownKey { key_id: String },
+
+    #[snafu(display("timestamp in [{HMAC_TIMESTAMP_HEADER}] is not a unix timestamp"))]
+    InvalidTimestamp { source: std::num::ParseIntError },
+
+    #[snafu(display(
+        "signature timestamp is more than {HMAC_MAX_CLOCK_SKEW_SECONDS} seconds away from the server time"
+    ))]
+    SignatureExpired {},
+
+    #[snafu(display("request signature does not match"))]
+    InvalidSignature {},
+
+    #[snafu(display("failed to read request body for signature verification"))]
+    ReadBody { source: axum::Error },
+
+    #[snafu(display("[{caller}] is not allowed to {scope:?}"))]
+    MissingScope { caller: String, scope: Scope },
+
+    #[snafu(display("JWT authentication failed: \n{source}"))]
+    Jwt { source: oidc::Error },
+}
+
+impl http_error::Error for Error {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            Error::MissingCredentials { .. } => StatusCode::UNAUTHORIZED,
+            Error::InvalidToken { .. } => StatusCode::UNAUTHORIZED,
+            Error::MalformedHeader { .. } => StatusCode::UNAUTHORIZED,
+            Error::UnknownKey { .. } => StatusCode::UNAUTHORIZED,
+            Error::InvalidTimestamp { .. } => StatusCode::UNAUTHORIZED,
+            Error::SignatureExpired { .. } => StatusCode::UNAUTHORIZED,
+            Error::InvalidSignature { .. } => StatusCode::UNAUTHORIZED,
+            Error::ReadBody { .. } => StatusCode::BAD_REQUEST,
+            Error::MissingScope { .. } => StatusCode::FORBIDDEN,
+            Error::Jwt { source } => source.status_code(),
+        }
+    }
+}
+
+/// Middleware that authenticates the caller and stores the resulting [`Caller`] in the request
+/// extensions.
+///
+/// Requests are authenticated by the first of these that is present:
+///  1. An HMAC signature in [`HMAC_SIGNATURE_HEADER`]
+///  2. A bearer token in the `AUTHORIZATION` header, which is either one of the static tokens or
+///     a JWT issued by the configured identity provider
+///  3. Nothing at all, which is only accepted if anonymous access is enabled
+///
+/// Authorization happens later in the handlers, as only they know which scope they need.
+///
+/// Rejected requests are recorded in the audit log, as they don't reach any handler.
+pub async fn authenticate(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Result<Response, http_error::JsonResponse<Error>> {
+    let config = state.config.current();
+    let auth_config = &config.auth_config;
+
+    let (parts, body) = request.into_parts();
+
+    let (caller, body) = match authenticate_parts(&state, auth_config, &parts, body).await {
+        Ok(authenticated) => authenticated,
+        Err(e) => {
+            tracing::info!(
+                error = &e as &dyn std::error::Error,
+                "Rejecting unauthenticated request"
+            );
+            let mut audit_event = AuditEvent::unauthenticated(
+                attempted_method(auth_config, &parts.headers),
+                parts.extensions.get::<ConnectInfo<SocketAddr>>().cloned(),
+                &parts.headers,
+                parts.uri.path(),
+            );
+            audit_event.finish(&Err::<(), _>(&e));
+            state.audit_log.record(audit_event, &config).await;
+            return Err(e.into());
+        }
+    };
+
+    tracing::debug!(caller.name, ?caller.method, "Authenticated request");
+
+    let mut request = Request::from_parts(parts, body);
+    request.extensions_mut().insert(caller);
+    Ok(next.run(request).await)
+}
+
+async fn authenticate_parts(
+    state: &AppState,
+    auth_config: &AuthConfig,
+    parts: &Parts,
+    body: Body,
+) -> Result<(Caller, Body), Error> {
+    if parts.headers.contains_key(&HMAC_SIGNATURE_HEADER) {
+        // The body is part of the signature, so we need to buffer it and put it back afterwards
+        let body = to_bytes(body, MAX_SIGNED_BODY_BYTES)
+            .await
+            .context(ReadBodySnafu)?;
+        let caller = verify_signature(auth_config, parts, &body)?;
+        Ok((caller, Body::from(body)))
+    } else if let Some(token) = bearer_token(&parts.headers)? {
+        let caller = match (verify_token(auth_config, token), &auth_config.oidc) {
+            (Ok(caller), _) => caller,
+            (Err(_), Some(oidc_config)) if looks_like_jwt(token) => {
+                validate_jwt(token, &state.http, &state.jwks_cache, oidc_config)
+                    .await
+                    .map(Caller::from_jwt_identity)
+                    .context(JwtSnafu)?
+            }
+            (Err(e), _) => return Err(e),
+        };
+        Ok((caller, body))
+    } else if auth_config.allow_anonymous {
+        Ok((Caller::anonymous(), body))
+    } else {
+        MissingCredentialsSnafu.fail()
+    }
+}
+
+/// How the caller tried to authenticate, checked in the same order as in [`authenticate`]
+fn attempted_method(auth_config: &AuthConfig, headers: &HeaderMap) -> AuthMethod {
+    if headers.contains_key(&HMAC_SIGNATURE_HEADER) {
+        AuthMethod::Hmac
+    } else if let Ok(Some(token)) = bearer_token(headers) {
+        if auth_config.oidc.is_some() && looks_like_jwt(token) {
+            AuthMethod::Jwt
+        } else {
+            AuthMethod::StaticToken
+        }
+    } else {
+        AuthMethod::Anonymous
+    }
+}
+
+fn bearer_token(headers: &HeaderMap) -> Result<Option<&str>, Error> {
+    let Some(value) = headers.get(AUTHORIZATION) else {
+        return Ok(None);
+    };
+    value
+        .to_str()
+        .ok()
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|token| Some(token.trim()))
+        .context(MalformedHeaderSnafu {
+            header: AUTHORIZATION.as_str(),
+        })
+}
+
+/// JWTs are three base64 encoded segments separated by dots
+fn looks_like_jwt(token: &str) -> bool {
+    token.split('.').count() == 3
+}
+
+fn verify_token(auth_config: &AuthConfig, token: &str) -> Result<Caller, Error> {
+    // Compare against every configured token in constant time, so the response time doesn't
+    // reveal how much of a token was guessed correctly
+    let mut matched = None;
+    for credential in &auth_config.api_tokens {
+        if bool::from(
+            credential
+                .secret
+                .expose_secret()
+                .as_bytes()
+                .ct_eq(token.as_bytes()),
+        ) {
+            matched = Some(credential);
+        }
+    }
+
+    matched
+        .map(|credential| Caller::from_credential(credential, AuthMethod::StaticToken))
+        .context(InvalidTokenSnafu)
+}
+
+/// Verify a request signed with one of the configured HMAC keys.
+///
+/// The signature is the hex encoded HMAC-SHA256 over `{timestamp}.{method}.{path and query}.{body}`
+/// and is sent as `sha256=<signature>`, the name of the key that was used goes into
+/// [`HMAC_KEY_ID_HEADER`].
+fn verify_signature(auth_config: &AuthConfig, parts: &Parts, body: &[u8]) -> Result<Caller, Error> {
+    let key_id = header_str(&parts.headers, &HMAC_KEY_ID_HEADER)?;
+    let timestamp = header_str(&parts.headers, &HMAC_TIMESTAMP_HEADER)?;
+    let signature = header_str(&parts.headers, &HMAC_SIGNATURE_HEADER)?;
+
+    let credential = auth_config
+        .hmac_keys
+        .iter()
+        .find(|credential| credential.name == key_id)
+        .context(UnknownKeySnafu { key_id })?;
+
+    let signed_at = timestamp.parse::<u64>().context(InvalidTimestampSnafu)?;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    if now.abs_diff(signed_at) > HMAC_MAX_CLOCK_SKEW_SECONDS {
+        return SignatureExpiredSnafu.fail();
+    }
+
+    let signature = signature
+        .strip_prefix("sha256=")
+        .and_then(|signature| hex::decode(signature).ok())
+        .context(MalformedHeaderSnafu {
+            header: HMAC_SIGNATURE_HEADER.as_str(),
+        })?;
+
+    let path_and_query = parts
+        .uri
+        .path_and_query()
+        .map(|path_and_query| path_and_query.as_str())
+        .unwrap_or_else(|| parts.uri.path());
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(credential.secret.expose_secret().as_bytes())
+        .expect("HMAC can take keys of any size");
+    mac.update(format!("{timestamp}.{}.{path_and_query}.", parts.method).as_bytes());
+    mac.update(body);
+    mac.verify_slice(&signature)
+        .ok()
+        .context(InvalidSignatureSnafu)?;
+
+    Ok(Caller::from_credential(credential, AuthMethod::Hmac))
+}
+
+fn header_str<'a>(headers: &'a HeaderMap, header: &HeaderName) -> Result<&'a str, Error> {
+    headers
+        .get(header)
+        .and_then(|value| value.to_str().ok())
+        .context(MalformedHeaderSnafu {
+            header: header.as_str(),
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        attempted_method, verify_signature, verify_token, AuthMethod, Scope, HMAC_KEY_ID_HEADER,
+        HMAC_SIGNATURE_HEADER, HMAC_TIMESTAMP_HEADER,
+    };
+    use crate::config::{ApiCredential, AuthConfig, JwksSource, OidcConfig};
+    use axum::http::{HeaderMap, Request};
+    use hmac::{Hmac, Mac};
+    use rstest::rstest;
+    use secrecy::Secret;
+    use sha2::Sha256;
+    use std::collections::HashSet;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    fn auth_config() -> AuthConfig {
+        let credential = ApiCredential {
+            name: "monitoring".to_string(),
+            secret: Secret::new("hunter2".to_string()),
+            scopes: HashSet::from([Scope::TriggerAlert]),
+        };
+        AuthConfig {
+            allow_anonymous: false,
+            api_tokens: vec![credential.clone()],
+            hmac_keys: vec![credential],
+            oidc: None,
+        }
+    }
+
+    fn sign(key: &str, payload: &str) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(key.as_bytes()).unwrap();
+        mac.update(payload.as_bytes());
+        format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+    }
+
+    #[rstest]
+    #[case("hunter2", true)]
+    #[case("hunter3", false)]
+    #[case("", false)]
+    fn test_verify_token(#[case] token: &str, #[case] valid: bool) {
+        let result = verify_token(&auth_config(), token);
+        assert_eq!(result.is_ok(), valid);
+        if let Ok(caller) = result {
+            assert_eq!(caller.name, "monitoring");
+            assert_eq!(caller.method, AuthMethod::StaticToken);
+        }
+    }
+
+    #[rstest]
+    // Order of columns: key id, signing key, signed body, sent body, age of signature, valid
+    #[case("monitoring", "hunter2", "{}", "{}", 0, true)]
+    #[case("monitoring", "hunter2", "{}", "{}", 200, true)]
+    #[case("monitoring", "hunter2", "{}", "{}", 400, false)]
+    #[case("monitoring", "hunter2", "{}", "{\"evil\":1}", 0, false)]
+    #[case("monitoring", "hunter3", "{}", "{}", 0, false)]
+    #[case("someone", "hunter2", "{}", "{}", 0, false)]
+    fn test_verify_signature(
+        #[case] key_id: &str,
+        #[case] key: &str,
+        #[case] signed_body: &str,
+        #[case] sent_body: &str,
+        #[case] age: u64,
+        #[case] valid: bool,
+    ) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            - age;
+        let signature = sign(
+            key,
+            &format!("{timestamp}.POST./alert?name=ops.{signed_body}"),
+        );
+
+        let (parts, _) = Request::builder()
+            .method("POST")
+            .uri("/alert?name=ops")
+            .header(&HMAC_KEY_ID_HEADER, key_id)
+            .header(&HMAC_TIMESTAMP_HEADER, timestamp.to_string())
+            .header(&HMAC_SIGNATURE_HEADER, signature)
+            .body(())
+            .unwrap()
+            .into_parts();
+
+        let result = verify_signature(&auth_config(), &parts, sent_body.as_bytes());
+        assert_eq!(result.is_ok(), valid);
+    }
+
+    #[rstest]
+    #[case(&[], false, AuthMethod::Anonymous)]
+    #[case(&[("authorization", "Bearer hunter2")], false, AuthMethod::StaticToken)]
+    #[case(&[("authorization", "Bearer a.b.c")], false, AuthMethod::StaticToken)]
+    #[case(&[("authorization", "Bearer a.b.c")], true, AuthMethod::Jwt)]
+    #[case(&[("authorization", "Basic aHVudGVyMg==")], true, AuthMethod::Anonymous)]
+    #[case(&[("authorization", "Bearer a.b.c"), ("x-wygc-signature", "sha256=00")], true, AuthMethod::Hmac)]
+    fn test_attempted_method(
+        #[case] headers: &[(&'static str, &'static str)],
+        #[case] oidc: bool,
+        #[case] expected: AuthMethod,
+    ) {
+        let mut auth_config = auth_config();
+        auth_config.oidc = oidc.then(|| OidcConfig {
+            issuer: "https://idp.example.com".to_string(),
+            audience: "whoyougonnacall".to_string(),
+            jwks_source: JwksSource::File("/dev/null".into()),
+            jwks_cache_ttl: std::time::Duration::from_secs(60),
+            username_claim: "preferred_username".to_string(),
+            groups_claim: "groups".to_string(),
+            group_scopes: Default::default(),
+        });
+        let headers = headers
+            .iter()
+            .map(|(name, value)| (name.parse().unwrap(), value.parse().unwrap()))
+            .collect::<HeaderMap>();
+        assert_eq!(attempted_method(&auth_config, &headers), expected);
+    }
+}
@@ -0,0 +1,294 @@
+use crate::opsgenie::{Contact, UserNotificationRules};
+use crate::{util, Schedule};
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use std::future::Future;
+use std::hash::Hash;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+
+/// What was last looked up in OpsGenie, so that repeated lookups don't need to ask OpsGenie again
+/// and so that people can still be paged while OpsGenie can't be reached.
+///
+/// The cache lives next to the config rather than in it, so that it survives config reloads. It
+/// is flushed when a reload points at another OpsGenie account, see [`crate::reload::watch`]. It
+/// can also be written to disk to survive restarts, see [`crate::snapshot`].
+#[derive(Debug, Clone, Default)]
+pub struct OpsgenieCache {
+    /// Usernames of the people currently on call, by schedule
+    pub on_call: TtlCache<Schedule, Vec<String>>,
+    /// Ids of schedules that were requested by name, by name. These are not part of the snapshot.
+    pub schedule_ids: TtlCache<String, String>,
+    /// Contacts, by username
+    pub contacts: TtlCache<String, Vec<Contact>>,
+    /// Notification rules, by username. These are not part of the snapshot, without them
+    /// everyone is called on all of their numbers.
+    pub notification_rules: TtlCache<String, UserNotificationRules>,
+}
+
+impl OpsgenieCache {
+    /// Forget everything that was looked up
+    pub fn clear(&self) {
+        self.on_call.clear();
+        self.schedule_ids.clear();
+        self.contacts.clear();
+        self.notification_rules.clear();
+    }
+}
+
+/// A value from a [`TtlCache`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Lookup<V> {
+    pub value: V,
+    /// Fetching a fresh value failed, this is the last value that was fetched successfully
+    pub stale: bool,
+}
+
+#[derive(Debug)]
+pub struct TtlCache<K, V> {
+    entries: Arc<Mutex<HashMap<K, CacheEntry<V>>>>,
+}
+
+// Deriving would require K and V to implement Default and Clone
+impl<K, V> Default for TtlCache<K, V> {
+    fn default() -> Self {
+        TtlCache {
+            entries: Arc::default(),
+        }
+    }
+}
+
+impl<K, V> Clone for TtlCache<K, V> {
+    fn clone(&self) -> Self {
+        TtlCache {
+            entries: self.entries.clone(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct CacheEntry<V> {
+    value: V,
+    /// Wall clock time rather than an `Instant`, so that entries can be written to disk
+    fetched_at: DateTime<Utc>,
+}
+
+impl<K: Hash + Eq + Clone, V: Clone> TtlCache<K, V> {
+    /// Return the cached value if it was fetched less than `ttl` ago, otherwise fetch it again.
+    ///
+    /// Entries are never evicted. If OpsGenie can't be reached or has trouble of its own, the
+    /// last value that was fetched is returned as stale, no matter how old it is. Only if there
+    /// is none the error is returned. Other errors, e.g. for a user that was deleted or an API key
+    /// that was revoked, are always returned.
+    pub async fn get_or_fetch<F, Fut>(
+        &self,
+        key: &K,
+        ttl: Duration,
+        fetch: F,
+    ) -> Result<Lookup<V>, util::Error>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<V, util::Error>>,
+    {
+        if let Some(value) = self.get(key, Some(ttl)) {
+            return Ok(Lookup {
+                value,
+                stale: false,
+            });
+        }
+
+        match fetch().await {
+            Ok(value) => {
+                self.lock().insert(
+                    key.clone(),
+                    CacheEntry {
+                        value: value.clone(),
+                        fetched_at: Utc::now(),
+                    },
+                );
+                Ok(Lookup {
+                    value,
+                    stale: false,
+                })
+            }
+            Err(e) if !e.is_transient() => Err(e),
+            Err(e) => match self.get(key, None) {
+                Some(value) => {
+                    tracing::warn!(
+                        error = &e as &dyn std::error::Error,
+                        "Request to OpsGenie failed, using the last known data instead"
+                    );
+                    Ok(Lookup { value, stale: true })
+                }
+                None => Err(e),
+            },
+        }
+    }
+
+    pub fn clear(&self) {
+        self.lock().clear();
+    }
+
+    /// The cached value and when it was fetched, no matter how old it is
+    pub fn entry(&self, key: &K) -> Option<(V, DateTime<Utc>)> {
+        self.lock()
+            .get(key)
+            .map(|entry| (entry.value.clone(), entry.fetched_at))
+    }
+
+    /// All cached values and when they were fetched
+    pub fn entries(&self) -> Vec<(K, V, DateTime<Utc>)> {
+        self.lock()
+            .iter()
+            .map(|(key, entry)| (key.clone(), entry.value.clone(), entry.fetched_at))
+            .collect()
+    }
+
+    /// Put a value that was fetched at some point in the past into the cache, unless the cache
+    /// already has a more recent one
+    pub fn restore(&self, key: K, value: V, fetched_at: DateTime<Utc>) {
+        let mut entries = self.lock();
+        if entries
+            .get(&key)
+            .is_some_and(|existing| existing.fetched_at >= fetched_at)
+        {
+            return;
+        }
+        entries.insert(key, CacheEntry { value, fetched_at });
+    }
+
+    /// The cached value if there is one that is younger than `max_age`
+    fn get(&self, key: &K, max_age: Option<Duration>) -> Option<V> {
+        let now = Utc::now();
+        self.lock()
+            .get(key)
+            .filter(|entry| {
+                max_age.is_none_or(|max_age| {
+                    // Entries from the future are as fresh as it gets
+                    (now - entry.fetched_at).to_std().unwrap_or_default() < max_age
+                })
+            })
+            .map(|entry| entry.value.clone())
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<K, CacheEntry<V>>> {
+        self.entries.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Lookup, TtlCache};
+    use crate::util;
+    use hyper::StatusCode;
+    use rstest::rstest;
+    use std::time::Duration;
+
+    fn response(status: StatusCode) -> util::Error {
+        util::Error::HttpErrorResponse {
+            status,
+            url: "https://api.opsgenie.com/v2/users/jane".to_string(),
+            text: String::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_get_or_fetch() {
+        let cache = TtlCache::<String, u32>::default();
+        let key = "ops".to_string();
+        let ttl = Duration::from_secs(60);
+
+        // Nothing to fall back to yet
+        assert!(cache
+            .get_or_fetch(&key, ttl, || async {
+                Err(response(StatusCode::SERVICE_UNAVAILABLE))
+            })
+            .await
+            .is_err());
+
+        let fetched = cache
+            .get_or_fetch(&key, ttl, || async { Ok::<_, util::Error>(1) })
+            .await
+            .unwrap();
+        assert_eq!(
+            fetched,
+            Lookup {
+                value: 1,
+                stale: false
+            }
+        );
+
+        // Still fresh, so this isn't fetched at all
+        let cached = cache
+            .get_or_fetch(&key, ttl, || async { Ok::<_, util::Error>(2) })
+            .await
+            .unwrap();
+        assert_eq!(cached.value, 1);
+
+        // Expired, fetching fails
+        let stale = cache
+            .get_or_fetch(&key, Duration::ZERO, || async {
+                Err(response(StatusCode::SERVICE_UNAVAILABLE))
+            })
+            .await
+            .unwrap();
+        assert_eq!(
+            stale,
+            Lookup {
+                value: 1,
+                stale: true
+            }
+        );
+
+        let refreshed = cache
+            .get_or_fetch(&key, Duration::ZERO, || async { Ok::<_, util::Error>(3) })
+            .await
+            .unwrap();
+        assert_eq!(
+            refreshed,
+            Lookup {
+                value: 3,
+                stale: false
+            }
+        );
+    }
+
+    #[rstest]
+    #[case(StatusCode::INTERNAL_SERVER_ERROR, true)]
+    #[case(StatusCode::BAD_GATEWAY, true)]
+    #[case(StatusCode::TOO_MANY_REQUESTS, true)]
+    #[case(StatusCode::UNAUTHORIZED, false)]
+    #[case(StatusCode::NOT_FOUND, false)]
+    #[tokio::test]
+    async fn test_stale_only_on_transient_errors(
+        #[case] status: StatusCode,
+        #[case] falls_back: bool,
+    ) {
+        let cache = TtlCache::<String, u32>::default();
+        let key = "jane".to_string();
+        cache
+            .get_or_fetch(&key, Duration::ZERO, || async { Ok::<_, util::Error>(1) })
+            .await
+            .unwrap();
+
+        let result = cache
+            .get_or_fetch(&key, Duration::ZERO, || async { Err(response(status)) })
+            .await;
+        assert_eq!(result.is_ok(), falls_back);
+    }
+
+    #[tokio::test]
+    async fn test_clear() {
+        let cache = TtlCache::<String, u32>::default();
+        let key = "jane".to_string();
+        cache
+            .get_or_fetch(&key, Duration::from_secs(60), || async {
+                Ok::<_, util::Error>(1)
+            })
+            .await
+            .unwrap();
+
+        cache.clear();
+        assert_eq!(cache.entry(&key), None);
+    }
+}
@@ -0,0 +1,425 @@
+use crate::cache::OpsgenieCache;
+use crate::cli::error::{
+    AlertFailedSnafu, ConstructHttpClientSnafu, OpsGenieSnafu, ParseConfigSnafu, TwilioSnafu,
+    ValidationFailedSnafu,
+};
+use crate::config::{Config, ConfigError};
+use crate::opsgenie::{get_oncall_number, get_schedule_names};
+use crate::privacy::loggable_numbers;
+use crate::twilio::{alert, get_workflow, AlertResult, DialNumberResult, OverallResult};
+use crate::{opsgenie, twilio, AlertInfo, Schedule, ScheduleRequestById, ScheduleRequestByName};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use reqwest::{Client, ClientBuilder};
+use serde::Serialize;
+use snafu::{ResultExt, Snafu};
+use std::fmt::Display;
+
+#[derive(Parser, Debug)]
+#[command(
+    version,
+    about = "Looks up who is on call in OpsGenie and rings them via Twilio"
+)]
+pub struct Cli {
+    /// Defaults to `serve` when omitted
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Run the HTTP server
+    Serve,
+
+    /// Parse the configuration and print it with all secrets redacted
+    CheckConfig,
+
+    /// Parse the configuration and check it against OpsGenie and Twilio
+    ///
+    /// This verifies that the credentials are accepted, that the Twilio workflow exists and that
+    /// the given schedule can be resolved to someone with a phone number.
+    Validate(ScheduleArgs),
+
+    /// Look up who is currently on call for a schedule
+    #[command(name = "whosoncall")]
+    WhosOnCall {
+        #[command(flatten)]
+        schedule: ScheduleArgs,
+
+        /// Format in which the result is printed
+        #[arg(long, value_enum, default_value_t)]
+        output: OutputFormat,
+    },
+
+    /// Ring whoever is currently on call for a schedule
+    ///
+    /// This talks to OpsGenie and Twilio directly and does not need the HTTP server to be
+    /// running.
+    Alert {
+        #[command(flatten)]
+        schedule: ScheduleArgs,
+
+        /// Message that is passed on to the Twilio workflow
+        #[arg(long)]
+        message: Option<String>,
+
+        /// Format in which the result is printed
+        #[arg(long, value_enum, default_value_t)]
+        output: OutputFormat,
+    },
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, Default)]
+pub enum OutputFormat {
+    #[default]
+    Table,
+    Json,
+}
+
+#[derive(Args, Debug)]
+#[group(required = true, multiple = false)]
+pub struct ScheduleArgs {
+    /// Name of the OpsGenie schedule
+    #[arg(long)]
+    schedule: Option<String>,
+
+    /// Id of the OpsGenie schedule
+    #[arg(long)]
+    schedule_id: Option<String>,
+}
+
+impl ScheduleArgs {
+    pub fn schedule(&self) -> Schedule {
+        // clap guarantees that exactly one of the two is set
+        match (&self.schedule, &self.schedule_id) {
+            (_, Some(id)) => Schedule::ScheduleById(ScheduleRequestById { id: id.clone() }),
+            (Some(name), None) => {
+                Schedule::ScheduleByName(ScheduleRequestByName { name: name.clone() })
+            }
+            (None, None) => unreachable!("clap enforces that a schedule is specified"),
+        }
+    }
+}
+
+#[derive(Snafu, Debug)]
+#[snafu(module)]
+pub enum Error {
+    #[snafu(display("Failed parsing config: \n{source}"))]
+    ParseConfig { source: ConfigError },
+
+    #[snafu(display("failed to construct http client: \n{source}"))]
+    ConstructHttpClient { source: reqwest::Error },
+
+    #[snafu(display("{failed_checks} check(s) failed"))]
+    ValidationFailed { failed_checks: usize },
+
+    #[snafu(display("error when obtaining information from OpsGenie: \n{source}"))]
+    OpsGenie { source: opsgenie::Error },
+
+    #[snafu(display("error when communicating with Twilio: \n{source}"))]
+    Twilio { source: twilio::Error },
+
+    #[snafu(display("none of the calls could be placed successfully"))]
+    AlertFailed {},
+}
+
+pub fn check_config() -> Result<(), Error> {
+    let config = Config::new().context(ParseConfigSnafu)?;
+    println!("{config:#?}");
+    Ok(())
+}
+
+pub async fn validate(args: ScheduleArgs) -> Result<(), Error> {
+    let (config, http) = setup()?;
+    println!("Config: OK");
+
+    let mut failed_checks = 0;
+
+    failed_checks += report(
+        "OpsGenie credentials",
+        get_schedule_names(&http, &config)
+            .await
+            .map(|schedules| format!("{} schedule(s) visible", schedules.len())),
+    );
+
+    failed_checks += report(
+        "Twilio credentials",
+        get_workflow(&http, &config).await.map(|workflow| {
+            if workflow.status != "published" {
+                tracing::warn!(
+                    workflow.status,
+                    "Twilio workflow is not published, calls may not work as expected"
+                );
+            }
+            format!(
+                "workflow [{}] has status [{}]",
+                workflow.friendly_name, workflow.status
+            )
+        }),
+    );
+
+    let schedule = args.schedule();
+    failed_checks += report(
+        &format!("Resolving schedule {schedule:?}"),
+        get_oncall_number(&schedule, &http, &OpsgenieCache::default(), &config)
+            .await
+            .map(|alert_info| {
+                serde_json::to_string_pretty(&alert_info)
+                    .unwrap_or_else(|_| format!("{alert_info:?}"))
+            }),
+    );
+
+    if failed_checks > 0 {
+        return ValidationFailedSnafu { failed_checks }.fail();
+    }
+    Ok(())
+}
+
+pub async fn whos_on_call(schedule: ScheduleArgs, output: OutputFormat) -> Result<(), Error> {
+    let (config, http) = setup()?;
+
+    let alert_info = get_oncall_number(
+        &schedule.schedule(),
+        &http,
+        &OpsgenieCache::default(),
+        &config,
+    )
+    .await
+    .context(OpsGenieSnafu)?;
+
+    match output {
+        OutputFormat::Json => print_json(&alert_info),
+        OutputFormat::Table => print_alert_info_table(&alert_info),
+    }
+    Ok(())
+}
+
+pub async fn alert_schedule(
+    schedule: ScheduleArgs,
+    message: Option<String>,
+    output: OutputFormat,
+) -> Result<(), Error> {
+    let (config, http) = setup()?;
+
+    let people_to_alert = get_oncall_number(
+        &schedule.schedule(),
+        &http,
+        &OpsgenieCache::default(),
+        &config,
+    )
+    .await
+    .context(OpsGenieSnafu)?;
+    let numbers = people_to_alert.phone_numbers();
+    tracing::info!(
+        "Will call these phones: [{:?}]",
+        loggable_numbers(&config, &numbers)
+    );
+
+    let alert_result = alert(&numbers, message.as_deref(), &http, &config)
+        .await
+        .context(TwilioSnafu)?;
+
+    match output {
+        OutputFormat::Json => print_json(&alert_result),
+        OutputFormat::Table => print_alert_result_table(&alert_result),
+    }
+
+    if alert_result.overall_result == OverallResult::Failure {
+        return AlertFailedSnafu.fail();
+    }
+    Ok(())
+}
+
+fn setup() -> Result<(Config, Client), Error> {
+    let config = Config::new().context(ParseConfigSnafu)?;
+    let http = ClientBuilder::new()
+        .build()
+        .context(ConstructHttpClientSnafu)?;
+    Ok((config, http))
+}
+
+fn print_json(value: &impl Serialize) {
+    match serde_json::to_string_pretty(value) {
+        Ok(json) => println!("{json}"),
+        // Our own types always serialize, but there is no need to panic if they ever don't
+        Err(e) => eprintln!("failed to serialize output: {e}"),
+    }
+}
+
+fn print_alert_info_table(alert_info: &AlertInfo) {
+    println!(
+        "Primary contact: {} ({})\n",
+        alert_info.username, alert_info.phone_number
+    );
+    print_table(
+        &["NAME", "PHONE NUMBERS"],
+        alert_info
+            .full_information
+            .iter()
+            .map(|person| vec![person.name.clone(), person.phone_numbers().join(", ")])
+            .collect(),
+    );
+}
+
+fn print_alert_result_table(alert_result: &AlertResult) {
+    println!("Overall result: {:?}\n", alert_result.overall_result);
+    print_table(
+        &["NUMBER", "RESULT", "DETAILS"],
+        alert_result
+            .detailed_result
+            .iter()
+            .map(|result| match result {
+                DialNumberResult::Success { number } => {
+                    vec![number.clone(), "success".to_string(), String::new()]
+                }
+                DialNumberResult::Failure { number, error } => {
+                    vec![number.clone(), "failure".to_string(), error.clone()]
+                }
+                DialNumberResult::Unknown { number, status } => {
+                    vec![number.clone(), "unknown".to_string(), status.clone()]
+                }
+            })
+            .collect(),
+    );
+}
+
+fn print_table(headers: &[&str], rows: Vec<Vec<String>>) {
+    print!("{}", format_table(headers, rows));
+}
+
+/// Rows left-aligned in columns that are as wide as their widest cell
+fn format_table(headers: &[&str], rows: Vec<Vec<String>>) -> String {
+    let mut widths = headers.iter().map(|h| h.len()).collect::<Vec<_>>();
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let format_row = |cells: Vec<&str>| {
+        cells
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{cell:<width$}"))
+            .collect::<Vec<_>>()
+            .join("  ")
+            .trim_end()
+            .to_string()
+    };
+
+    let mut table = format_row(headers.to_vec()) + "\n";
+    for row in &rows {
+        table += &format_row(row.iter().map(String::as_str).collect());
+        table += "\n";
+    }
+    table
+}
+
+/// Print the outcome of a single check and return the number of failures (0 or 1) so they can be
+/// summed up
+fn report<E: std::error::Error>(check: &str, result: Result<impl Display, E>) -> usize {
+    match result {
+        Ok(details) => {
+            println!("{check}: OK\n{details}");
+            0
+        }
+        Err(e) => {
+            println!("{check}: FAILED\n{e}");
+            1
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{format_table, report, Cli, Command, OutputFormat};
+    use crate::{Schedule, ScheduleRequestById, ScheduleRequestByName};
+    use clap::Parser;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case(&["alert", "--schedule", "ops"], Schedule::ScheduleByName(ScheduleRequestByName { name: "ops".to_string() }), None)]
+    #[case(&["alert", "--schedule-id", "4a2b", "--message", "db01 is down"], Schedule::ScheduleById(ScheduleRequestById { id: "4a2b".to_string() }), Some("db01 is down"))]
+    fn test_parse_schedule(
+        #[case] args: &[&str],
+        #[case] expected: Schedule,
+        #[case] expected_message: Option<&str>,
+    ) {
+        let cli =
+            Cli::try_parse_from(std::iter::once("whoyougonnacall").chain(args.iter().copied()))
+                .unwrap();
+        match cli.command {
+            Some(Command::Alert {
+                schedule,
+                message,
+                output,
+            }) => {
+                assert_eq!(schedule.schedule(), expected);
+                assert_eq!(message.as_deref(), expected_message);
+                assert!(matches!(output, OutputFormat::Table));
+            }
+            other => panic!("unexpected command {other:?}"),
+        }
+    }
+
+    #[rstest]
+    #[case(&["alert"])]
+    #[case(&["alert", "--schedule", "ops", "--schedule-id", "4a2b"])]
+    #[case(&["whosoncall", "--schedule", "ops", "--output", "yaml"])]
+    fn test_parse_invalid(#[case] args: &[&str]) {
+        assert!(Cli::try_parse_from(
+            std::iter::once("whoyougonnacall").chain(args.iter().copied())
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_parse_defaults_to_serve() {
+        assert!(Cli::try_parse_from(["whoyougonnacall"])
+            .unwrap()
+            .command
+            .is_none());
+    }
+
+    #[test]
+    fn test_parse_output() {
+        let cli = Cli::try_parse_from([
+            "whoyougonnacall",
+            "whosoncall",
+            "--schedule",
+            "ops",
+            "--output",
+            "json",
+        ])
+        .unwrap();
+        assert!(matches!(
+            cli.command,
+            Some(Command::WhosOnCall {
+                output: OutputFormat::Json,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_format_table() {
+        let table = format_table(
+            &["NAME", "PHONE NUMBERS"],
+            vec![
+                vec!["jane@example.com".to_string(), "+49123456123".to_string()],
+                vec!["joe@example.com".to_string(), String::new()],
+            ],
+        );
+        assert_eq!(
+            table,
+            "NAME              PHONE NUMBERS\n\
+             jane@example.com  +49123456123\n\
+             joe@example.com\n"
+        );
+    }
+
+    #[test]
+    fn test_report() {
+        assert_eq!(report::<std::fmt::Error>("check", Ok("fine")), 0);
+        assert_eq!(report("check", Err::<&str, _>(std::fmt::Error)), 1);
+    }
+}
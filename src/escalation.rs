@@ -0,0 +1,827 @@
+use crate::audit::AuditEvent;
+use crate::cache::OpsgenieCache;
+use crate::config::Config;
+use crate::escalation::error::{
+    KeyInUseSnafu, NestedEscalationSnafu, NoResolvableStepSnafu, RequestEscalationSnafu,
+    ResolveRecipientSnafu, RouteTeamSnafu, UnknownEscalationSnafu, UnsupportedNotifyTypeSnafu,
+    UnsupportedRecipientSnafu,
+};
+use crate::notification::{apply_notification_rules, call_now};
+use crate::opsgenie::{
+    get_alert_info_for_users, get_escalation, get_oncall_number, AlertPriority,
+    EscalationRecipient, EscalationRule,
+};
+use crate::privacy::{loggable_numbers, MaskPhoneNumbers};
+use crate::routing::{route_team, RoutingTarget};
+use crate::{
+    http_error, incident, opsgenie, routing, AlertInfo, AppState, Escalation, Schedule,
+    ScheduleRequestById, Team, TeamRequestById,
+};
+use axum::http::StatusCode;
+use chrono::Utc;
+use reqwest::Client;
+use serde::Serialize;
+use snafu::{OptionExt, ResultExt, Snafu};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+use tokio::task::AbortHandle;
+use tokio::time::Instant;
+
+#[derive(Snafu, Debug)]
+#[snafu(module)]
+pub enum Error {
+    #[snafu(display("failed to look up escalation policy: \n{source}"))]
+    RequestEscalation { source: opsgenie::Error },
+
+    #[snafu(display("failed to look up {recipient}: \n{source}"))]
+    ResolveRecipient {
+        #[snafu(source(from(opsgenie::Error, Box::new)))]
+        source: Box<opsgenie::Error>,
+        recipient: String,
+    },
+
+    #[snafu(display("notify type [{notify_type}] of {recipient} is not supported"))]
+    UnsupportedNotifyType {
+        notify_type: String,
+        recipient: String,
+    },
+
+    #[snafu(display("{recipient} is not supported as escalation recipient"))]
+    UnsupportedRecipient { recipient: String },
+
+    #[snafu(display("failed to evaluate routing rules of {recipient}: \n{source}"))]
+    RouteTeam {
+        #[snafu(source(from(routing::Error, Box::new)))]
+        source: Box<routing::Error>,
+        recipient: String,
+    },
+
+    #[snafu(display("{recipient} routes to another escalation policy, which is not supported"))]
+    NestedEscalation { recipient: String },
+
+    #[snafu(display("no step of escalation policy [{escalation}] can be called"))]
+    NoResolvableStep { escalation: String },
+
+    #[snafu(display("no escalation with key [{key}] is running"))]
+    UnknownEscalation { key: String },
+
+    #[snafu(display(
+        "escalation with key [{key}] is already running for escalation policy [{policy}]"
+    ))]
+    KeyInUse { key: String, policy: String },
+}
+
+impl http_error::Error for Error {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            Error::RequestEscalation { source } => source.status_code(),
+            Error::ResolveRecipient { source, .. } => source.status_code(),
+            Error::UnsupportedNotifyType { .. } => StatusCode::UNPROCESSABLE_ENTITY,
+            Error::UnsupportedRecipient { .. } => StatusCode::UNPROCESSABLE_ENTITY,
+            Error::RouteTeam { source, .. } => source.status_code(),
+            Error::NestedEscalation { .. } => StatusCode::UNPROCESSABLE_ENTITY,
+            Error::NoResolvableStep { .. } => StatusCode::IM_A_TEAPOT,
+            Error::UnknownEscalation { .. } => StatusCode::NOT_FOUND,
+            Error::KeyInUse { .. } => StatusCode::CONFLICT,
+        }
+    }
+}
+
+/// One rule of an escalation policy and, once it was called, the people it resolved to
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EscalationStep {
+    /// Seconds after the start of the escalation at which this step is called
+    pub delay_seconds: u64,
+    /// The recipient as configured in OpsGenie, e.g. `schedule [ops]`
+    pub recipient: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub alert_info: Option<AlertInfo>,
+    /// Why the recipient could not be resolved, steps without anyone to call are skipped
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(skip)]
+    rule: EscalationRule,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EscalationStatus {
+    /// Identifies the escalation, e.g. to acknowledge it
+    pub key: String,
+    pub escalation: Escalation,
+    pub policy_id: String,
+    pub policy_name: String,
+    pub started_at: String,
+    pub steps: Vec<EscalationStep>,
+    /// Number of steps that have been called so far, steps that were skipped count as called
+    pub steps_called: usize,
+    /// Passed on to the Twilio workflow for every call
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+    /// Alias of the OpsGenie alert that was created for the escalation, the outcome of every
+    /// step is added to it as a note
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub opsgenie_alias: Option<String>,
+    /// The alert that the escalation is called for, its own key or the alias of the alert that
+    /// OpsGenie told us about. Acknowledging or closing the alert stops the escalation.
+    #[serde(skip)]
+    pub alert_alias: String,
+    /// Priority that the notification rules of the people called are evaluated for, see
+    /// [`crate::notification`]
+    pub priority: AlertPriority,
+}
+
+impl EscalationStatus {
+    /// Whether the escalation calls the given escalation policy, no matter if it was requested
+    /// by name or id
+    fn is_for(&self, escalation: &Escalation) -> bool {
+        match escalation.identifier() {
+            (id, "id") => id == self.policy_id,
+            (name, _) => name == self.policy_name,
+        }
+    }
+
+    /// Fail if the key of this escalation was reused for another escalation policy
+    pub fn check_policy(&self, escalation: &Escalation) -> Result<(), Error> {
+        if self.is_for(escalation) {
+            Ok(())
+        } else {
+            KeyInUseSnafu {
+                key: &self.key,
+                policy: &self.policy_name,
+            }
+            .fail()
+        }
+    }
+}
+
+impl MaskPhoneNumbers for EscalationStatus {
+    fn mask_phone_numbers(&mut self) {
+        self.steps
+            .iter_mut()
+            .filter_map(|step| step.alert_info.as_mut())
+            .for_each(MaskPhoneNumbers::mask_phone_numbers);
+    }
+}
+
+/// The steps of an escalation policy, as they are called when the escalation is started
+#[derive(Debug, Clone)]
+pub struct EscalationPlan {
+    pub policy_id: String,
+    pub policy_name: String,
+    pub steps: Vec<EscalationStep>,
+}
+
+/// Fetch an escalation policy from OpsGenie and turn its rules into steps.
+///
+/// Who a step calls is only resolved when it is called, as the people on call may change while
+/// the escalation is running. Rules that we can't call at all are kept with the reason, so that
+/// the caller can see what will be skipped. It is only an error if no rule can be called.
+pub async fn plan_escalation(
+    escalation: &Escalation,
+    http: &Client,
+    config: &Config,
+) -> Result<EscalationPlan, Error> {
+    let policy = get_escalation(escalation, http, config)
+        .await
+        .context(RequestEscalationSnafu)?;
+    tracing::debug!(
+        policy.id,
+        policy.name,
+        rules = policy.rules.len(),
+        "Planning escalation policy"
+    );
+
+    let mut steps = policy
+        .rules
+        .into_iter()
+        .map(|rule| {
+            let recipient = describe_recipient(&rule.recipient);
+            let error = check_rule(&rule, &recipient).err().map(|e| {
+                tracing::warn!(
+                    error = &e as &dyn std::error::Error,
+                    recipient,
+                    "Skipping escalation step"
+                );
+                e.to_string()
+            });
+            EscalationStep {
+                delay_seconds: rule.delay.as_duration().as_secs(),
+                recipient,
+                alert_info: None,
+                error,
+                rule,
+            }
+        })
+        .collect::<Vec<_>>();
+    // OpsGenie returns the rules in order, but nothing guarantees that they are sorted by delay
+    steps.sort_by_key(|step| step.delay_seconds);
+
+    if steps.iter().all(|step| step.error.is_some()) {
+        return NoResolvableStepSnafu {
+            escalation: policy.name,
+        }
+        .fail();
+    }
+    Ok(EscalationPlan {
+        policy_id: policy.id,
+        policy_name: policy.name,
+        steps,
+    })
+}
+
+/// Who would be called first if the escalation was started right now, steps that don't resolve
+/// to anyone are skipped
+pub async fn resolve_first_step(
+    escalation: &Escalation,
+    http: &Client,
+    cache: &OpsgenieCache,
+    config: &Config,
+) -> Result<Option<AlertInfo>, Error> {
+    let plan = plan_escalation(escalation, http, config).await?;
+    for step in plan.steps.iter().filter(|step| step.error.is_none()) {
+        match resolve_rule(&step.rule, &step.recipient, http, cache, config).await {
+            Ok(alert_info) => return Ok(Some(alert_info)),
+            Err(e) => tracing::warn!(
+                error = &e as &dyn std::error::Error,
+                step.recipient,
+                "Skipping escalation step"
+            ),
+        }
+    }
+    Ok(None)
+}
+
+fn describe_recipient(recipient: &EscalationRecipient) -> String {
+    match recipient {
+        EscalationRecipient::User { username } => format!("user [{username}]"),
+        EscalationRecipient::Schedule { name, .. } => format!("schedule [{name}]"),
+        EscalationRecipient::Team { name, .. } => format!("team [{name}]"),
+        EscalationRecipient::Unsupported => "unknown recipient".to_string(),
+    }
+}
+
+/// Whether we can call the rule at all, regardless of who is on call
+fn check_rule(rule: &EscalationRule, recipient: &str) -> Result<(), Error> {
+    // Other notify types address e.g. the next or previous on-call person of a schedule, which
+    // isn't something our lookups can answer
+    if rule.notify_type != "default" {
+        return UnsupportedNotifyTypeSnafu {
+            notify_type: &rule.notify_type,
+            recipient,
+        }
+        .fail();
+    }
+    if let EscalationRecipient::Unsupported = rule.recipient {
+        return UnsupportedRecipientSnafu { recipient }.fail();
+    }
+    Ok(())
+}
+
+async fn resolve_rule(
+    rule: &EscalationRule,
+    recipient: &str,
+    http: &Client,
+    cache: &OpsgenieCache,
+    config: &Config,
+) -> Result<AlertInfo, Error> {
+    check_rule(rule, recipient)?;
+    match &rule.recipient {
+        EscalationRecipient::User { username } => {
+            get_alert_info_for_users(vec![username.clone()], http, cache, config).await
+        }
+        EscalationRecipient::Schedule { id, .. } => {
+            let schedule = Schedule::ScheduleById(ScheduleRequestById { id: id.clone() });
+            get_oncall_number(&schedule, http, cache, config).await
+        }
+        EscalationRecipient::Team { id, .. } => {
+            let team = Team::TeamById(TeamRequestById {
+                team_id: id.clone(),
+            });
+            match route_team(&team, Utc::now(), http, config)
+                .await
+                .context(RouteTeamSnafu { recipient })?
+            {
+                RoutingTarget::Schedule(schedule) => {
+                    get_oncall_number(&schedule, http, cache, config).await
+                }
+                RoutingTarget::Escalation(_) => {
+                    return NestedEscalationSnafu { recipient }.fail();
+                }
+            }
+        }
+        EscalationRecipient::Unsupported => unreachable!("rejected by check_rule"),
+    }
+    .context(ResolveRecipientSnafu { recipient })
+}
+
+/// Escalations that are currently being called, keyed by their key.
+///
+/// Each escalation runs as its own task that calls the steps one after the other and removes the
+/// escalation once the last step was called. Acknowledging an escalation stops the task.
+#[derive(Debug, Clone, Default)]
+pub struct EscalationRunner {
+    running: Arc<Mutex<HashMap<String, RunningEscalation>>>,
+    started: Arc<AtomicU64>,
+}
+
+#[derive(Debug)]
+struct RunningEscalation {
+    status: EscalationStatus,
+    abort_handle: AbortHandle,
+}
+
+impl EscalationRunner {
+    /// A key for an escalation that was started without one
+    pub fn generate_key(&self) -> String {
+        let started = self.started.fetch_add(1, Ordering::Relaxed);
+        format!("{}-{started}", chrono::Utc::now().timestamp_millis())
+    }
+
+    pub fn status(&self, key: &str) -> Result<EscalationStatus, Error> {
+        self.lock()
+            .get(key)
+            .map(|running| running.status.clone())
+            .context(UnknownEscalationSnafu { key })
+    }
+
+    /// Start calling the steps of an escalation in the background, every step that was called is
+    /// recorded as a copy of the given audit event.
+    ///
+    /// If an escalation with the same key is already running, it is left alone and its status is
+    /// returned instead. It is an error if that escalation calls another escalation policy.
+    pub fn start(
+        &self,
+        status: EscalationStatus,
+        state: &AppState,
+        audit_event: AuditEvent,
+    ) -> Result<EscalationStatus, Error> {
+        let mut running = self.lock();
+        if let Some(existing) = running.get(&status.key) {
+            tracing::info!(key = status.key, "Escalation is already running");
+            existing.status.check_policy(&status.escalation)?;
+            return Ok(existing.status.clone());
+        }
+
+        let task = tokio::spawn(run_escalation(status.clone(), state.clone(), audit_event));
+        running.insert(
+            status.key.clone(),
+            RunningEscalation {
+                status: status.clone(),
+                abort_handle: task.abort_handle(),
+            },
+        );
+        Ok(status)
+    }
+
+    /// Stop calling further steps of an escalation
+    pub fn acknowledge(&self, key: &str) -> Result<EscalationStatus, Error> {
+        let running = self
+            .lock()
+            .remove(key)
+            .context(UnknownEscalationSnafu { key })?;
+        running.abort_handle.abort();
+        tracing::info!(key, "Escalation acknowledged");
+        Ok(running.status)
+    }
+
+    /// Stop all escalations that are called for the alert, see
+    /// [`EscalationStatus::alert_alias`]
+    pub fn acknowledge_alert(&self, alias: &str) -> Vec<EscalationStatus> {
+        let mut running = self.lock();
+        let keys = running
+            .values()
+            .filter(|escalation| escalation.status.alert_alias == alias)
+            .map(|escalation| escalation.status.key.clone())
+            .collect::<Vec<_>>();
+        let mut acknowledged = Vec::new();
+        for key in keys {
+            if let Some(escalation) = running.remove(&key) {
+                escalation.abort_handle.abort();
+                tracing::info!(key, alias, "Escalation acknowledged");
+                acknowledged.push(escalation.status);
+            }
+        }
+        acknowledged
+    }
+
+    fn step_called(&self, key: &str, steps_called: usize) {
+        if let Some(running) = self.lock().get_mut(key) {
+            running.status.steps_called = steps_called;
+        }
+    }
+
+    fn step_resolved(&self, key: &str, index: usize, result: &Result<AlertInfo, Error>) {
+        if let Some(step) = self
+            .lock()
+            .get_mut(key)
+            .and_then(|running| running.status.steps.get_mut(index))
+        {
+            match result {
+                Ok(alert_info) => step.alert_info = Some(alert_info.clone()),
+                Err(e) => step.error = Some(e.to_string()),
+            }
+        }
+    }
+
+    fn finished(&self, key: &str) {
+        self.lock().remove(key);
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, RunningEscalation>> {
+        self.running.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+async fn run_escalation(status: EscalationStatus, state: AppState, audit_event: AuditEvent) {
+    let started = Instant::now();
+    let key = &status.key;
+
+    for (index, step) in status.steps.iter().enumerate() {
+        if step.error.is_none() {
+            tokio::time::sleep_until(started + Duration::from_secs(step.delay_seconds)).await;
+            call_step(index, step, &status, &state, &audit_event).await;
+        }
+        state.escalations.step_called(key, index + 1);
+    }
+
+    tracing::info!(key, "All escalation steps were called");
+    state.escalations.finished(key);
+}
+
+async fn call_step(
+    index: usize,
+    step: &EscalationStep,
+    status: &EscalationStatus,
+    state: &AppState,
+    audit_event: &AuditEvent,
+) {
+    let EscalationStatus {
+        key,
+        message,
+        opsgenie_alias,
+        alert_alias,
+        priority,
+        ..
+    } = status;
+    // Re-read the config for every step, credentials may have been rotated in the meantime
+    let config = state.config.current();
+    let mut step_audit_event = audit_event.clone();
+    step_audit_event.escalation_step(index);
+
+    // Whoever is on call right now is called, not whoever was when the escalation started
+    let resolved = resolve_rule(
+        &step.rule,
+        &step.recipient,
+        &state.http,
+        &state.opsgenie_cache,
+        &config,
+    )
+    .await;
+    state.escalations.step_resolved(key, index, &resolved);
+    let mut alert_info = match resolved {
+        Ok(alert_info) => alert_info,
+        Err(e) => {
+            tracing::warn!(
+                error = &e as &dyn std::error::Error,
+                key,
+                step = index,
+                step.recipient,
+                "Skipping escalation step"
+            );
+            step_audit_event.finish(&Err::<(), _>(e));
+            state.audit_log.record(step_audit_event, &config).await;
+            return;
+        }
+    };
+
+    // Notification rules depend on the time, so they are applied when the step is called
+    apply_notification_rules(
+        &mut alert_info,
+        *priority,
+        &state.http,
+        &state.opsgenie_cache,
+        &config,
+    )
+    .await;
+    let numbers = alert_info.phone_numbers();
+    let delayed_calls = alert_info.delayed_calls();
+    tracing::info!(
+        key,
+        step = index,
+        step.recipient,
+        numbers = ?loggable_numbers(&config, &numbers),
+        "Calling escalation step"
+    );
+
+    step_audit_event.resolved(&alert_info);
+    let result = call_now(
+        &numbers,
+        &delayed_calls,
+        message.as_deref(),
+        &state.http,
+        &config,
+    )
+    .await;
+    // Later steps are meant to be called whether this one worked or not, so are the delayed
+    // numbers of this step
+    state.delayed_calls.start(
+        key,
+        alert_alias,
+        delayed_calls,
+        message.clone(),
+        opsgenie_alias.clone(),
+        state,
+        step_audit_event.clone(),
+    );
+    if let Some(opsgenie_alias) = opsgenie_alias {
+        let called = format!("escalation step {index} ({})", step.recipient);
+        incident::record_calls(opsgenie_alias, &called, &result, &state.http, &config).await;
+    }
+    if let Ok(alert_result) = &result {
+        step_audit_event.dialed(alert_result);
+    }
+    step_audit_event.finish(&result);
+    state.audit_log.record(step_audit_event, &config).await;
+}
+
+#[cfg(test)]
+mod test {
+    use super::{describe_recipient, plan_escalation, EscalationStatus};
+    use crate::audit::{AuditAction, AuditEvent};
+    use crate::auth::{AuthMethod, Caller, Scope};
+    use crate::escalation::Error;
+    use crate::fake_api::FakeApi;
+    use crate::http_error::Error as _;
+    use crate::opsgenie::{AlertPriority, EscalationPolicy};
+    use crate::{
+        AlertTarget, AppState, Escalation, EscalationRequestById, EscalationRequestByName,
+    };
+    use axum::http::{HeaderMap, Method, StatusCode};
+    use serde_json::{json, Value};
+    use std::time::Duration;
+
+    fn by_name(name: &str) -> Escalation {
+        Escalation::EscalationByName(EscalationRequestByName {
+            name: name.to_string(),
+        })
+    }
+
+    fn by_id(id: &str) -> Escalation {
+        Escalation::EscalationById(EscalationRequestById { id: id.to_string() })
+    }
+
+    fn rule(notify_type: &str, minutes: u64, recipient: Value) -> Value {
+        json!({
+            "condition": "if-not-acked",
+            "notifyType": notify_type,
+            "delay": {"timeAmount": minutes, "timeUnit": "minutes"},
+            "recipient": recipient,
+        })
+    }
+
+    /// The schedule after 0 and 5 minutes, a user without contacts after an hour and a rule that
+    /// can't be called at all
+    fn policy(api: &FakeApi, id: &str, name: &str) {
+        let schedule = json!({"type": "schedule", "id": "1c2d", "name": "ops"});
+        let policy = json!({"data": {
+            "id": id,
+            "name": name,
+            "rules": [
+                rule("default", 60, json!({"type": "user", "id": "7e8f", "username": "nobody"})),
+                rule("default", 5, schedule.clone()),
+                rule("default", 0, schedule.clone()),
+                rule("next", 120, schedule),
+            ],
+        }});
+        for identifier in [id, name] {
+            api.respond(
+                Method::GET,
+                &format!("/v2/escalations/{identifier}"),
+                StatusCode::OK,
+                policy.clone(),
+            );
+        }
+    }
+
+    async fn start(
+        state: &AppState,
+        key: &str,
+        escalation: &Escalation,
+    ) -> Result<EscalationStatus, Error> {
+        let config = state.config.current();
+        let plan = plan_escalation(escalation, &state.http, &config).await?;
+        let caller = Caller {
+            name: "monitoring".to_string(),
+            method: AuthMethod::StaticToken,
+            scopes: Scope::all(),
+        };
+        let audit_event = AuditEvent::new(
+            AuditAction::Escalate,
+            &caller,
+            None,
+            &HeaderMap::new(),
+            &AlertTarget::Escalation(escalation.clone()),
+        );
+        let status = EscalationStatus {
+            key: key.to_string(),
+            escalation: escalation.clone(),
+            policy_id: plan.policy_id,
+            policy_name: plan.policy_name,
+            started_at: chrono::Utc::now().to_rfc3339(),
+            steps: plan.steps,
+            steps_called: 0,
+            message: None,
+            opsgenie_alias: None,
+            alert_alias: key.to_string(),
+            priority: AlertPriority::P1,
+        };
+        state.escalations.start(status, state, audit_event)
+    }
+
+    /// Let the escalation make progress without advancing the paused clock, until the condition
+    /// holds
+    async fn run_until(condition: impl Fn() -> bool) {
+        while !condition() {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    fn steps_called(state: &AppState, key: &str) -> usize {
+        state
+            .escalations
+            .status(key)
+            .map(|status| status.steps_called)
+            .unwrap_or(usize::MAX)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_steps_are_resolved_when_called() {
+        let api = FakeApi::start().await;
+        policy(&api, "9a3f", "ops_escalation");
+        api.on_call("1c2d", "ops", "jane", "+4915112345678");
+        api.accept_calls();
+        let mut config = api.config();
+        config.opsgenie_config.cache_ttl = Duration::ZERO;
+        let state = AppState::for_tests(config);
+
+        let status = start(&state, "outage", &by_name("ops_escalation"))
+            .await
+            .unwrap();
+        let delays = status
+            .steps
+            .iter()
+            .map(|step| (step.delay_seconds, step.error.is_some()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            delays,
+            [(0, false), (300, false), (3600, false), (7200, true)]
+        );
+        assert!(status.steps.iter().all(|step| step.alert_info.is_none()));
+
+        run_until(|| steps_called(&state, "outage") == 1).await;
+        assert_eq!(api.dialed(), ["+4915112345678"]);
+
+        // The handover happens before the second step is due
+        api.on_call("1c2d", "ops", "joe", "+4915187654321");
+        tokio::time::advance(Duration::from_secs(4 * 60)).await;
+        for _ in 0..100 {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(steps_called(&state, "outage"), 1);
+        assert_eq!(api.dialed(), ["+4915112345678"]);
+
+        tokio::time::advance(Duration::from_secs(60)).await;
+        run_until(|| steps_called(&state, "outage") == 2).await;
+        assert_eq!(api.dialed(), ["+4915112345678", "+4915187654321"]);
+        let status = state.escalations.status("outage").unwrap();
+        let called = status
+            .steps
+            .iter()
+            .map(|step| step.alert_info.as_ref().map(|info| info.username.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(called, [Some("jane"), Some("joe"), None, None]);
+        assert!(api.requests(Method::GET, "/v2/users/nobody").is_empty());
+
+        // Nobody can be called in the last two steps, which ends the escalation
+        tokio::time::advance(Duration::from_secs(55 * 60)).await;
+        run_until(|| state.escalations.status("outage").is_err()).await;
+        assert_eq!(api.requests(Method::GET, "/v2/users/nobody").len(), 1);
+        assert_eq!(api.dialed().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_key_is_reused_for_same_policy_only() {
+        let api = FakeApi::start().await;
+        policy(&api, "9a3f", "ops_escalation");
+        policy(&api, "7b1c", "db_escalation");
+        api.on_call("1c2d", "ops", "jane", "+4915112345678");
+        api.accept_calls();
+        let state = AppState::for_tests(api.config());
+
+        let first = start(&state, "outage", &by_name("ops_escalation"))
+            .await
+            .unwrap();
+        let again = start(&state, "outage", &by_id("9a3f")).await.unwrap();
+        assert_eq!(again.started_at, first.started_at);
+        assert!(first.check_policy(&by_id("9a3f")).is_ok());
+
+        let error = start(&state, "outage", &by_name("db_escalation"))
+            .await
+            .unwrap_err();
+        assert!(matches!(error, Error::KeyInUse { .. }));
+        assert_eq!(error.status_code(), StatusCode::CONFLICT);
+        assert!(first.check_policy(&by_id("7b1c")).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_policy_without_supported_rule() {
+        let api = FakeApi::start().await;
+        let schedule = json!({"type": "schedule", "id": "1c2d", "name": "ops"});
+        api.respond(
+            Method::GET,
+            "/v2/escalations/ops_escalation",
+            StatusCode::OK,
+            json!({"data": {
+                "id": "9a3f",
+                "name": "ops_escalation",
+                "rules": [
+                    rule("next", 0, schedule),
+                    rule("default", 5, json!({"type": "none"})),
+                ],
+            }}),
+        );
+        let config = api.config();
+
+        let result =
+            plan_escalation(&by_name("ops_escalation"), &reqwest::Client::new(), &config).await;
+        assert!(matches!(result, Err(Error::NoResolvableStep { .. })));
+    }
+
+    #[test]
+    fn test_parse_escalation_policy() {
+        let policy: EscalationPolicy = serde_json::from_value(json!({
+            "id": "9a3f5f8c",
+            "name": "ops_escalation",
+            "description": "",
+            "ownerTeam": {"id": "5b1a", "name": "ops"},
+            "rules": [
+                {
+                    "condition": "if-not-acked",
+                    "notifyType": "default",
+                    "delay": {"timeAmount": 0, "timeUnit": "minutes"},
+                    "recipient": {"type": "schedule", "id": "1c2d", "name": "ops_schedule"}
+                },
+                {
+                    "condition": "if-not-acked",
+                    "notifyType": "default",
+                    "delay": {"timeAmount": 5, "timeUnit": "minutes"},
+                    "recipient": {"type": "user", "id": "7e8f", "username": "jane@example.com"}
+                },
+                {
+                    "condition": "if-not-closed",
+                    "notifyType": "all",
+                    "delay": {"timeAmount": 1, "timeUnit": "hours"},
+                    "recipient": {"type": "team", "id": "5b1a", "name": "ops"}
+                },
+                {
+                    "condition": "if-not-acked",
+                    "notifyType": "default",
+                    "delay": {"timeAmount": 2, "timeUnit": "hours"},
+                    "recipient": {"type": "none"}
+                }
+            ],
+            "repeat": {"waitInterval": 10, "count": 1}
+        }))
+        .unwrap();
+
+        let steps = policy
+            .rules
+            .iter()
+            .map(|rule| {
+                (
+                    rule.delay.as_duration(),
+                    describe_recipient(&rule.recipient),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            steps,
+            vec![
+                (Duration::ZERO, "schedule [ops_schedule]".to_string()),
+                (
+                    Duration::from_secs(5 * 60),
+                    "user [jane@example.com]".to_string()
+                ),
+                (Duration::from_secs(60 * 60), "team [ops]".to_string()),
+                (
+                    Duration::from_secs(2 * 60 * 60),
+                    "unknown recipient".to_string()
+                ),
+            ]
+        );
+    }
+}
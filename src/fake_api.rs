@@ -0,0 +1,218 @@
+//! A fake OpsGenie and Twilio API for tests, see [`FakeApi`]
+
+use crate::config::Config;
+use axum::body::Bytes;
+use axum::extract::State;
+use axum::http::{Method, StatusCode, Uri};
+use axum::response::IntoResponse;
+use axum::{Json, Router};
+use serde_json::{json, Value};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+use url::Url;
+
+/// Answers requests with canned JSON responses by method and path and records every request.
+///
+/// OpsGenie is served below `/v2/` and Twilio below `/Flows/`, matching [`Config::for_tests`].
+/// Paths without a response get a `404`.
+#[derive(Debug, Clone)]
+pub struct FakeApi {
+    pub base_url: Url,
+    state: Arc<Mutex<FakeApiState>>,
+}
+
+#[derive(Debug, Default)]
+struct FakeApiState {
+    /// Responses that are used once, in order, before falling back to `responses`
+    queued: HashMap<(Method, String), VecDeque<(StatusCode, Value)>>,
+    responses: HashMap<(Method, String), (StatusCode, Value)>,
+    delays: HashMap<String, Duration>,
+    requests: Vec<RecordedRequest>,
+}
+
+#[derive(Debug, Clone)]
+pub struct RecordedRequest {
+    pub method: Method,
+    pub path: String,
+    pub query: String,
+    pub body: String,
+}
+
+impl FakeApi {
+    pub async fn start() -> Self {
+        let state = Arc::<Mutex<FakeApiState>>::default();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let app = Router::new().fallback(respond).with_state(state.clone());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        FakeApi {
+            base_url: Url::parse(&format!("http://{address}/")).unwrap(),
+            state,
+        }
+    }
+
+    /// A config that talks to this API
+    pub fn config(&self) -> Config {
+        Config::for_tests(&self.base_url)
+    }
+
+    /// Answer every request to the path with this response
+    pub fn respond(&self, method: Method, path: &str, status: StatusCode, body: Value) {
+        self.lock()
+            .responses
+            .insert((method, path.to_string()), (status, body));
+    }
+
+    /// Answer the next request to the path with this response, before any other response
+    pub fn respond_once(&self, method: Method, path: &str, status: StatusCode, body: Value) {
+        self.lock()
+            .queued
+            .entry((method, path.to_string()))
+            .or_default()
+            .push_back((status, body));
+    }
+
+    /// Answer requests to the path only after a while, e.g. to make them finish out of order
+    pub fn delay(&self, path: &str, delay: Duration) {
+        self.lock().delays.insert(path.to_string(), delay);
+    }
+
+    /// Someone with a single phone number is on call for the schedule with the given name
+    pub fn on_call(&self, schedule_id: &str, schedule_name: &str, username: &str, number: &str) {
+        let on_call = json!({"data": {"onCallRecipients": [username]}});
+        for identifier in [schedule_id, schedule_name] {
+            self.respond(
+                Method::GET,
+                &format!("/v2/schedules/{}/on-calls", urlencoding::encode(identifier)),
+                StatusCode::OK,
+                on_call.clone(),
+            );
+        }
+        self.respond(
+            Method::GET,
+            &format!("/v2/schedules/{}", urlencoding::encode(schedule_name)),
+            StatusCode::OK,
+            json!({"data": {"id": schedule_id, "name": schedule_name}}),
+        );
+        self.contacts(username, &[number]);
+    }
+
+    /// The user has a voice contact for each of the numbers
+    pub fn contacts(&self, username: &str, numbers: &[&str]) {
+        let contacts = numbers
+            .iter()
+            .enumerate()
+            .map(|(index, number)| {
+                json!({"id": index.to_string(), "contactMethod": "voice", "to": number, "enabled": true})
+            })
+            .collect::<Vec<_>>();
+        self.respond(
+            Method::GET,
+            &format!("/v2/users/{}", urlencoding::encode(username)),
+            StatusCode::OK,
+            json!({"data": {
+                "id": username,
+                "username": username,
+                "fullName": username,
+                "userContacts": contacts,
+            }}),
+        );
+    }
+
+    /// The rule is the only notification rule of the user for new alerts
+    pub fn notification_rule(&self, username: &str, rule: Value) {
+        self.respond(
+            Method::GET,
+            &format!(
+                "/v2/users/{}/notification-rules",
+                urlencoding::encode(username)
+            ),
+            StatusCode::OK,
+            json!({"data": [{"id": "1", "actionType": "create-alert", "enabled": true}]}),
+        );
+        self.respond(
+            Method::GET,
+            &format!(
+                "/v2/users/{}/notification-rules/1",
+                urlencoding::encode(username)
+            ),
+            StatusCode::OK,
+            json!({"data": rule}),
+        );
+    }
+
+    /// Twilio accepts every call
+    pub fn accept_calls(&self) {
+        self.respond(
+            Method::POST,
+            "/Flows/FW0123456789abcdef0123456789abcdef/Executions/",
+            StatusCode::OK,
+            json!({"status": "active"}),
+        );
+    }
+
+    pub fn requests(&self, method: Method, path: &str) -> Vec<RecordedRequest> {
+        self.lock()
+            .requests
+            .iter()
+            .filter(|request| request.method == method && request.path == path)
+            .cloned()
+            .collect()
+    }
+
+    /// Numbers that Twilio was asked to call, in order
+    pub fn dialed(&self) -> Vec<String> {
+        self.requests(
+            Method::POST,
+            "/Flows/FW0123456789abcdef0123456789abcdef/Executions/",
+        )
+        .iter()
+        .filter_map(|request| {
+            url::form_urlencoded::parse(request.body.as_bytes())
+                .find(|(key, _)| key == "To")
+                .map(|(_, number)| number.to_string())
+        })
+        .collect()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, FakeApiState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+async fn respond(
+    State(state): State<Arc<Mutex<FakeApiState>>>,
+    method: Method,
+    uri: Uri,
+    body: Bytes,
+) -> impl IntoResponse {
+    let path = uri.path().to_string();
+    let delay = state
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .delays
+        .get(&path)
+        .copied();
+    if let Some(delay) = delay {
+        tokio::time::sleep(delay).await;
+    }
+
+    let mut state = state.lock().unwrap_or_else(PoisonError::into_inner);
+    state.requests.push(RecordedRequest {
+        method: method.clone(),
+        path: path.clone(),
+        query: uri.query().unwrap_or_default().to_string(),
+        body: String::from_utf8_lossy(&body).to_string(),
+    });
+
+    let key = (method, path);
+    let queued = state
+        .queued
+        .get_mut(&key)
+        .and_then(|queued| queued.pop_front());
+    let (status, body) = queued
+        .or_else(|| state.responses.get(&key).cloned())
+        .unwrap_or((StatusCode::NOT_FOUND, json!({"message": "not found"})));
+    (status, Json(body))
+}
@@ -0,0 +1,264 @@
+use crate::cache::OpsgenieCache;
+use crate::config::Config;
+use crate::heartbeat::error::{
+    CanarySnafu, PingSnafu, StaleCanarySnafu, TwilioSnafu, UnpublishedWorkflowSnafu,
+};
+use crate::opsgenie::{get_oncall_number, ping_heartbeat};
+use crate::reload::SharedConfig;
+use crate::twilio::get_workflow;
+use crate::{opsgenie, twilio, Schedule, ScheduleRequestByName};
+use reqwest::Client;
+use snafu::{ensure, ResultExt, Snafu};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::{interval, MissedTickBehavior};
+
+/// Status of the Twilio workflow once it can take executions
+static PUBLISHED_WORKFLOW_STATUS: &str = "published";
+
+#[derive(Snafu, Debug)]
+#[snafu(module)]
+pub enum Error {
+    #[snafu(display("Twilio workflow can't be retrieved: \n{source}"))]
+    Twilio { source: twilio::Error },
+
+    #[snafu(display("Twilio workflow has status [{status}] instead of being published"))]
+    UnpublishedWorkflow { status: String },
+
+    #[snafu(display("canary schedule [{schedule}] can't be resolved: \n{source}"))]
+    Canary {
+        source: opsgenie::Error,
+        schedule: String,
+    },
+
+    #[snafu(display("canary schedule [{schedule}] was only resolved from stale data"))]
+    StaleCanary { schedule: String },
+
+    #[snafu(display("failed to ping OpsGenie: \n{source}"))]
+    Ping { source: opsgenie::Error },
+}
+
+/// Shared between the heartbeat task and the status endpoint
+#[derive(Debug, Clone, Default)]
+pub struct Heartbeat {
+    failing: Arc<AtomicBool>,
+}
+
+impl Heartbeat {
+    /// Whether the last check failed, OpsGenie isn't pinged as long as this is the case. Always
+    /// `false` if no heartbeat is configured.
+    ///
+    /// `/status` doesn't require authentication, so why the check failed is only logged.
+    pub fn is_failing(&self) -> bool {
+        self.failing.load(Ordering::Relaxed)
+    }
+
+    fn set_failing(&self, failing: bool) {
+        self.failing.store(failing, Ordering::Relaxed);
+    }
+}
+
+/// Ping the OpsGenie heartbeat `WYGC_OPSGENIE_HEARTBEAT` for as long as we are able to call
+/// people, so that OpsGenie alerts through its own channels once we aren't.
+///
+/// This runs until the process exits. The interval is only read on startup, everything else is
+/// read from the current config every time.
+pub async fn run(
+    shared_config: SharedConfig,
+    http: Client,
+    opsgenie_cache: OpsgenieCache,
+    heartbeat: Heartbeat,
+) {
+    // A zero interval would make tokio panic
+    let heartbeat_interval = shared_config
+        .current()
+        .opsgenie_config
+        .heartbeat_interval
+        .max(Duration::from_secs(1));
+    let mut ticker = interval(heartbeat_interval);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        ticker.tick().await;
+        let config = shared_config.current();
+        let Some(name) = &config.opsgenie_config.heartbeat else {
+            heartbeat.set_failing(false);
+            continue;
+        };
+
+        match beat(name, &http, &opsgenie_cache, &config).await {
+            Ok(()) => {
+                tracing::debug!(name, "Pinged heartbeat");
+                heartbeat.set_failing(false);
+            }
+            Err(e) => {
+                tracing::error!(
+                    error = &e as &dyn std::error::Error,
+                    name,
+                    "Heartbeat check failed, OpsGenie is not pinged"
+                );
+                heartbeat.set_failing(true);
+            }
+        }
+    }
+}
+
+/// Check that calls would go through and only then ping OpsGenie, which also shows that
+/// OpsGenie can be reached
+async fn beat(
+    name: &str,
+    http: &Client,
+    cache: &OpsgenieCache,
+    config: &Config,
+) -> Result<(), Error> {
+    let workflow = get_workflow(http, config).await.context(TwilioSnafu)?;
+    ensure!(
+        workflow.status == PUBLISHED_WORKFLOW_STATUS,
+        UnpublishedWorkflowSnafu {
+            status: workflow.status
+        }
+    );
+
+    if let Some(schedule_name) = &config.opsgenie_config.heartbeat_canary_schedule {
+        let schedule = Schedule::ScheduleByName(ScheduleRequestByName {
+            name: schedule_name.clone(),
+        });
+        let alert_info = get_oncall_number(&schedule, http, cache, config)
+            .await
+            .context(CanarySnafu {
+                schedule: schedule_name,
+            })?;
+        // Stale data means OpsGenie couldn't be asked, that is what the heartbeat is there for
+        ensure!(
+            !alert_info.stale,
+            StaleCanarySnafu {
+                schedule: schedule_name
+            }
+        );
+    }
+
+    ping_heartbeat(name, http, config).await.context(PingSnafu)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{beat, run, Error, Heartbeat};
+    use crate::cache::OpsgenieCache;
+    use crate::fake_api::FakeApi;
+    use crate::reload::SharedConfig;
+    use axum::http::{Method, StatusCode};
+    use rstest::rstest;
+    use serde_json::json;
+    use std::time::Duration;
+
+    static WORKFLOW_PATH: &str = "/Flows/FW0123456789abcdef0123456789abcdef";
+    static PING_PATH: &str = "/v2/heartbeats/wygc/ping";
+
+    fn workflow(api: &FakeApi, status: &str) {
+        api.respond(
+            Method::GET,
+            WORKFLOW_PATH,
+            StatusCode::OK,
+            json!({"friendly_name": "Call on duty", "status": status}),
+        );
+    }
+
+    fn accept_pings(api: &FakeApi) {
+        api.respond(
+            Method::POST,
+            PING_PATH,
+            StatusCode::ACCEPTED,
+            json!({"result": "PONG - Heartbeat received"}),
+        );
+    }
+
+    #[rstest]
+    #[case::healthy("published", None, true)]
+    #[case::unpublished("draft", None, false)]
+    #[case::canary("published", Some("ops"), true)]
+    #[case::nobody_on_call("published", Some("empty"), false)]
+    #[tokio::test]
+    async fn test_beat(
+        #[case] workflow_status: &str,
+        #[case] canary: Option<&str>,
+        #[case] pinged: bool,
+    ) {
+        let api = FakeApi::start().await;
+        workflow(&api, workflow_status);
+        api.on_call("1c2d", "ops", "jane", "+4915112345678");
+        accept_pings(&api);
+        let mut config = api.config();
+        config.opsgenie_config.heartbeat_canary_schedule = canary.map(str::to_string);
+
+        let result = beat(
+            "wygc",
+            &reqwest::Client::new(),
+            &Default::default(),
+            &config,
+        )
+        .await;
+        assert_eq!(result.is_ok(), pinged);
+        assert_eq!(
+            api.requests(Method::POST, PING_PATH).len(),
+            usize::from(pinged)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_stale_canary_is_not_enough() {
+        let api = FakeApi::start().await;
+        workflow(&api, "published");
+        api.on_call("1c2d", "ops", "jane", "+4915112345678");
+        accept_pings(&api);
+        let mut config = api.config();
+        config.opsgenie_config.heartbeat_canary_schedule = Some("ops".to_string());
+        config.opsgenie_config.cache_ttl = Duration::ZERO;
+        let http = reqwest::Client::new();
+        let cache = OpsgenieCache::default();
+
+        beat("wygc", &http, &cache, &config).await.unwrap();
+        api.respond_once(
+            Method::GET,
+            "/v2/schedules/ops/on-calls",
+            StatusCode::SERVICE_UNAVAILABLE,
+            json!({"message": "unavailable"}),
+        );
+        let result = beat("wygc", &http, &cache, &config).await;
+        assert!(matches!(result, Err(Error::StaleCanary { .. })));
+        assert_eq!(api.requests(Method::POST, PING_PATH).len(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_failing_until_checks_pass_again() {
+        let api = FakeApi::start().await;
+        workflow(&api, "published");
+        api.respond_once(
+            Method::GET,
+            WORKFLOW_PATH,
+            StatusCode::OK,
+            json!({"friendly_name": "Call on duty", "status": "draft"}),
+        );
+        accept_pings(&api);
+        let mut config = api.config();
+        config.opsgenie_config.heartbeat = Some("wygc".to_string());
+        let heartbeat = Heartbeat::default();
+        tokio::spawn(run(
+            SharedConfig::new(config),
+            reqwest::Client::new(),
+            OpsgenieCache::default(),
+            heartbeat.clone(),
+        ));
+
+        // The clock only moves once nothing else can run, so keep busy while waiting
+        while !heartbeat.is_failing() {
+            tokio::task::yield_now().await;
+        }
+        assert!(api.requests(Method::POST, PING_PATH).is_empty());
+
+        tokio::time::advance(Duration::from_secs(60)).await;
+        while heartbeat.is_failing() {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(api.requests(Method::POST, PING_PATH).len(), 1);
+    }
+}
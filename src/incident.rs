@@ -0,0 +1,203 @@
+use crate::config::Config;
+use crate::opsgenie::{
+    create_alert, update_alert, AlertPriority, AlertUpdate, CreateAlertRequest, Responder,
+    ALERT_SOURCE,
+};
+use crate::privacy::MaskPhoneNumbers;
+use crate::twilio::{AlertResult, DialNumberResult};
+use crate::{twilio, AlertTarget};
+use reqwest::Client;
+
+/// Message of created alerts if the caller didn't pass one
+static DEFAULT_ALERT_MESSAGE: &str = "Alert triggered via whoyougonnacall";
+
+/// Create an OpsGenie alert for people that are about to be called, if
+/// `WYGC_OPSGENIE_CREATE_ALERTS` is enabled.
+///
+/// Returns the alias if the alert was created. Failing to create it doesn't stop anyone from
+/// being called, the error is logged instead.
+pub async fn open(
+    alias: &str,
+    target: &AlertTarget,
+    message: Option<&str>,
+    priority: Option<AlertPriority>,
+    caller: &str,
+    http: &Client,
+    config: &Config,
+) -> Option<String> {
+    let opsgenie_config = &config.opsgenie_config;
+    if !opsgenie_config.create_alerts {
+        return None;
+    }
+
+    let request = CreateAlertRequest {
+        alias,
+        message: message.unwrap_or(DEFAULT_ALERT_MESSAGE),
+        priority: priority.unwrap_or(opsgenie_config.alert_priority),
+        responders: vec![Responder::from(target)],
+        user: caller,
+        source: ALERT_SOURCE,
+    };
+    match create_alert(&request, http, config).await {
+        Ok(()) => {
+            tracing::info!(alias, %target, "Created OpsGenie alert");
+            Some(alias.to_string())
+        }
+        Err(e) => {
+            tracing::warn!(
+                error = &e as &dyn std::error::Error,
+                alias,
+                %target,
+                "Failed to create OpsGenie alert, calling people regardless"
+            );
+            None
+        }
+    }
+}
+
+/// Add a note with the outcome of calling someone to an alert, failures are logged
+pub async fn record_calls(
+    alias: &str,
+    called: &str,
+    result: &Result<AlertResult, twilio::Error>,
+    http: &Client,
+    config: &Config,
+) {
+    let note = describe_calls(called, result, config.mask_phone_numbers);
+    if let Err(e) = update_alert(
+        alias,
+        AlertUpdate::AddNote,
+        &note,
+        ALERT_SOURCE,
+        http,
+        config,
+    )
+    .await
+    {
+        tracing::warn!(
+            error = &e as &dyn std::error::Error,
+            alias,
+            "Failed to add call outcome to OpsGenie alert"
+        );
+    }
+}
+
+/// Acknowledge or close an alert on behalf of `caller`
+pub async fn update(
+    alias: &str,
+    update: AlertUpdate,
+    caller: &str,
+    http: &Client,
+    config: &Config,
+) -> Result<(), crate::opsgenie::Error> {
+    let note = match update {
+        AlertUpdate::Acknowledge => format!("Acknowledged by {caller} via {ALERT_SOURCE}"),
+        AlertUpdate::Close => format!("Closed by {caller} via {ALERT_SOURCE}"),
+        AlertUpdate::AddNote => format!("Updated by {caller} via {ALERT_SOURCE}"),
+    };
+    update_alert(alias, update, &note, caller, http, config).await?;
+    tracing::info!(alias, ?update, caller, "Updated OpsGenie alert");
+    Ok(())
+}
+
+/// Numbers are masked the same way as in logs, as notes are visible to everyone in OpsGenie
+fn describe_calls(
+    called: &str,
+    result: &Result<AlertResult, twilio::Error>,
+    mask_phone_numbers: bool,
+) -> String {
+    let alert_result = match result {
+        Ok(alert_result) => alert_result,
+        Err(e) => return format!("Calling {called} failed: {e}"),
+    };
+
+    let outcomes = alert_result
+        .detailed_result
+        .iter()
+        .map(|dialed| {
+            let mut dialed = dialed.clone();
+            if mask_phone_numbers {
+                dialed.mask_phone_numbers();
+            }
+            match dialed {
+                DialNumberResult::Success { number } => format!("{number} was called"),
+                DialNumberResult::Failure { number, error } => {
+                    format!("{number} failed ({error})")
+                }
+                DialNumberResult::Unknown { number, status } => {
+                    format!("{number} has status {status}")
+                }
+            }
+        })
+        .collect::<Vec<_>>();
+    format!("Called {called}: {}", outcomes.join(", "))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{describe_calls, open, DEFAULT_ALERT_MESSAGE};
+    use crate::fake_api::FakeApi;
+    use crate::twilio::{AlertResult, DialNumberResult, OverallResult};
+    use crate::{AlertTarget, Schedule, ScheduleRequestByName};
+    use axum::http::{Method, StatusCode};
+    use rstest::rstest;
+    use serde_json::{json, Value};
+
+    #[test]
+    fn test_describe_calls() {
+        let result = Ok(AlertResult {
+            overall_result: OverallResult::PartialSuccess,
+            detailed_result: vec![
+                DialNumberResult::Success {
+                    number: "+49123456123".to_string(),
+                },
+                DialNumberResult::Failure {
+                    number: "+49123456456".to_string(),
+                    error: "busy".to_string(),
+                },
+            ],
+        });
+        assert_eq!(
+            describe_calls("schedule [ops]", &result, true),
+            "Called schedule [ops]: +49******123 was called, +49******456 failed (busy)"
+        );
+        assert_eq!(
+            describe_calls("schedule [ops]", &result, false),
+            "Called schedule [ops]: +49123456123 was called, +49123456456 failed (busy)"
+        );
+    }
+
+    #[rstest]
+    #[case(Some("db01 is down"), "db01 is down")]
+    #[case(None, DEFAULT_ALERT_MESSAGE)]
+    #[tokio::test]
+    async fn test_open_uses_message(#[case] message: Option<&str>, #[case] expected: &str) {
+        let api = FakeApi::start().await;
+        api.respond(
+            Method::POST,
+            "/v2/alerts",
+            StatusCode::ACCEPTED,
+            json!({"requestId": "1"}),
+        );
+        let mut config = api.config();
+        config.opsgenie_config.create_alerts = true;
+        let target = AlertTarget::Schedule(Schedule::ScheduleByName(ScheduleRequestByName {
+            name: "ops".to_string(),
+        }));
+
+        let alias = open(
+            "outage",
+            &target,
+            message,
+            None,
+            "jane",
+            &reqwest::Client::new(),
+            &config,
+        )
+        .await;
+        assert_eq!(alias.as_deref(), Some("outage"));
+        let requests = api.requests(Method::POST, "/v2/alerts");
+        let body = serde_json::from_str::<Value>(&requests[0].body).unwrap();
+        assert_eq!(body["message"], expected);
+    }
+}
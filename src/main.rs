@@ -1,18 +1,67 @@
+mod audit;
+mod auth;
+mod cache;
+mod cli;
 mod config;
+mod escalation;
+#[cfg(test)]
+mod fake_api;
+mod heartbeat;
 mod http_error;
+mod incident;
+mod notification;
+mod oidc;
 mod opsgenie;
+mod overrides;
+mod phone;
+mod privacy;
+mod ratelimit;
+mod reload;
+mod routing;
+mod slack;
+mod snapshot;
+mod timeline;
 mod twilio;
 mod util;
+mod webhook;
 
-use crate::config::{enable_log_exporter, enable_trace_exporter, Config, ConfigError};
-use crate::opsgenie::{get_oncall_number, UserPhoneNumber};
-use crate::twilio::{alert, AlertResult};
+use crate::audit::{AuditAction, AuditEvent, AuditLog};
+use crate::auth::{Caller, Scope};
+use crate::cache::OpsgenieCache;
+use crate::cli::{Cli, Command};
+use crate::config::{
+    enable_log_exporter, enable_trace_exporter, Config, ConfigError, SLACK_BASEURL_ENVNAME,
+};
+use crate::escalation::{plan_escalation, EscalationRunner, EscalationStatus};
+use crate::heartbeat::Heartbeat;
+use crate::notification::{apply_notification_rules, call_now, DelayedCall, DelayedCallRunner};
+use crate::oidc::JwksCache;
+use crate::opsgenie::{
+    check_alert_source, get_schedule_id, get_shift_oncall_number, AlertPriority, AlertUpdate,
+    FailedLookup, UserContacts,
+};
+use crate::overrides::{OverrideRequest, OverrideResult};
+use crate::privacy::{loggable_numbers, should_mask, MaskPhoneNumbers};
+use crate::ratelimit::RateLimiter;
+use crate::reload::SharedConfig;
+use crate::routing::get_team_oncall;
+use crate::snapshot::SnapshotInfo;
+use crate::timeline::{
+    get_schedule_timeline, get_user_timeline, timeline_range, to_icalendar, ScheduleTimeline, Shift,
+};
+use crate::twilio::AlertResult;
+use crate::webhook::{CalledResponders, WebhookPayload, WebhookResult};
 use crate::StartupError::{InitializeTelemetry, ParseConfig};
 use axum::body::Bytes;
-use axum::extract::Query;
+use axum::extract::{ConnectInfo, Extension, Path, Query};
+use axum::http::header::CONTENT_TYPE;
 use axum::http::HeaderMap;
-use axum::routing::get;
+use axum::middleware::from_fn_with_state;
+use axum::response::IntoResponse;
+use axum::routing::{delete, get, post};
 use axum::{extract::State, Json, Router};
+use chrono::{DateTime, FixedOffset, Utc};
+use clap::Parser;
 use futures::{future, pin_mut, FutureExt};
 use reqwest::{ClientBuilder, Url};
 use serde::{Deserialize, Serialize};
@@ -24,6 +73,7 @@ use std::env;
 use std::env::var_os;
 use std::ffi::OsString;
 use std::fmt::{Debug, Display, Formatter};
+use std::net::SocketAddr;
 use std::process::{ExitCode, Termination};
 use std::str::ParseBoolError;
 use std::time::Duration;
@@ -34,10 +84,40 @@ use tracing::{instrument, Value};
 
 pub const APP_NAME: &str = "who-you-gonna-call";
 
+static ICALENDAR_CONTENT_TYPE: &str = "text/calendar; charset=utf-8";
+
 #[derive(Debug, Clone)]
 struct AppState {
     http: reqwest::Client,
-    config: Config,
+    config: SharedConfig,
+    /// Lives outside of the config, so that it survives reloads
+    opsgenie_cache: OpsgenieCache,
+    jwks_cache: JwksCache,
+    rate_limiter: RateLimiter,
+    audit_log: AuditLog,
+    escalations: EscalationRunner,
+    delayed_calls: DelayedCallRunner,
+    called_responders: CalledResponders,
+    heartbeat: Heartbeat,
+}
+
+#[cfg(test)]
+impl AppState {
+    /// A fresh state around the given config, e.g. [`fake_api::FakeApi::config`]
+    fn for_tests(config: Config) -> Self {
+        AppState {
+            http: reqwest::Client::new(),
+            config: SharedConfig::new(config),
+            opsgenie_cache: Default::default(),
+            jwks_cache: Default::default(),
+            rate_limiter: Default::default(),
+            audit_log: Default::default(),
+            escalations: Default::default(),
+            delayed_calls: Default::default(),
+            called_responders: Default::default(),
+            heartbeat: Default::default(),
+        }
+    }
 }
 
 #[derive(Snafu, Debug)]
@@ -61,6 +141,9 @@ enum StartupError {
     InitializeTelemetry {
         source: stackable_telemetry::tracing::Error,
     },
+
+    #[snafu(display("failed to run command: \n{source}"))]
+    RunCommand { source: cli::Error },
 }
 
 #[derive(Snafu, Debug)]
@@ -70,6 +153,20 @@ enum RequestError {
     OpsGenie { source: opsgenie::Error },
     #[snafu(display("error when communicating with Twilio: : \n{source}"))]
     Twilio { source: twilio::Error },
+    #[snafu(display("request was not authorized: \n{source}"))]
+    Auth { source: auth::Error },
+    #[snafu(display("alert was rejected: \n{source}"))]
+    RateLimit { source: ratelimit::Error },
+    #[snafu(display("error when handling escalation: \n{source}"))]
+    Escalation { source: escalation::Error },
+    #[snafu(display("error when routing to team: \n{source}"))]
+    Routing { source: routing::Error },
+    #[snafu(display("error when looking up timeline: \n{source}"))]
+    Timeline { source: timeline::Error },
+    #[snafu(display("error when changing override: \n{source}"))]
+    Override { source: overrides::Error },
+    #[snafu(display("error when reading snapshot: \n{source}"))]
+    Snapshot { source: snapshot::Error },
 }
 
 impl http_error::Error for RequestError {
@@ -83,13 +180,21 @@ impl http_error::Error for RequestError {
         match self {
             Self::OpsGenie { source } => source.status_code(),
             Self::Twilio { source } => source.status_code(),
+            Self::Auth { source } => source.status_code(),
+            Self::RateLimit { source } => source.status_code(),
+            Self::Escalation { source } => source.status_code(),
+            Self::Routing { source } => source.status_code(),
+            Self::Timeline { source } => source.status_code(),
+            Self::Override { source } => source.status_code(),
+            Self::Snapshot { source } => source.status_code(),
         }
     }
 }
 
 #[tokio::main]
 async fn main() -> ExitCode {
-    match run().await {
+    let cli = Cli::parse();
+    match run(cli).await {
         Ok(_) => ExitCode::SUCCESS,
         Err(e) => {
             // TODO: Not sure whats better here, we log both for now
@@ -100,7 +205,7 @@ async fn main() -> ExitCode {
     }
 }
 
-async fn run() -> Result<(), StartupError> {
+async fn run(cli: Cli) -> Result<(), StartupError> {
     let mut builder = Tracing::builder()
         .service_name(APP_NAME)
         .with_console_output("WYGC_CONSOLE", LevelFilter::INFO);
@@ -117,10 +222,40 @@ async fn run() -> Result<(), StartupError> {
 
     let _tracing_guard = builder.build().init().context(InitializeTelemetrySnafu)?;
 
+    // Running without a subcommand starts the server, which is what the container image does
+    match cli.command.unwrap_or(Command::Serve) {
+        Command::Serve => serve().await,
+        Command::CheckConfig => cli::check_config().context(RunCommandSnafu),
+        Command::Validate(args) => cli::validate(args).await.context(RunCommandSnafu),
+        Command::WhosOnCall { schedule, output } => cli::whos_on_call(schedule, output)
+            .await
+            .context(RunCommandSnafu),
+        Command::Alert {
+            schedule,
+            message,
+            output,
+        } => cli::alert_schedule(schedule, message, output)
+            .await
+            .context(RunCommandSnafu),
+    }
+}
+
+async fn serve() -> Result<(), StartupError> {
     // Create config object and error out if anything goes wrong
     let config = Config::new().context(ParseConfigSnafu)?;
 
     tracing::info!(?config, "Config parsed successfully");
+    // Only warned about on startup, reloads would repeat it every time
+    if config.slack_config.is_none() {
+        tracing::warn!("[{SLACK_BASEURL_ENVNAME}] not set, Slack notifications will be disabled!");
+    }
+    config.auth_config.warn_about_access();
+
+    // Keep the config in a shared container that can be swapped out at runtime when the
+    // configuration changes
+    let config = SharedConfig::new(config);
+    let opsgenie_cache = OpsgenieCache::default();
+    tokio::spawn(reload::watch(config.clone(), opsgenie_cache.clone()));
 
     tracing::debug!("Registering shutdown hook..");
     let shutdown_requested = tokio::signal::ctrl_c().map(|_| ());
@@ -140,24 +275,76 @@ async fn run() -> Result<(), StartupError> {
         .context(ConstructHttpClientSnafu)?;
     tracing::debug!(?http, "Reqwest client initialized");
 
+    tokio::spawn(snapshot::run(
+        config.clone(),
+        http.clone(),
+        opsgenie_cache.clone(),
+    ));
+    let heartbeat = Heartbeat::default();
+    tokio::spawn(heartbeat::run(
+        config.clone(),
+        http.clone(),
+        opsgenie_cache.clone(),
+        heartbeat.clone(),
+    ));
+
     use axum::Router;
     use stackable_webhook::{Options, WebhookServer};
 
+    let state = AppState {
+        http,
+        config: config.clone(),
+        opsgenie_cache,
+        jwks_cache: JwksCache::default(),
+        rate_limiter: RateLimiter::default(),
+        audit_log: AuditLog::default(),
+        escalations: EscalationRunner::default(),
+        delayed_calls: DelayedCallRunner::default(),
+        called_responders: CalledResponders::default(),
+        heartbeat,
+    };
+
+    // Everything above the route_layer requires authentication, the status endpoint is left open
+    // for liveness probes
     let app = Router::new()
         .route("/whosoncall", get(get_person_on_call))
+        .route("/whosoncall/next", get(get_next_person_on_call))
+        .route(
+            "/schedules/:schedule/timeline",
+            get(get_schedule_timeline_json),
+        )
+        .route(
+            "/schedules/:schedule/timeline.ics",
+            get(get_schedule_calendar),
+        )
+        .route("/schedules/:schedule/overrides", post(create_override))
+        .route(
+            "/schedules/:schedule/overrides/:alias",
+            delete(delete_override),
+        )
+        .route("/users/:user/timeline", get(get_user_timeline_json))
+        .route("/users/:user/timeline.ics", get(get_user_calendar))
         .route("/alert", get(alert_on_call))
+        .route("/escalate", get(escalate))
+        .route("/escalations/:key", get(get_escalation_status))
+        .route(
+            "/escalations/:key/acknowledge",
+            post(acknowledge_escalation),
+        )
+        .route("/alerts/:alias/acknowledge", post(acknowledge_alert))
+        .route("/alerts/:alias/close", post(close_alert))
+        .route("/opsgenie/webhook", post(opsgenie_webhook))
+        .route("/admin/snapshot", get(get_snapshot))
+        .route_layer(from_fn_with_state(state.clone(), auth::authenticate))
         .route("/status", get(health))
-        .with_state(AppState {
-            http,
-            config: config.clone(),
-            // TODO: get rid of the .clone() but ... lifetimes ... shared state is not easy
-            //  https://stackoverflow.com/questions/75121484/shared-state-doesnt-work-because-of-lifetimes
-        });
+        .with_state(state);
 
+    // The listener is only bound once, changes to the bind address require a restart
+    let startup_config = config.current();
     let server = WebhookServer::new(
         app,
         Options::builder()
-            .bind_address(config.bind_address, config.bind_port)
+            .bind_address(startup_config.bind_address, startup_config.bind_port)
             .build(),
     );
 
@@ -177,13 +364,149 @@ async fn run() -> Result<(), StartupError> {
     Ok(server.run().await.context(RunServerSnafu)?)
 }
 
-#[derive(Debug, Deserialize, PartialEq, Eq, Hash, Clone)]
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Hash, Clone)]
 #[serde(rename_all = "camelCase", untagged)]
 enum Schedule {
     ScheduleById(ScheduleRequestById),
     ScheduleByName(ScheduleRequestByName),
 }
 
+impl Schedule {
+    /// The identifier and the matching OpsGenie `identifierType`
+    fn identifier(&self) -> (&str, &'static str) {
+        match self {
+            Schedule::ScheduleById(ScheduleRequestById { id }) => (id, "id"),
+            Schedule::ScheduleByName(ScheduleRequestByName { name }) => (name, "name"),
+        }
+    }
+}
+
+impl Display for Schedule {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Schedule::ScheduleById(ScheduleRequestById { id }) => write!(f, "id [{id}]"),
+            Schedule::ScheduleByName(ScheduleRequestByName { name }) => write!(f, "[{name}]"),
+        }
+    }
+}
+
+/// An OpsGenie escalation policy, identified the same way as a [`Schedule`]
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Hash, Clone)]
+#[serde(rename_all = "camelCase", untagged)]
+enum Escalation {
+    EscalationById(EscalationRequestById),
+    EscalationByName(EscalationRequestByName),
+}
+
+impl Escalation {
+    /// The identifier and the matching OpsGenie `identifierType`
+    fn identifier(&self) -> (&str, &'static str) {
+        match self {
+            Escalation::EscalationById(EscalationRequestById { id }) => (id, "id"),
+            Escalation::EscalationByName(EscalationRequestByName { name }) => (name, "name"),
+        }
+    }
+}
+
+impl Display for Escalation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Escalation::EscalationById(EscalationRequestById { id }) => write!(f, "id [{id}]"),
+            Escalation::EscalationByName(EscalationRequestByName { name }) => {
+                write!(f, "[{name}]")
+            }
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Hash, Clone)]
+#[serde(rename_all = "camelCase")]
+struct EscalationRequestByName {
+    name: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Hash, Clone)]
+#[serde(rename_all = "camelCase")]
+struct EscalationRequestById {
+    id: String,
+}
+
+/// An OpsGenie team, it is resolved to a schedule or escalation policy through its routing rules
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Hash, Clone)]
+#[serde(rename_all = "camelCase", untagged)]
+enum Team {
+    TeamById(TeamRequestById),
+    TeamByName(TeamRequestByName),
+}
+
+impl Team {
+    /// The identifier and the matching OpsGenie `teamIdentifierType`
+    fn identifier(&self) -> (&str, &'static str) {
+        match self {
+            Team::TeamById(TeamRequestById { team_id }) => (team_id, "id"),
+            Team::TeamByName(TeamRequestByName { team }) => (team, "name"),
+        }
+    }
+}
+
+impl Display for Team {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Team::TeamById(TeamRequestById { team_id }) => write!(f, "id [{team_id}]"),
+            Team::TeamByName(TeamRequestByName { team }) => write!(f, "[{team}]"),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Hash, Clone)]
+#[serde(rename_all = "camelCase")]
+struct TeamRequestByName {
+    team: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Hash, Clone)]
+#[serde(rename_all = "camelCase")]
+struct TeamRequestById {
+    team_id: String,
+}
+
+/// Who to look up or alert on `/whosoncall` and `/alert`
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Hash, Clone)]
+#[serde(untagged)]
+enum OnCallRequest {
+    Schedule(Schedule),
+    Team(Team),
+}
+
+impl From<&OnCallRequest> for AlertTarget {
+    fn from(request: &OnCallRequest) -> Self {
+        match request {
+            OnCallRequest::Schedule(schedule) => AlertTarget::Schedule(schedule.clone()),
+            OnCallRequest::Team(team) => AlertTarget::Team(team.clone()),
+        }
+    }
+}
+
+/// Everything that people can be alerted through, rate limits and audit events are tracked per
+/// target
+#[derive(Debug, Serialize, PartialEq, Eq, Hash, Clone)]
+#[serde(rename_all = "camelCase")]
+enum AlertTarget {
+    Schedule(Schedule),
+    Escalation(Escalation),
+    Team(Team),
+}
+
+impl Display for AlertTarget {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AlertTarget::Schedule(schedule) => write!(f, "schedule {schedule}"),
+            AlertTarget::Escalation(escalation) => write!(f, "escalation policy {escalation}"),
+            AlertTarget::Team(team) => write!(f, "team {team}"),
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Hash, Clone)]
 #[serde(rename_all = "camelCase")]
 struct ScheduleRequestByName {
@@ -201,14 +524,231 @@ struct ScheduleRequestById {
 struct AlertInfo {
     username: String,
     phone_number: String,
-    full_information: Vec<UserPhoneNumber>,
+    full_information: Vec<UserContacts>,
+    /// Start of the shift of `username`, only set when looking up a shift other than the current
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    shift_start: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    shift_end: Option<DateTime<Utc>>,
+    /// OpsGenie couldn't be reached, this is who was on call and their numbers when it last
+    /// could be
+    #[serde(default)]
+    stale: bool,
+    /// People on call whose phone numbers couldn't be looked up and who are not called
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    failed_lookups: Vec<FailedLookup>,
+}
+
+impl AlertInfo {
+    /// All phone numbers of all people on call that get rung right away when alerting
+    fn phone_numbers(&self) -> Vec<String> {
+        self.full_information
+            .iter()
+            .flat_map(UserContacts::phone_numbers)
+            .collect()
+    }
+
+    /// Numbers that notification rules only want rung after a while and that aren't rung right
+    /// away anyway, by their delay
+    fn delayed_calls(&self) -> Vec<DelayedCall> {
+        let numbers = self.phone_numbers();
+        let mut delayed_calls = self
+            .full_information
+            .iter()
+            .flat_map(UserContacts::delayed_phone_numbers)
+            .filter(|(_, number)| !numbers.contains(number))
+            .map(|(after_seconds, number)| DelayedCall {
+                after_seconds,
+                number,
+            })
+            .collect::<Vec<_>>();
+        // Every number is only rung once, as early as any rule wants it
+        delayed_calls.sort_by(|a, b| {
+            a.number
+                .cmp(&b.number)
+                .then(a.after_seconds.cmp(&b.after_seconds))
+        });
+        delayed_calls.dedup_by(|later, earlier| later.number == earlier.number);
+        delayed_calls.sort_by_key(|call| call.after_seconds);
+        delayed_calls
+    }
+}
+
+#[derive(Debug, Deserialize, PartialEq, Eq, Hash, Clone)]
+#[serde(rename_all = "camelCase")]
+struct ShiftParameters {
+    /// RFC 3339 timestamp to look up the shift for instead of now
+    date: Option<DateTime<FixedOffset>>,
+}
+
+impl ShiftParameters {
+    fn date(&self) -> Option<DateTime<Utc>> {
+        self.date.map(|date| date.with_timezone(&Utc))
+    }
+}
+
+/// How the schedule in the path of `/schedules/<schedule>/...` is identified
+#[derive(Debug, Deserialize, Default, PartialEq, Eq, Hash, Clone, Copy)]
+#[serde(rename_all = "camelCase")]
+enum IdentifierType {
+    #[default]
+    Name,
+    Id,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Eq, Hash, Clone)]
+#[serde(rename_all = "camelCase")]
+struct SchedulePathParameters {
+    #[serde(default)]
+    identifier_type: IdentifierType,
+}
+
+impl SchedulePathParameters {
+    fn schedule(&self, identifier: String) -> Schedule {
+        match self.identifier_type {
+            IdentifierType::Name => {
+                Schedule::ScheduleByName(ScheduleRequestByName { name: identifier })
+            }
+            IdentifierType::Id => Schedule::ScheduleById(ScheduleRequestById { id: identifier }),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, PartialEq, Eq, Hash, Clone)]
+#[serde(rename_all = "camelCase")]
+struct TimelineParameters {
+    /// RFC 3339 timestamp, defaults to now
+    from: Option<DateTime<FixedOffset>>,
+    /// RFC 3339 timestamp, defaults to four weeks after `from`
+    to: Option<DateTime<FixedOffset>>,
+    /// Only include the shifts of this user, only used for schedule timelines
+    user: Option<String>,
+}
+
+impl TimelineParameters {
+    fn range(&self) -> Result<(DateTime<Utc>, DateTime<Utc>), RequestError> {
+        timeline_range(
+            self.from.map(|from| from.with_timezone(&Utc)),
+            self.to.map(|to| to.with_timezone(&Utc)),
+            Utc::now(),
+        )
+        .context(request_error::TimelineSnafu)
+    }
+}
+
+#[derive(Debug, Deserialize, PartialEq, Eq, Hash, Clone)]
+#[serde(rename_all = "camelCase")]
+struct AlertParameters {
+    /// Passed on to the Twilio workflow, e.g. to be read out on the call
+    message: Option<String>,
+    /// Alias of the OpsGenie alert that is created for this alert, generated if not set
+    dedup_key: Option<String>,
+    /// Priority of the OpsGenie alert, defaults to `WYGC_OPSGENIE_ALERT_PRIORITY`
+    priority: Option<AlertPriority>,
+}
+
+/// The OpsGenie alert that people are called for
+#[derive(Debug, PartialEq, Eq, Clone)]
+enum OpsgenieAlert {
+    /// Created before calling if `WYGC_OPSGENIE_CREATE_ALERTS` is enabled, its alias is the
+    /// `dedupKey` of the request
+    Create,
+    /// Already exists in OpsGenie with this alias, because OpsGenie told us about it
+    Existing(String),
+}
+
+impl OpsgenieAlert {
+    /// The alias that identifies the calls, and the alias of the OpsGenie alert that call
+    /// outcomes are added to if there is one
+    #[allow(clippy::too_many_arguments)]
+    async fn open(
+        &self,
+        dedup_key: Option<&str>,
+        target: &AlertTarget,
+        message: Option<&str>,
+        priority: Option<AlertPriority>,
+        caller: &Caller,
+        state: &AppState,
+        config: &Config,
+    ) -> (String, Option<String>) {
+        match self {
+            OpsgenieAlert::Create => {
+                let alias = dedup_key
+                    .map(str::to_string)
+                    .unwrap_or_else(|| state.escalations.generate_key());
+                let opsgenie_alias = incident::open(
+                    &alias,
+                    target,
+                    message,
+                    priority,
+                    &caller.name,
+                    &state.http,
+                    config,
+                )
+                .await;
+                (alias, opsgenie_alias)
+            }
+            // Notes are only added with `WYGC_OPSGENIE_CREATE_ALERTS`, as the API key may not be
+            // allowed to update alerts otherwise
+            OpsgenieAlert::Existing(alias) => (
+                alias.clone(),
+                config.opsgenie_config.create_alerts.then(|| alias.clone()),
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct AlertResponse {
+    #[serde(flatten)]
+    result: AlertResult,
+    /// Alias of the OpsGenie alert that was created, see `WYGC_OPSGENIE_CREATE_ALERTS`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    opsgenie_alias: Option<String>,
+    /// Numbers that notification rules want rung later, acknowledging or closing the alert with
+    /// `delayedCallsKey` as alias stops them
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    delayed_calls: Vec<DelayedCall>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    delayed_calls_key: Option<String>,
+}
+
+impl MaskPhoneNumbers for AlertResponse {
+    fn mask_phone_numbers(&mut self) {
+        self.result.mask_phone_numbers();
+        self.delayed_calls
+            .iter_mut()
+            .for_each(MaskPhoneNumbers::mask_phone_numbers);
+    }
+}
+
+#[derive(Debug, Deserialize, PartialEq, Eq, Hash, Clone)]
+#[serde(rename_all = "camelCase")]
+struct EscalationParameters {
+    /// Passed on to the Twilio workflow for every call of the escalation
+    message: Option<String>,
+    /// Identifies the incident, triggering an escalation with a key that is already being called
+    /// doesn't start the calls again. Also the alias of the OpsGenie alert.
+    dedup_key: Option<String>,
+    /// Priority of the OpsGenie alert, defaults to `WYGC_OPSGENIE_ALERT_PRIORITY`
+    priority: Option<AlertPriority>,
 }
 
 #[instrument(name = "health_check")]
-async fn health() -> Result<Json<Status>, http_error::JsonResponse<RequestError>> {
-    tracing::info!("Responding healthy to healthcheck");
+async fn health(
+    State(state): State<AppState>,
+) -> Result<Json<Status>, http_error::JsonResponse<RequestError>> {
+    // We still answer, but can't call anyone if the last heartbeat check failed
+    let health = if state.heartbeat.is_failing() {
+        Health::Sick
+    } else {
+        Health::Healthy
+    };
+    tracing::info!(?health, "Responding to healthcheck");
     Ok(Json(Status {
-        health: Health::Healthy,
+        health,
+        config_reload_failures: state.config.reload_failures(),
     }))
 }
 
@@ -216,6 +756,7 @@ async fn health() -> Result<Json<Status>, http_error::JsonResponse<RequestError>
 #[serde(rename_all = "camelCase")]
 pub struct Status {
     health: Health,
+    config_reload_failures: u64,
 }
 
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Hash, Clone)]
@@ -228,47 +769,1288 @@ pub enum Health {
 #[instrument(name = "who_is_on_call")]
 async fn get_person_on_call(
     State(state): State<AppState>,
-    Query(requested_schedule): Query<Schedule>,
+    Extension(caller): Extension<Caller>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    Query(requested_schedule): Query<OnCallRequest>,
+    Query(shift_parameters): Query<ShiftParameters>,
+    headers: HeaderMap,
+) -> Result<Json<AlertInfo>, http_error::JsonResponse<RequestError>> {
+    let shift = match shift_parameters.date() {
+        Some(date) => Shift::At(date),
+        None => Shift::Current,
+    };
+    audited_look_up_on_call(
+        state,
+        caller,
+        connect_info,
+        requested_schedule,
+        shift,
+        headers,
+    )
+    .await
+}
+
+#[instrument(name = "who_is_on_call_next")]
+async fn get_next_person_on_call(
+    State(state): State<AppState>,
+    Extension(caller): Extension<Caller>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    Query(requested_schedule): Query<OnCallRequest>,
+    Query(shift_parameters): Query<ShiftParameters>,
     headers: HeaderMap,
 ) -> Result<Json<AlertInfo>, http_error::JsonResponse<RequestError>> {
-    let AppState { http, config } = state;
+    audited_look_up_on_call(
+        state,
+        caller,
+        connect_info,
+        requested_schedule,
+        Shift::Next(shift_parameters.date()),
+        headers,
+    )
+    .await
+}
+
+async fn audited_look_up_on_call(
+    state: AppState,
+    caller: Caller,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    requested_schedule: OnCallRequest,
+    shift: Shift,
+    headers: HeaderMap,
+) -> Result<Json<AlertInfo>, http_error::JsonResponse<RequestError>> {
+    let config = state.config.current();
+    let mut audit_event = AuditEvent::new(
+        AuditAction::Lookup,
+        &caller,
+        connect_info,
+        &headers,
+        &AlertTarget::from(&requested_schedule),
+    );
+
+    let result = look_up_on_call(
+        &state,
+        &config,
+        &caller,
+        &requested_schedule,
+        shift,
+        &mut audit_event,
+    )
+    .await;
+
+    audit_event.finish(&result);
+    state.audit_log.record(audit_event, &config).await;
+    Ok(Json(result?))
+}
+
+async fn look_up_on_call(
+    state: &AppState,
+    config: &Config,
+    caller: &Caller,
+    requested_schedule: &OnCallRequest,
+    shift: Shift,
+    audit_event: &mut AuditEvent,
+) -> Result<AlertInfo, RequestError> {
+    caller
+        .require(Scope::ReadOnCall)
+        .context(request_error::AuthSnafu)?;
     tracing::info!(
         ?requested_schedule,
+        ?shift,
         "Got request to look up on call persons for schedule"
     );
+    let mut alert_info = get_on_call(
+        requested_schedule,
+        shift,
+        &state.http,
+        &state.opsgenie_cache,
+        config,
+    )
+    .await?;
+    audit_event.resolved(&alert_info);
+
+    if should_mask(config, caller) {
+        alert_info.mask_phone_numbers();
+    }
+    Ok(alert_info)
+}
+
+#[instrument(name = "schedule_timeline")]
+async fn get_schedule_timeline_json(
+    State(state): State<AppState>,
+    Extension(caller): Extension<Caller>,
+    Path(schedule): Path<String>,
+    Query(path_parameters): Query<SchedulePathParameters>,
+    Query(parameters): Query<TimelineParameters>,
+) -> Result<Json<ScheduleTimeline>, http_error::JsonResponse<RequestError>> {
+    let schedule = path_parameters.schedule(schedule);
     Ok(Json(
-        get_oncall_number(&requested_schedule, &http, &config)
-            .await
-            .context(request_error::OpsGenieSnafu)?,
+        look_up_schedule_timeline(&state, &caller, &schedule, &parameters).await?,
+    ))
+}
+
+#[instrument(name = "schedule_calendar")]
+async fn get_schedule_calendar(
+    State(state): State<AppState>,
+    Extension(caller): Extension<Caller>,
+    Path(schedule): Path<String>,
+    Query(path_parameters): Query<SchedulePathParameters>,
+    Query(parameters): Query<TimelineParameters>,
+) -> Result<impl IntoResponse, http_error::JsonResponse<RequestError>> {
+    let schedule = path_parameters.schedule(schedule);
+    let timeline = look_up_schedule_timeline(&state, &caller, &schedule, &parameters).await?;
+    Ok(calendar_response(
+        &timeline,
+        &format!("On call for {}", schedule.identifier().0),
     ))
 }
 
+async fn look_up_schedule_timeline(
+    state: &AppState,
+    caller: &Caller,
+    schedule: &Schedule,
+    parameters: &TimelineParameters,
+) -> Result<ScheduleTimeline, RequestError> {
+    caller
+        .require(Scope::ReadOnCall)
+        .context(request_error::AuthSnafu)?;
+    let (from, to) = parameters.range()?;
+    tracing::info!(%schedule, %from, %to, "Got request for schedule timeline");
+    let config = state.config.current();
+    get_schedule_timeline(
+        schedule,
+        from,
+        to,
+        parameters.user.as_deref(),
+        &state.http,
+        &config,
+    )
+    .await
+    .context(request_error::TimelineSnafu)
+}
+
+#[instrument(name = "user_timeline")]
+async fn get_user_timeline_json(
+    State(state): State<AppState>,
+    Extension(caller): Extension<Caller>,
+    Path(user): Path<String>,
+    Query(parameters): Query<TimelineParameters>,
+) -> Result<Json<ScheduleTimeline>, http_error::JsonResponse<RequestError>> {
+    Ok(Json(
+        look_up_user_timeline(&state, &caller, &user, &parameters).await?,
+    ))
+}
+
+#[instrument(name = "user_calendar")]
+async fn get_user_calendar(
+    State(state): State<AppState>,
+    Extension(caller): Extension<Caller>,
+    Path(user): Path<String>,
+    Query(parameters): Query<TimelineParameters>,
+) -> Result<impl IntoResponse, http_error::JsonResponse<RequestError>> {
+    let timeline = look_up_user_timeline(&state, &caller, &user, &parameters).await?;
+    Ok(calendar_response(
+        &timeline,
+        &format!("On call shifts of {user}"),
+    ))
+}
+
+async fn look_up_user_timeline(
+    state: &AppState,
+    caller: &Caller,
+    user: &str,
+    parameters: &TimelineParameters,
+) -> Result<ScheduleTimeline, RequestError> {
+    caller
+        .require(Scope::ReadOnCall)
+        .context(request_error::AuthSnafu)?;
+    let (from, to) = parameters.range()?;
+    tracing::info!(user, %from, %to, "Got request for user timeline");
+    let config = state.config.current();
+    get_user_timeline(user, from, to, &state.http, &config)
+        .await
+        .context(request_error::TimelineSnafu)
+}
+
+fn calendar_response(timeline: &ScheduleTimeline, calendar_name: &str) -> impl IntoResponse {
+    (
+        [(CONTENT_TYPE, ICALENDAR_CONTENT_TYPE)],
+        to_icalendar(timeline, calendar_name, Utc::now()),
+    )
+}
+
 #[instrument(name = "alert")]
 async fn alert_on_call(
     State(state): State<AppState>,
-    Query(requested_alert): Query<Schedule>,
-) -> Result<Json<AlertResult>, http_error::JsonResponse<RequestError>> {
-    let AppState { http, config } = state;
-    tracing::info!(?requested_alert, "Got alert request!");
+    Extension(caller): Extension<Caller>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    Query(requested_alert): Query<OnCallRequest>,
+    Query(parameters): Query<AlertParameters>,
+    headers: HeaderMap,
+) -> Result<Json<AlertResponse>, http_error::JsonResponse<RequestError>> {
+    let config = state.config.current();
+    let mut audit_event = AuditEvent::new(
+        AuditAction::Alert,
+        &caller,
+        connect_info,
+        &headers,
+        &AlertTarget::from(&requested_alert),
+    );
 
-    let schedule = requested_alert.clone();
-    let people_to_alert = get_oncall_number(&schedule, &http, &config)
-        .await
-        .context(request_error::OpsGenieSnafu)?;
+    let result = trigger_alert(
+        &state,
+        &config,
+        &caller,
+        &requested_alert,
+        &parameters,
+        &OpsgenieAlert::Create,
+        &mut audit_event,
+    )
+    .await;
+
+    audit_event.finish(&result);
+    state.audit_log.record(audit_event, &config).await;
+    Ok(Json(result?))
+}
+
+async fn trigger_alert(
+    state: &AppState,
+    config: &Config,
+    caller: &Caller,
+    requested_alert: &OnCallRequest,
+    parameters: &AlertParameters,
+    opsgenie_alert: &OpsgenieAlert,
+    audit_event: &mut AuditEvent,
+) -> Result<AlertResponse, RequestError> {
+    caller
+        .require(Scope::TriggerAlert)
+        .context(request_error::AuthSnafu)?;
+    let AppState {
+        http,
+        opsgenie_cache,
+        ..
+    } = state;
+    tracing::info!(
+        caller.name,
+        ?caller.method,
+        ?requested_alert,
+        ?parameters,
+        "Got alert request!"
+    );
+
+    let target = AlertTarget::from(requested_alert);
+    let mut people_to_alert = get_on_call(
+        requested_alert,
+        Shift::Current,
+        http,
+        opsgenie_cache,
+        config,
+    )
+    .await?;
+    // Only counted once we know who to call, requests that fail anyway don't use up the limit
+    check_rate_limit(
+        state,
+        config,
+        caller,
+        opsgenie_alert,
+        &rate_limit_target(requested_alert, http, opsgenie_cache, config).await,
+    )?;
+    let priority = parameters
+        .priority
+        .unwrap_or(config.opsgenie_config.alert_priority);
+    apply_notification_rules(&mut people_to_alert, priority, http, opsgenie_cache, config).await;
+    audit_event.resolved(&people_to_alert);
+
+    let (alias, opsgenie_alias) = opsgenie_alert
+        .open(
+            parameters.dedup_key.as_deref(),
+            &target,
+            parameters.message.as_deref(),
+            parameters.priority,
+            caller,
+            state,
+            config,
+        )
+        .await;
+    if let Some(opsgenie_alias) = &opsgenie_alias {
+        audit_event.opsgenie_alert(opsgenie_alias);
+    }
 
     // Collect all phone number that we need to ring into one vec
-    let numbers: Vec<String> = people_to_alert
-        .full_information
+    let numbers = people_to_alert.phone_numbers();
+    let delayed_calls = people_to_alert.delayed_calls();
+
+    tracing::info!(
+        "Will call these phones: [{:?}]",
+        loggable_numbers(config, &numbers)
+    );
+
+    let delayed_audit_event = audit_event.clone();
+    let result = call_now(
+        &numbers,
+        &delayed_calls,
+        parameters.message.as_deref(),
+        http,
+        config,
+    )
+    .await;
+    if let Some(opsgenie_alias) = &opsgenie_alias {
+        incident::record_calls(opsgenie_alias, &target.to_string(), &result, http, config).await;
+    }
+    let alert_result = result.context(request_error::TwilioSnafu)?;
+    audit_event.dialed(&alert_result);
+
+    let delayed_calls_key = (!delayed_calls.is_empty()).then(|| alias.clone());
+    state.delayed_calls.start(
+        &alias,
+        &alias,
+        delayed_calls.clone(),
+        parameters.message.clone(),
+        opsgenie_alias.clone(),
+        state,
+        delayed_audit_event,
+    );
+
+    let mut response = AlertResponse {
+        result: alert_result,
+        opsgenie_alias,
+        delayed_calls,
+        delayed_calls_key,
+    };
+    if should_mask(config, caller) {
+        response.mask_phone_numbers();
+    }
+    Ok(response)
+}
+
+#[instrument(name = "escalate")]
+async fn escalate(
+    State(state): State<AppState>,
+    Extension(caller): Extension<Caller>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    Query(requested_escalation): Query<Escalation>,
+    Query(parameters): Query<EscalationParameters>,
+    headers: HeaderMap,
+) -> Result<Json<EscalationStatus>, http_error::JsonResponse<RequestError>> {
+    let config = state.config.current();
+    let mut audit_event = AuditEvent::new(
+        AuditAction::Escalate,
+        &caller,
+        connect_info,
+        &headers,
+        &AlertTarget::Escalation(requested_escalation.clone()),
+    );
+
+    let result = start_escalation(
+        &state,
+        &config,
+        &caller,
+        &requested_escalation,
+        parameters,
+        &OpsgenieAlert::Create,
+        &mut audit_event,
+    )
+    .await;
+
+    audit_event.finish(&result);
+    state.audit_log.record(audit_event, &config).await;
+    Ok(Json(result?))
+}
+
+async fn start_escalation(
+    state: &AppState,
+    config: &Config,
+    caller: &Caller,
+    requested_escalation: &Escalation,
+    parameters: EscalationParameters,
+    opsgenie_alert: &OpsgenieAlert,
+    audit_event: &mut AuditEvent,
+) -> Result<EscalationStatus, RequestError> {
+    caller
+        .require(Scope::TriggerAlert)
+        .context(request_error::AuthSnafu)?;
+    tracing::info!(
+        caller.name,
+        ?caller.method,
+        ?requested_escalation,
+        ?parameters,
+        "Got escalation request!"
+    );
+
+    let key = match opsgenie_alert {
+        OpsgenieAlert::Create => parameters
+            .dedup_key
+            .clone()
+            .unwrap_or_else(|| state.escalations.generate_key()),
+        // An alert can have several escalation policies as responders, each is called on its own
+        OpsgenieAlert::Existing(alias) => {
+            format!("{alias}:{}", requested_escalation.identifier().0)
+        }
+    };
+    audit_event.escalation(&key);
+
+    // Monitoring systems tend to send the same incident over and over, that should neither count
+    // against the rate limits nor start the calls from the beginning
+    let mut status = match state.escalations.status(&key).ok() {
+        Some(status) => {
+            status
+                .check_policy(requested_escalation)
+                .context(request_error::EscalationSnafu)?;
+            status
+        }
+        None => {
+            let plan = plan_escalation(requested_escalation, &state.http, config)
+                .await
+                .context(request_error::EscalationSnafu)?;
+            check_rate_limit(
+                state,
+                config,
+                caller,
+                opsgenie_alert,
+                &AlertTarget::Escalation(requested_escalation.clone()),
+            )?;
+            let (alert_alias, opsgenie_alias) = opsgenie_alert
+                .open(
+                    Some(&key),
+                    &AlertTarget::Escalation(requested_escalation.clone()),
+                    parameters.message.as_deref(),
+                    parameters.priority,
+                    caller,
+                    state,
+                    config,
+                )
+                .await;
+            if let Some(opsgenie_alias) = &opsgenie_alias {
+                audit_event.opsgenie_alert(opsgenie_alias);
+            }
+            let status = EscalationStatus {
+                key,
+                escalation: requested_escalation.clone(),
+                policy_id: plan.policy_id,
+                policy_name: plan.policy_name,
+                started_at: chrono::Utc::now().to_rfc3339(),
+                steps: plan.steps,
+                steps_called: 0,
+                message: parameters.message,
+                opsgenie_alias,
+                alert_alias,
+                priority: parameters
+                    .priority
+                    .unwrap_or(config.opsgenie_config.alert_priority),
+            };
+            state
+                .escalations
+                .start(status, state, audit_event.clone())
+                .context(request_error::EscalationSnafu)?
+        }
+    };
+
+    if should_mask(config, caller) {
+        status.mask_phone_numbers();
+    }
+    Ok(status)
+}
+
+#[instrument(name = "escalation_status")]
+async fn get_escalation_status(
+    State(state): State<AppState>,
+    Extension(caller): Extension<Caller>,
+    Path(key): Path<String>,
+) -> Result<Json<EscalationStatus>, http_error::JsonResponse<RequestError>> {
+    caller
+        .require(Scope::ReadOnCall)
+        .context(request_error::AuthSnafu)?;
+    let config = state.config.current();
+    let mut status = state
+        .escalations
+        .status(&key)
+        .context(request_error::EscalationSnafu)?;
+    if should_mask(&config, &caller) {
+        status.mask_phone_numbers();
+    }
+    Ok(Json(status))
+}
+
+/// Response of `POST /escalations/<key>/acknowledge`
+#[derive(Debug, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct EscalationAcknowledgement {
+    /// Missing if the escalation is already over, but calls it delayed were still pending
+    #[serde(flatten)]
+    escalation: Option<EscalationStatus>,
+    /// Calls that notification rules delayed were still pending for the key and are stopped
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    delayed_calls_cancelled: bool,
+}
+
+impl MaskPhoneNumbers for EscalationAcknowledgement {
+    fn mask_phone_numbers(&mut self) {
+        self.escalation
+            .iter_mut()
+            .for_each(MaskPhoneNumbers::mask_phone_numbers);
+    }
+}
+
+#[instrument(name = "acknowledge_escalation")]
+async fn acknowledge_escalation(
+    State(state): State<AppState>,
+    Extension(caller): Extension<Caller>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    Path(key): Path<String>,
+    headers: HeaderMap,
+) -> Result<Json<EscalationAcknowledgement>, http_error::JsonResponse<RequestError>> {
+    let config = state.config.current();
+    let mut audit_event = match state.escalations.status(&key) {
+        Ok(status) => AuditEvent::new(
+            AuditAction::Acknowledge,
+            &caller,
+            connect_info,
+            &headers,
+            &AlertTarget::Escalation(status.escalation),
+        ),
+        Err(_) => AuditEvent::for_escalation(
+            AuditAction::Acknowledge,
+            &caller,
+            connect_info,
+            &headers,
+            &key,
+        ),
+    };
+    audit_event.escalation(&key);
+
+    let result: Result<_, RequestError> = async {
+        caller
+            .require(Scope::TriggerAlert)
+            .context(request_error::AuthSnafu)?;
+        // Delayed calls outlive the escalation itself, once the last step was called the
+        // escalation is gone but numbers may still be rung later
+        let delayed_calls_cancelled = state.delayed_calls.cancel(&key);
+        let escalation = match state.escalations.acknowledge(&key) {
+            Ok(status) => Some(status),
+            Err(_) if delayed_calls_cancelled => None,
+            Err(e) => return Err(e).context(request_error::EscalationSnafu),
+        };
+        tracing::info!(
+            caller.name,
+            key,
+            delayed_calls_cancelled,
+            "Got escalation acknowledgement"
+        );
+
+        // Nobody is called anymore at this point, so failing to acknowledge the OpsGenie alert
+        // as well only gets logged
+        if let Some(EscalationStatus {
+            opsgenie_alias: Some(opsgenie_alias),
+            ..
+        }) = &escalation
+        {
+            audit_event.opsgenie_alert(opsgenie_alias);
+            if let Err(e) = incident::update(
+                opsgenie_alias,
+                AlertUpdate::Acknowledge,
+                &caller.name,
+                &state.http,
+                &config,
+            )
+            .await
+            {
+                tracing::warn!(
+                    error = &e as &dyn std::error::Error,
+                    opsgenie_alias,
+                    "Failed to acknowledge OpsGenie alert"
+                );
+            }
+        }
+        Ok(EscalationAcknowledgement {
+            escalation,
+            delayed_calls_cancelled,
+        })
+    }
+    .await;
+
+    audit_event.finish(&result);
+    state.audit_log.record(audit_event, &config).await;
+    let mut acknowledgement = result?;
+    if should_mask(&config, &caller) {
+        acknowledgement.mask_phone_numbers();
+    }
+    Ok(Json(acknowledgement))
+}
+
+#[instrument(name = "snapshot")]
+async fn get_snapshot(
+    State(state): State<AppState>,
+    Extension(caller): Extension<Caller>,
+) -> Result<Json<SnapshotInfo>, http_error::JsonResponse<RequestError>> {
+    caller
+        .require(Scope::Admin)
+        .context(request_error::AuthSnafu)?;
+    let config = state.config.current();
+    let mut snapshot_info = snapshot::read(&config)
+        .await
+        .context(request_error::SnapshotSnafu)?;
+    if should_mask(&config, &caller) {
+        snapshot_info.mask_phone_numbers();
+    }
+    Ok(Json(snapshot_info))
+}
+
+/// Response of `/alerts/<alias>/acknowledge` and `/alerts/<alias>/close`
+#[derive(Debug, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct OpsgenieAlertResult {
+    opsgenie_alias: String,
+    /// Escalations for the alert, which were stopped as well
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    escalations: Vec<EscalationStatus>,
+    /// Calls that notification rules delayed were still pending for the alias and are stopped
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    delayed_calls_cancelled: bool,
+}
+
+impl MaskPhoneNumbers for OpsgenieAlertResult {
+    fn mask_phone_numbers(&mut self) {
+        self.escalations
+            .iter_mut()
+            .for_each(MaskPhoneNumbers::mask_phone_numbers);
+    }
+}
+
+/// The keys of the escalations, for the audit log
+fn escalation_keys(escalations: &[EscalationStatus]) -> String {
+    escalations
         .iter()
-        .map(|person| person.phone.clone())
-        .flatten()
-        .collect();
+        .map(|escalation| escalation.key.as_str())
+        .collect::<Vec<_>>()
+        .join(",")
+}
 
-    tracing::info!("Will call these phones: [{:?}]", numbers);
+#[instrument(name = "acknowledge_alert")]
+async fn acknowledge_alert(
+    State(state): State<AppState>,
+    Extension(caller): Extension<Caller>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    Path(alias): Path<String>,
+    headers: HeaderMap,
+) -> Result<Json<OpsgenieAlertResult>, http_error::JsonResponse<RequestError>> {
+    let audit_event = AuditEvent::for_opsgenie_alert(
+        AuditAction::Acknowledge,
+        &caller,
+        connect_info,
+        &headers,
+        &alias,
+    );
+    update_opsgenie_alert(state, caller, alias, AlertUpdate::Acknowledge, audit_event).await
+}
 
+#[instrument(name = "close_alert")]
+async fn close_alert(
+    State(state): State<AppState>,
+    Extension(caller): Extension<Caller>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    Path(alias): Path<String>,
+    headers: HeaderMap,
+) -> Result<Json<OpsgenieAlertResult>, http_error::JsonResponse<RequestError>> {
+    let audit_event =
+        AuditEvent::for_opsgenie_alert(AuditAction::Close, &caller, connect_info, &headers, &alias);
+    update_opsgenie_alert(state, caller, alias, AlertUpdate::Close, audit_event).await
+}
+
+async fn update_opsgenie_alert(
+    state: AppState,
+    caller: Caller,
+    alias: String,
+    update: AlertUpdate,
+    mut audit_event: AuditEvent,
+) -> Result<Json<OpsgenieAlertResult>, http_error::JsonResponse<RequestError>> {
+    let config = state.config.current();
+
+    let result: Result<_, RequestError> = async {
+        caller
+            .require(Scope::TriggerAlert)
+            .context(request_error::AuthSnafu)?;
+        tracing::info!(
+            caller.name,
+            alias,
+            ?update,
+            "Got request to update OpsGenie alert"
+        );
+
+        // Once the alert is taken care of nobody else needs to be called
+        let escalations = state.escalations.acknowledge_alert(&alias);
+        if !escalations.is_empty() {
+            audit_event.escalation(&escalation_keys(&escalations));
+        }
+        let delayed_calls_cancelled = state.delayed_calls.cancel_alert(&alias);
+        // Without OpsGenie alerts the alias only names calls of our own, which are stopped now
+        let stopped_calls = !escalations.is_empty() || delayed_calls_cancelled;
+        if config.opsgenie_config.create_alerts || !stopped_calls {
+            // The scope lets callers stop our calls, not take care of alerts of other integrations
+            check_alert_source(&alias, &state.http, &config)
+                .await
+                .context(request_error::OpsGenieSnafu)?;
+            incident::update(&alias, update, &caller.name, &state.http, &config)
+                .await
+                .context(request_error::OpsGenieSnafu)?;
+        }
+        Ok(OpsgenieAlertResult {
+            opsgenie_alias: alias.clone(),
+            escalations,
+            delayed_calls_cancelled,
+        })
+    }
+    .await;
+
+    audit_event.finish(&result);
+    state.audit_log.record(audit_event, &config).await;
+    let mut alert_result = result?;
+    if should_mask(&config, &caller) {
+        alert_result.mask_phone_numbers();
+    }
+    Ok(Json(alert_result))
+}
+
+#[instrument(name = "opsgenie_webhook")]
+async fn opsgenie_webhook(
+    State(state): State<AppState>,
+    Extension(caller): Extension<Caller>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+    Json(payload): Json<WebhookPayload>,
+) -> Result<Json<WebhookResult>, http_error::JsonResponse<RequestError>> {
+    let config = state.config.current();
     Ok(Json(
-        alert(&numbers, &http, &config)
-            .await
-            .context(request_error::TwilioSnafu)?,
+        webhook::handle(&state, &config, &caller, connect_info, &headers, payload).await?,
     ))
 }
+
+#[instrument(name = "create_override")]
+async fn create_override(
+    State(state): State<AppState>,
+    Extension(caller): Extension<Caller>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    Path(schedule): Path<String>,
+    Query(path_parameters): Query<SchedulePathParameters>,
+    headers: HeaderMap,
+    Json(request): Json<OverrideRequest>,
+) -> Result<Json<OverrideResult>, http_error::JsonResponse<RequestError>> {
+    let config = state.config.current();
+    let schedule = path_parameters.schedule(schedule);
+    let audit_event = AuditEvent::new(
+        AuditAction::CreateOverride,
+        &caller,
+        connect_info,
+        &headers,
+        &AlertTarget::Schedule(schedule.clone()),
+    );
+
+    let result = async {
+        caller
+            .require(Scope::ManageOverrides)
+            .context(request_error::AuthSnafu)?;
+        tracing::info!(caller.name, %schedule, ?request, "Got request to create override");
+        overrides::create(
+            &schedule,
+            &request,
+            &state.http,
+            &state.opsgenie_cache,
+            &config,
+        )
+        .await
+        .context(request_error::OverrideSnafu)
+    }
+    .await;
+
+    override_response(&state, &config, &caller, audit_event, result).await
+}
+
+#[instrument(name = "delete_override")]
+async fn delete_override(
+    State(state): State<AppState>,
+    Extension(caller): Extension<Caller>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    Path((schedule, alias)): Path<(String, String)>,
+    Query(path_parameters): Query<SchedulePathParameters>,
+    headers: HeaderMap,
+) -> Result<Json<OverrideResult>, http_error::JsonResponse<RequestError>> {
+    let config = state.config.current();
+    let schedule = path_parameters.schedule(schedule);
+    let audit_event = AuditEvent::new(
+        AuditAction::DeleteOverride,
+        &caller,
+        connect_info,
+        &headers,
+        &AlertTarget::Schedule(schedule.clone()),
+    );
+
+    let result = async {
+        caller
+            .require(Scope::ManageOverrides)
+            .context(request_error::AuthSnafu)?;
+        tracing::info!(caller.name, %schedule, alias, "Got request to delete override");
+        overrides::delete(
+            &schedule,
+            &alias,
+            &state.http,
+            &state.opsgenie_cache,
+            &config,
+        )
+        .await
+        .context(request_error::OverrideSnafu)
+    }
+    .await;
+
+    override_response(&state, &config, &caller, audit_event, result).await
+}
+
+async fn override_response(
+    state: &AppState,
+    config: &Config,
+    caller: &Caller,
+    mut audit_event: AuditEvent,
+    result: Result<OverrideResult, RequestError>,
+) -> Result<Json<OverrideResult>, http_error::JsonResponse<RequestError>> {
+    if let Ok(OverrideResult {
+        on_call: Some(on_call),
+        ..
+    }) = &result
+    {
+        audit_event.resolved(on_call);
+    }
+    audit_event.finish(&result);
+    state.audit_log.record(audit_event, config).await;
+
+    let mut override_result = result?;
+    if should_mask(config, caller) {
+        override_result.mask_phone_numbers();
+    }
+    Ok(Json(override_result))
+}
+
+/// Look up who is on call in a shift of a schedule, or of a team through its routing rules
+async fn get_on_call(
+    request: &OnCallRequest,
+    shift: Shift,
+    http: &reqwest::Client,
+    cache: &OpsgenieCache,
+    config: &Config,
+) -> Result<AlertInfo, RequestError> {
+    match request {
+        OnCallRequest::Schedule(schedule) => {
+            get_shift_oncall_number(schedule, shift, http, cache, config)
+                .await
+                .context(request_error::OpsGenieSnafu)
+        }
+        OnCallRequest::Team(team) => get_team_oncall(team, shift, http, cache, config)
+            .await
+            .context(request_error::RoutingSnafu),
+    }
+}
+
+/// The target that alerts for a request are counted against. Schedules are counted by id, so
+/// that requesting them by name doesn't get them a separate limit.
+async fn rate_limit_target(
+    request: &OnCallRequest,
+    http: &reqwest::Client,
+    cache: &OpsgenieCache,
+    config: &Config,
+) -> AlertTarget {
+    let OnCallRequest::Schedule(schedule) = request else {
+        return AlertTarget::from(request);
+    };
+    match get_schedule_id(schedule, http, cache, config).await {
+        Ok(id) => AlertTarget::Schedule(Schedule::ScheduleById(ScheduleRequestById { id })),
+        Err(e) => {
+            tracing::warn!(
+                error = &e as &dyn std::error::Error,
+                %schedule,
+                "Failed to look up the id of the schedule, counting alerts by its name instead"
+            );
+            AlertTarget::from(request)
+        }
+    }
+}
+
+/// Count an alert against the rate limits, Slack is told the first time a limit is hit.
+///
+/// Only alerts triggered via the API are limited, the CLI stays usable as a last resort. Alerts
+/// that OpsGenie told us about were raised by whatever feeds OpsGenie rather than by the caller,
+/// so they only count against the limit of their target.
+fn check_rate_limit(
+    state: &AppState,
+    config: &Config,
+    caller: &Caller,
+    opsgenie_alert: &OpsgenieAlert,
+    target: &AlertTarget,
+) -> Result<(), RequestError> {
+    let AppState {
+        http, rate_limiter, ..
+    } = state;
+    let limited_caller = match opsgenie_alert {
+        OpsgenieAlert::Create => Some(caller.name.as_str()),
+        OpsgenieAlert::Existing(_) => None,
+    };
+    let rate_limit = rate_limiter.check(limited_caller, target, &config.rate_limit_config);
+    if let Err(error) = &rate_limit {
+        if rate_limiter.should_notify(error, &config.rate_limit_config) {
+            slack::notify(
+                format!(":no_entry: Rejected alert for {target}: {error}"),
+                http,
+                config,
+            );
+        }
+    }
+    rate_limit.context(request_error::RateLimitSnafu)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        acknowledge_alert, acknowledge_escalation, create_override, delete_override,
+        start_escalation, trigger_alert, AlertParameters, AlertResponse, AlertTarget, AppState,
+        Escalation, EscalationParameters, EscalationRequestByName, OnCallRequest, OpsgenieAlert,
+        RequestError, Schedule, SchedulePathParameters, ScheduleRequestById, ScheduleRequestByName,
+    };
+    use crate::audit::{AuditAction, AuditEvent};
+    use crate::auth::{AuthMethod, Caller, Scope};
+    use crate::fake_api::FakeApi;
+    use crate::http_error::Error as _;
+    use crate::overrides::OverrideRequest;
+    use axum::extract::{Path, Query, State};
+    use axum::http::{HeaderMap, Method, StatusCode};
+    use axum::{Extension, Json};
+    use serde_json::json;
+    use std::collections::HashSet;
+    use std::time::Duration;
+
+    fn caller() -> Caller {
+        Caller {
+            name: "monitoring".to_string(),
+            method: AuthMethod::StaticToken,
+            scopes: Scope::all(),
+        }
+    }
+
+    fn by_name(name: &str) -> OnCallRequest {
+        OnCallRequest::Schedule(Schedule::ScheduleByName(ScheduleRequestByName {
+            name: name.to_string(),
+        }))
+    }
+
+    fn by_id(id: &str) -> OnCallRequest {
+        OnCallRequest::Schedule(Schedule::ScheduleById(ScheduleRequestById {
+            id: id.to_string(),
+        }))
+    }
+
+    async fn alert(
+        state: &AppState,
+        request: &OnCallRequest,
+    ) -> Result<AlertResponse, RequestError> {
+        let config = state.config.current();
+        let caller = caller();
+        let mut audit_event = AuditEvent::new(
+            AuditAction::Alert,
+            &caller,
+            None,
+            &HeaderMap::new(),
+            &AlertTarget::from(request),
+        );
+        let parameters = AlertParameters {
+            message: None,
+            dedup_key: None,
+            priority: None,
+        };
+        trigger_alert(
+            state,
+            &config,
+            &caller,
+            request,
+            &parameters,
+            &OpsgenieAlert::Create,
+            &mut audit_event,
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_schedule_limit_applies_to_name_and_id() {
+        let api = FakeApi::start().await;
+        api.on_call("4a2b", "ops", "jane", "+4915112345678");
+        api.accept_calls();
+        let mut config = api.config();
+        config.rate_limit_config.per_target = Some(1);
+        let state = AppState::for_tests(config);
+
+        alert(&state, &by_name("ops")).await.unwrap();
+        let result = alert(&state, &by_id("4a2b")).await;
+        assert!(matches!(result, Err(RequestError::RateLimit { .. })));
+        assert_eq!(api.dialed(), ["+4915112345678"]);
+    }
+
+    #[tokio::test]
+    async fn test_failed_lookup_is_not_counted() {
+        let api = FakeApi::start().await;
+        api.on_call("4a2b", "ops", "jane", "+4915112345678");
+        api.respond_once(
+            Method::GET,
+            "/v2/schedules/4a2b/on-calls",
+            StatusCode::INTERNAL_SERVER_ERROR,
+            json!({"message": "internal error"}),
+        );
+        api.accept_calls();
+        let mut config = api.config();
+        config.rate_limit_config.per_target = Some(1);
+        let state = AppState::for_tests(config);
+
+        let result = alert(&state, &by_id("4a2b")).await;
+        assert!(matches!(result, Err(RequestError::OpsGenie { .. })));
+        alert(&state, &by_id("4a2b")).await.unwrap();
+        assert_eq!(api.dialed(), ["+4915112345678"]);
+    }
+
+    #[tokio::test]
+    async fn test_overrides_require_scope() {
+        let api = FakeApi::start().await;
+        api.respond(
+            Method::POST,
+            "/v2/schedules/ops/overrides",
+            StatusCode::CREATED,
+            json!({"data": {"alias": "sick-day"}}),
+        );
+        api.respond(
+            Method::DELETE,
+            "/v2/schedules/ops/overrides/sick-day",
+            StatusCode::OK,
+            json!({"result": "Deleted"}),
+        );
+        let state = AppState::for_tests(api.config());
+        let mut caller = caller();
+        caller.scopes = HashSet::from([Scope::ReadOnCall, Scope::TriggerAlert]);
+        let parameters = || {
+            Query(SchedulePathParameters {
+                identifier_type: Default::default(),
+            })
+        };
+        let request = || {
+            Json(OverrideRequest {
+                user: "jane".to_string(),
+                start: "2030-06-01T09:00:00+02:00".parse().unwrap(),
+                end: "2030-06-01T17:00:00+02:00".parse().unwrap(),
+                alias: Some("sick-day".to_string()),
+            })
+        };
+
+        let created = create_override(
+            State(state.clone()),
+            Extension(caller.clone()),
+            None,
+            Path("ops".to_string()),
+            parameters(),
+            HeaderMap::new(),
+            request(),
+        )
+        .await;
+        assert!(matches!(
+            created.map_err(|e| e.error),
+            Err(RequestError::Auth { .. })
+        ));
+        let deleted = delete_override(
+            State(state.clone()),
+            Extension(caller.clone()),
+            None,
+            Path(("ops".to_string(), "sick-day".to_string())),
+            parameters(),
+            HeaderMap::new(),
+        )
+        .await;
+        assert!(matches!(
+            deleted.map_err(|e| e.error),
+            Err(RequestError::Auth { .. })
+        ));
+        assert!(api
+            .requests(Method::POST, "/v2/schedules/ops/overrides")
+            .is_empty());
+        assert!(api
+            .requests(Method::DELETE, "/v2/schedules/ops/overrides/sick-day")
+            .is_empty());
+
+        caller.scopes.insert(Scope::ManageOverrides);
+        let created = create_override(
+            State(state.clone()),
+            Extension(caller.clone()),
+            None,
+            Path("ops".to_string()),
+            parameters(),
+            HeaderMap::new(),
+            request(),
+        )
+        .await;
+        assert_eq!(created.ok().unwrap().alias, "sick-day");
+        let deleted = delete_override(
+            State(state),
+            Extension(caller),
+            None,
+            Path(("ops".to_string(), "sick-day".to_string())),
+            parameters(),
+            HeaderMap::new(),
+        )
+        .await;
+        assert_eq!(deleted.ok().unwrap().alias, "sick-day");
+    }
+
+    #[tokio::test]
+    async fn test_only_own_alerts_are_updated() {
+        let api = FakeApi::start().await;
+        for (alias, source) in [("ours", "whoyougonnacall"), ("theirs", "prometheus")] {
+            api.respond(
+                Method::GET,
+                &format!("/v2/alerts/{alias}"),
+                StatusCode::OK,
+                json!({"data": {"alias": alias, "source": source}}),
+            );
+            api.respond(
+                Method::POST,
+                &format!("/v2/alerts/{alias}/acknowledge"),
+                StatusCode::ACCEPTED,
+                json!({"result": "Request will be processed"}),
+            );
+        }
+        let state = AppState::for_tests(api.config());
+        let acknowledge = |alias: &str| {
+            acknowledge_alert(
+                State(state.clone()),
+                Extension(caller()),
+                None,
+                Path(alias.to_string()),
+                HeaderMap::new(),
+            )
+        };
+
+        let result = acknowledge("theirs").await.map_err(|e| e.error);
+        assert!(matches!(result, Err(RequestError::OpsGenie { .. })));
+        assert_eq!(result.err().unwrap().status_code(), StatusCode::FORBIDDEN);
+        assert!(api
+            .requests(Method::POST, "/v2/alerts/theirs/acknowledge")
+            .is_empty());
+
+        let result = acknowledge("ours").await.ok().unwrap();
+        assert_eq!(result.opsgenie_alias, "ours");
+        assert_eq!(
+            api.requests(Method::POST, "/v2/alerts/ours/acknowledge")
+                .len(),
+            1
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_acknowledge_stops_delayed_calls_after_last_step() {
+        let api = FakeApi::start().await;
+        api.respond(
+            Method::GET,
+            "/v2/escalations/ops_escalation",
+            StatusCode::OK,
+            json!({"data": {
+                "id": "9a3f",
+                "name": "ops_escalation",
+                "rules": [{
+                    "condition": "if-not-acked",
+                    "notifyType": "default",
+                    "delay": {"timeAmount": 0, "timeUnit": "minutes"},
+                    "recipient": {"type": "schedule", "id": "1c2d", "name": "ops"},
+                }],
+            }}),
+        );
+        api.on_call("1c2d", "ops", "jane", "+4915112345678");
+        api.contacts("jane", &["+4915112345678", "+4915187654321"]);
+        api.notification_rule(
+            "jane",
+            json!({
+                "name": "Default",
+                "order": 0,
+                "criteria": {"type": "match-all"},
+                "steps": [
+                    {"contact": {"method": "voice", "to": "49-15112345678"}, "enabled": true},
+                    {
+                        "contact": {"method": "voice", "to": "49-15187654321"},
+                        "sendAfter": {"timeAmount": 5, "timeUnit": "minutes"},
+                        "enabled": true
+                    }
+                ]
+            }),
+        );
+        api.accept_calls();
+        let mut config = api.config();
+        config.opsgenie_config.notification_rules = true;
+        let state = AppState::for_tests(config);
+        let config = state.config.current();
+        let escalation = Escalation::EscalationByName(EscalationRequestByName {
+            name: "ops_escalation".to_string(),
+        });
+        let mut audit_event = AuditEvent::new(
+            AuditAction::Escalate,
+            &caller(),
+            None,
+            &HeaderMap::new(),
+            &AlertTarget::Escalation(escalation.clone()),
+        );
+
+        start_escalation(
+            &state,
+            &config,
+            &caller(),
+            &escalation,
+            EscalationParameters {
+                message: None,
+                dedup_key: Some("outage".to_string()),
+                priority: None,
+            },
+            &OpsgenieAlert::Create,
+            &mut audit_event,
+        )
+        .await
+        .unwrap();
+        // Calling the only step ends the escalation, the second number is still to be rung. The
+        // clock only moves once nothing else can run, so keep busy until then.
+        while state.escalations.status("outage").is_ok() {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(api.dialed(), ["+4915112345678"]);
+
+        let result = acknowledge_escalation(
+            State(state.clone()),
+            Extension(caller()),
+            None,
+            Path("outage".to_string()),
+            HeaderMap::new(),
+        )
+        .await;
+        let Json(acknowledgement) = result.map_err(|e| e.error).unwrap();
+        assert!(acknowledgement.escalation.is_none());
+        assert!(acknowledgement.delayed_calls_cancelled);
+
+        tokio::time::advance(Duration::from_secs(10 * 60)).await;
+        for _ in 0..100 {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(api.dialed(), ["+4915112345678"]);
+    }
+
+    #[tokio::test]
+    async fn test_acknowledge_unknown_escalation() {
+        let api = FakeApi::start().await;
+        let state = AppState::for_tests(api.config());
+
+        let result = acknowledge_escalation(
+            State(state),
+            Extension(caller()),
+            None,
+            Path("outage".to_string()),
+            HeaderMap::new(),
+        )
+        .await;
+        assert!(matches!(
+            result.map_err(|e| e.error),
+            Err(RequestError::Escalation { .. })
+        ));
+    }
+}
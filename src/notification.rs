@@ -0,0 +1,513 @@
+use crate::audit::AuditEvent;
+use crate::cache::OpsgenieCache;
+use crate::config::Config;
+use crate::opsgenie::{
+    get_notification_rules, AlertPriority, Contact, ContactMethod, NotificationCondition,
+    NotificationCriteria, NotificationRule, NotificationStep, UserContacts, UserNotificationRules,
+};
+use crate::phone::normalize_phone_number;
+use crate::privacy::{loggable_numbers, mask_phone_number, MaskPhoneNumbers};
+use crate::routing::is_within_time_restriction;
+use crate::twilio::{alert, AlertResult, OverallResult};
+use crate::{incident, twilio, AlertInfo, AppState};
+use chrono::{DateTime, Utc};
+use futures::future::join_all;
+use phonenumber::country;
+use reqwest::Client;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+use tokio::task::AbortHandle;
+use tokio::time::Instant;
+
+static MATCH_ALL_CRITERIA: &str = "match-all";
+static MATCH_ANY_CONDITION_CRITERIA: &str = "match-any-condition";
+static MATCH_ALL_CONDITIONS_CRITERIA: &str = "match-all-conditions";
+
+/// A number that a notification rule only wants rung after a while
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct DelayedCall {
+    /// Seconds after the alert at which the number is rung
+    pub after_seconds: u64,
+    pub number: String,
+}
+
+impl MaskPhoneNumbers for DelayedCall {
+    fn mask_phone_numbers(&mut self) {
+        self.number = mask_phone_number(&self.number);
+    }
+}
+
+/// Decide through which contacts the people in `alert_info` are notified about an alert with the
+/// given priority, if `WYGC_OPSGENIE_NOTIFICATION_RULES` is enabled.
+///
+/// Contacts that the applying rule doesn't use are marked as quiet, the others get the delay of
+/// the step that uses them. People whose rules can't be looked up, or for whom no rule applies,
+/// keep all of their contacts, we'd rather call someone once too often than not at all. People
+/// whose rule doesn't ring any phone asked not to be called, so they are skipped.
+pub async fn apply_notification_rules(
+    alert_info: &mut AlertInfo,
+    priority: AlertPriority,
+    http: &Client,
+    cache: &OpsgenieCache,
+    config: &Config,
+) {
+    let opsgenie_config = &config.opsgenie_config;
+    if !opsgenie_config.notification_rules {
+        return;
+    }
+
+    let lookups = join_all(
+        alert_info
+            .full_information
+            .iter()
+            .map(|person| get_notification_rules(&person.name, http, cache, config)),
+    )
+    .await;
+    let now = Utc::now();
+    for (person, rules) in alert_info.full_information.iter_mut().zip(lookups) {
+        match rules {
+            Ok(rules) => {
+                alert_info.stale |= rules.stale;
+                apply_rules(
+                    person,
+                    &rules.value,
+                    priority,
+                    opsgenie_config.default_phone_region,
+                    now,
+                );
+            }
+            Err(e) => tracing::warn!(
+                error = &e as &dyn std::error::Error,
+                person.name,
+                "Failed to look up notification rules, calling all numbers"
+            ),
+        }
+    }
+}
+
+fn apply_rules(
+    person: &mut UserContacts,
+    rules: &UserNotificationRules,
+    priority: AlertPriority,
+    default_phone_region: Option<country::Id>,
+    now: DateTime<Utc>,
+) {
+    let Some(rule) = select_rule(rules, priority, now) else {
+        tracing::info!(
+            person.name,
+            ?priority,
+            "No notification rule applies, calling all numbers"
+        );
+        return;
+    };
+
+    let mut contacts = person.contacts.clone();
+    for contact in &mut contacts {
+        match rule
+            .steps
+            .iter()
+            .find(|step| step.enabled && uses_contact(step, contact, default_phone_region))
+        {
+            Some(step) => {
+                contact.send_after_seconds = step
+                    .send_after
+                    .as_ref()
+                    .map_or(0, |delay| delay.as_duration().as_secs());
+            }
+            None => contact.quiet = true,
+        }
+    }
+
+    // We can only ring phones, people that are only notified through the app or by mail don't
+    // hear from us at all
+    if !contacts
+        .iter()
+        .any(|contact| contact.is_usable() && contact.method.is_phone())
+    {
+        tracing::info!(
+            person.name,
+            rule.name,
+            "Notification rule doesn't ring any phone, not calling them"
+        );
+    } else {
+        tracing::debug!(person.name, rule.name, "Applied notification rule");
+    }
+    person.contacts = contacts;
+    person.notification_rule = Some(rule.name.clone());
+}
+
+/// The first rule in order that matches the priority and applies at the given time
+fn select_rule(
+    rules: &UserNotificationRules,
+    priority: AlertPriority,
+    now: DateTime<Utc>,
+) -> Option<&NotificationRule> {
+    rules.rules.iter().find(|rule| {
+        criteria_match(&rule.criteria, priority)
+            && is_within_time_restriction(
+                &rule.name,
+                rules.timezone.as_deref(),
+                rule.time_restriction.as_ref(),
+                now,
+            )
+    })
+}
+
+fn criteria_match(criteria: &NotificationCriteria, priority: AlertPriority) -> bool {
+    let criteria_type = criteria.criteria_type.as_str();
+    if criteria_type == MATCH_ALL_CRITERIA {
+        true
+    } else if criteria_type == MATCH_ANY_CONDITION_CRITERIA {
+        criteria
+            .conditions
+            .iter()
+            .any(|condition| condition_matches(condition, priority))
+    } else if criteria_type == MATCH_ALL_CONDITIONS_CRITERIA {
+        criteria
+            .conditions
+            .iter()
+            .all(|condition| condition_matches(condition, priority))
+    } else {
+        false
+    }
+}
+
+/// The priority is all we know about an alert, conditions on any other field of it never match,
+/// no matter if they are negated
+fn condition_matches(condition: &NotificationCondition, priority: AlertPriority) -> bool {
+    if condition.field != "priority" || condition.operation != "equals" {
+        tracing::debug!(
+            condition.field,
+            condition.operation,
+            "Ignoring notification rule condition"
+        );
+        return false;
+    }
+    let equals = AlertPriority::from_str(&condition.expected_value).ok() == Some(priority);
+    equals != condition.not
+}
+
+/// Steps name their contact by method and address, phone numbers need to be normalized the same
+/// way as the contacts of the person
+fn uses_contact(
+    step: &NotificationStep,
+    contact: &Contact,
+    default_phone_region: Option<country::Id>,
+) -> bool {
+    if ContactMethod::from_opsgenie(&step.contact.method) != Some(contact.method) {
+        return false;
+    }
+    if contact.method.is_phone() {
+        normalize_phone_number(&step.contact.to, default_phone_region)
+            .is_ok_and(|number| number == contact.address)
+    } else {
+        step.contact.to == contact.address
+    }
+}
+
+/// Ring the numbers that are due right away.
+///
+/// Notification rules may delay every call, then nobody is rung now and that is not a failure.
+pub async fn call_now(
+    numbers: &[String],
+    delayed_calls: &[DelayedCall],
+    message: Option<&str>,
+    http: &Client,
+    config: &Config,
+) -> Result<AlertResult, twilio::Error> {
+    if numbers.is_empty() && !delayed_calls.is_empty() {
+        tracing::info!("All calls are delayed by notification rules, nobody is rung right now");
+        return Ok(AlertResult {
+            overall_result: OverallResult::Success,
+            detailed_result: Vec::new(),
+        });
+    }
+    alert(numbers, message, http, config).await
+}
+
+/// Delayed calls that have not been made yet, keyed by the alias of the alert or the key of the
+/// escalation they belong to.
+///
+/// Every batch of delayed calls runs as its own task, acknowledging or closing the alert or
+/// acknowledging the escalation stops all of them.
+#[derive(Debug, Clone, Default)]
+pub struct DelayedCallRunner {
+    pending: Arc<Mutex<HashMap<String, Vec<PendingCalls>>>>,
+}
+
+#[derive(Debug)]
+struct PendingCalls {
+    /// The alert that the calls are made for, for escalations this isn't their key
+    alert_alias: String,
+    task: AbortHandle,
+}
+
+impl DelayedCallRunner {
+    /// Ring the numbers once their delay has passed, every call is recorded as a copy of the given
+    /// audit event
+    #[allow(clippy::too_many_arguments)]
+    pub fn start(
+        &self,
+        key: &str,
+        alert_alias: &str,
+        calls: Vec<DelayedCall>,
+        message: Option<String>,
+        opsgenie_alias: Option<String>,
+        state: &AppState,
+        audit_event: AuditEvent,
+    ) {
+        if calls.is_empty() {
+            return;
+        }
+
+        let task = tokio::spawn(run_delayed_calls(
+            calls,
+            message,
+            opsgenie_alias,
+            state.clone(),
+            audit_event,
+        ));
+        let mut pending = self.lock();
+        // Forget about batches that are done, so that this doesn't grow forever
+        pending.retain(|_, batches| {
+            batches.retain(|batch| !batch.task.is_finished());
+            !batches.is_empty()
+        });
+        pending
+            .entry(key.to_string())
+            .or_default()
+            .push(PendingCalls {
+                alert_alias: alert_alias.to_string(),
+                task: task.abort_handle(),
+            });
+    }
+
+    /// Stop all delayed calls for the key, returns whether any were still pending
+    pub fn cancel(&self, key: &str) -> bool {
+        let batches = self.lock().remove(key).unwrap_or_default();
+        let cancelled = abort(batches);
+        if cancelled {
+            tracing::info!(key, "Delayed calls cancelled");
+        }
+        cancelled
+    }
+
+    /// Stop all delayed calls for the alert, including those of escalations that were started for
+    /// it, returns whether any were still pending
+    pub fn cancel_alert(&self, alias: &str) -> bool {
+        let mut pending = self.lock();
+        let mut batches = Vec::new();
+        for key_batches in pending.values_mut() {
+            let (stopped, kept) = std::mem::take(key_batches)
+                .into_iter()
+                .partition::<Vec<_>, _>(|batch| batch.alert_alias == alias);
+            batches.extend(stopped);
+            *key_batches = kept;
+        }
+        pending.retain(|_, key_batches| !key_batches.is_empty());
+        let cancelled = abort(batches);
+        if cancelled {
+            tracing::info!(alias, "Delayed calls cancelled");
+        }
+        cancelled
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, Vec<PendingCalls>>> {
+        self.pending.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Returns whether any of the batches was still pending
+fn abort(batches: Vec<PendingCalls>) -> bool {
+    let mut cancelled = false;
+    for batch in batches
+        .into_iter()
+        .filter(|batch| !batch.task.is_finished())
+    {
+        batch.task.abort();
+        cancelled = true;
+    }
+    cancelled
+}
+
+/// `calls` are sorted by their delay, numbers with the same delay are rung together
+async fn run_delayed_calls(
+    calls: Vec<DelayedCall>,
+    message: Option<String>,
+    opsgenie_alias: Option<String>,
+    state: AppState,
+    audit_event: AuditEvent,
+) {
+    let started = Instant::now();
+    for batch in calls.chunk_by(|a, b| a.after_seconds == b.after_seconds) {
+        let after_seconds = batch[0].after_seconds;
+        tokio::time::sleep_until(started + Duration::from_secs(after_seconds)).await;
+
+        // Re-read the config for every batch, credentials may have been rotated in the meantime
+        let config = state.config.current();
+        let numbers = batch
+            .iter()
+            .map(|call| call.number.clone())
+            .collect::<Vec<_>>();
+        tracing::info!(
+            after_seconds,
+            numbers = ?loggable_numbers(&config, &numbers),
+            "Calling delayed numbers"
+        );
+
+        let mut call_audit_event = audit_event.clone();
+        call_audit_event.delayed_call(after_seconds);
+        let result = alert(&numbers, message.as_deref(), &state.http, &config).await;
+        if let Some(opsgenie_alias) = &opsgenie_alias {
+            let called = format!("numbers delayed by {after_seconds}s");
+            incident::record_calls(opsgenie_alias, &called, &result, &state.http, &config).await;
+        }
+        if let Ok(alert_result) = &result {
+            call_audit_event.dialed(alert_result);
+        }
+        call_audit_event.finish(&result);
+        state.audit_log.record(call_audit_event, &config).await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{apply_rules, select_rule};
+    use crate::opsgenie::{
+        AlertPriority, Contact, ContactMethod, ContactSource, NotificationRule, UserContacts,
+        UserNotificationRules,
+    };
+    use chrono::{DateTime, Utc};
+    use rstest::rstest;
+    use serde_json::json;
+
+    fn rules() -> UserNotificationRules {
+        let rules: Vec<NotificationRule> = serde_json::from_value(json!([
+            {
+                "name": "Critical",
+                "order": 0,
+                "criteria": {
+                    "type": "match-any-condition",
+                    "conditions": [
+                        {"field": "priority", "operation": "equals", "expectedValue": "P1"},
+                        {"field": "message", "operation": "contains", "expectedValue": "down"}
+                    ]
+                },
+                "steps": [
+                    {"contact": {"method": "voice", "to": "49-17612345678"}, "enabled": true},
+                    {
+                        "contact": {"method": "voice", "to": "49-17612345679"},
+                        "sendAfter": {"timeAmount": 5, "timeUnit": "minutes"},
+                        "enabled": true
+                    }
+                ]
+            },
+            {
+                "name": "Nights",
+                "order": 1,
+                "criteria": {"type": "match-all"},
+                "timeRestriction": {
+                    "type": "time-of-day",
+                    "restriction": {"startHour": 22, "startMin": 0, "endHour": 7, "endMin": 0}
+                },
+                "steps": [
+                    {"contact": {"method": "mobile", "to": "jane"}, "enabled": true}
+                ]
+            },
+            {
+                "name": "Default",
+                "order": 2,
+                "criteria": {"type": "match-all"},
+                "steps": [
+                    {"contact": {"method": "sms", "to": "49-17612345678"}, "enabled": true},
+                    {"contact": {"method": "voice", "to": "49-17612345678"}, "enabled": false}
+                ]
+            }
+        ]))
+        .unwrap();
+        UserNotificationRules {
+            timezone: Some("Europe/Berlin".to_string()),
+            rules,
+        }
+    }
+
+    fn contact(method: ContactMethod, address: &str) -> Contact {
+        Contact {
+            method,
+            address: address.to_string(),
+            enabled: true,
+            source: ContactSource::Opsgenie,
+            error: None,
+            quiet: false,
+            send_after_seconds: 0,
+        }
+    }
+
+    fn person() -> UserContacts {
+        UserContacts {
+            name: "jane".to_string(),
+            contacts: vec![
+                contact(ContactMethod::Voice, "+4917612345678"),
+                contact(ContactMethod::Sms, "+4917612345678"),
+                contact(ContactMethod::Voice, "+4917612345679"),
+                contact(ContactMethod::MobileApp, "jane"),
+            ],
+            notification_rule: None,
+        }
+    }
+
+    #[rstest]
+    // Wednesday 10:00 in Berlin
+    #[case(AlertPriority::P1, "2024-10-02T08:00:00Z", Some("Critical"))]
+    #[case(AlertPriority::P3, "2024-10-02T08:00:00Z", Some("Default"))]
+    // Wednesday 23:00 in Berlin
+    #[case(AlertPriority::P3, "2024-10-02T21:00:00Z", Some("Nights"))]
+    fn test_select_rule(
+        #[case] priority: AlertPriority,
+        #[case] now: &str,
+        #[case] expected: Option<&str>,
+    ) {
+        let rules = rules();
+        let now = now.parse::<DateTime<Utc>>().unwrap();
+        assert_eq!(
+            select_rule(&rules, priority, now).map(|rule| rule.name.as_str()),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_apply_rules() {
+        let rules = rules();
+        let daytime = "2024-10-02T08:00:00Z".parse::<DateTime<Utc>>().unwrap();
+
+        let mut critical = person();
+        apply_rules(&mut critical, &rules, AlertPriority::P1, None, daytime);
+        assert_eq!(critical.notification_rule.as_deref(), Some("Critical"));
+        assert_eq!(critical.phone_numbers(), vec!["+4917612345678".to_string()]);
+        assert_eq!(
+            critical.delayed_phone_numbers(),
+            vec![(300, "+4917612345679".to_string())]
+        );
+
+        // Only the sms step is enabled, the number is the same though
+        let mut default = person();
+        apply_rules(&mut default, &rules, AlertPriority::P3, None, daytime);
+        assert_eq!(default.notification_rule.as_deref(), Some("Default"));
+        assert!(default.contacts[0].quiet);
+        assert!(!default.contacts[1].quiet);
+        assert_eq!(default.phone_numbers(), vec!["+4917612345678".to_string()]);
+        assert!(default.delayed_phone_numbers().is_empty());
+
+        // The app is all that is used at night, which we can't notify through, so nobody is rung
+        let night = "2024-10-02T21:00:00Z".parse::<DateTime<Utc>>().unwrap();
+        let mut nights = person();
+        apply_rules(&mut nights, &rules, AlertPriority::P3, None, night);
+        assert_eq!(nights.notification_rule.as_deref(), Some("Nights"));
+        assert!(nights.phone_numbers().is_empty());
+        assert!(nights.delayed_phone_numbers().is_empty());
+    }
+}
@@ -1,18 +1,52 @@
-use crate::config::Config;
+use crate::cache::{Lookup, OpsgenieCache};
+use crate::config::{Config, OpsgenieConfig};
 use crate::opsgenie::error::{
-    NoOnCallPersonSnafu, NoPhoneNumberSnafu, RequestOnCallPersonSnafu,
-    RequestPhoneNumberForPersonSnafu,
+    CreateAlertSnafu, CreateOverrideSnafu, DeleteOverrideSnafu, ForeignAlertSnafu,
+    NoOnCallPersonSnafu, NoPhoneNumberSnafu, PingHeartbeatSnafu, RequestAlertSnafu,
+    RequestEscalationSnafu, RequestNotificationRulesSnafu, RequestOnCallPersonSnafu,
+    RequestPhoneNumberForPersonSnafu, RequestRoutingRulesSnafu, RequestScheduleSnafu,
+    RequestSchedulesSnafu, RequestTimelineSnafu, UpdateAlertSnafu,
+};
+use crate::phone::normalize_phone_number;
+use crate::timeline::{find_shift, Shift};
+use crate::util::{send_json_request_with_retries, send_request_with_retries};
+use crate::{
+    http_error, AlertInfo, AlertTarget, Escalation, Schedule, ScheduleRequestById,
+    ScheduleRequestByName, Team,
 };
-use crate::util::send_json_request;
-use crate::{http_error, AlertInfo, Schedule};
 use axum::http::{HeaderMap, StatusCode};
+use chrono::{DateTime, Utc};
+use futures::{stream, StreamExt};
 use hyper::header::AUTHORIZATION;
+use phonenumber::country;
 use reqwest::{Client, Url};
 use secrecy::ExposeSecret;
-use serde::{Deserialize, Serialize};
-use snafu::{OptionExt, ResultExt, Snafu};
+use serde::de::DeserializeOwned;
+use serde::ser::SerializeStruct;
+use serde::{Deserialize, Serialize, Serializer};
+use snafu::{ensure, OptionExt, ResultExt, Snafu};
+use std::str::FromStr;
+use std::time::Duration;
+
+/// How far the timeline used to find the start and end of a shift reaches before and after the
+/// requested time, shifts are cut off at these bounds
+static SHIFT_LOOKAROUND_DAYS: i64 = 14;
+
+/// Shown as source of the alerts we create and of updates we make to them
+pub(crate) static ALERT_SOURCE: &str = "whoyougonnacall";
+
+/// Phone numbers of people on call are looked up in parallel, but without hammering OpsGenie
+/// when a team has lots of people on call at once
+static MAX_CONCURRENT_USER_LOOKUPS: usize = 5;
+
+/// Upper bound for the number of pages of a list that are fetched, in case OpsGenie keeps linking
+/// to a next page
+static MAX_PAGES: usize = 50;
+
+/// Action type of the notification rules that apply when an alert is created, other rules are
+/// about e.g. acknowledged alerts or upcoming shifts
+static CREATE_ALERT_ACTION: &str = "create-alert";
 
-static OPSGENIE_BASEURL: &str = "https://api.opsgenie.com/v2/";
 #[derive(Snafu, Debug)]
 #[snafu(module)]
 pub(crate) enum Error {
@@ -27,6 +61,68 @@ pub(crate) enum Error {
     NoOnCallPerson {},
     #[snafu(display("User [{username}] has no phone number configured!"))]
     NoPhoneNumber { username: String },
+    #[snafu(display("requesting list of schedules failed: \n{source}"))]
+    RequestSchedules { source: crate::util::Error },
+    #[snafu(display("requesting schedule {schedule} failed: \n{source}"))]
+    RequestSchedule {
+        source: crate::util::Error,
+        schedule: Schedule,
+    },
+    #[snafu(display("requesting escalation policy {escalation} failed: \n{source}"))]
+    RequestEscalation {
+        source: crate::util::Error,
+        escalation: Escalation,
+    },
+    #[snafu(display("requesting routing rules of team {team} failed: \n{source}"))]
+    RequestRoutingRules {
+        source: crate::util::Error,
+        team: Team,
+    },
+    #[snafu(display("requesting timeline of schedule {schedule} failed: \n{source}"))]
+    RequestTimeline {
+        source: crate::util::Error,
+        schedule: Schedule,
+    },
+    #[snafu(display("creating override for schedule {schedule} failed: \n{source}"))]
+    CreateOverride {
+        source: crate::util::Error,
+        schedule: Schedule,
+    },
+    #[snafu(display("deleting override [{alias}] of schedule {schedule} failed: \n{source}"))]
+    DeleteOverride {
+        source: crate::util::Error,
+        schedule: Schedule,
+        alias: String,
+    },
+    #[snafu(display("creating alert [{alias}] failed: \n{source}"))]
+    CreateAlert {
+        source: crate::util::Error,
+        alias: String,
+    },
+    #[snafu(display("updating alert [{alias}] failed: \n{source}"))]
+    UpdateAlert {
+        source: crate::util::Error,
+        alias: String,
+    },
+    #[snafu(display("requesting alert [{alias}] failed: \n{source}"))]
+    RequestAlert {
+        source: crate::util::Error,
+        alias: String,
+    },
+    #[snafu(display(
+        "alert [{alias}] was created by [{alert_source}], only alerts created by {ALERT_SOURCE} can be updated"
+    ))]
+    ForeignAlert { alias: String, alert_source: String },
+    #[snafu(display("pinging heartbeat [{name}] failed: \n{source}"))]
+    PingHeartbeat {
+        source: crate::util::Error,
+        name: String,
+    },
+    #[snafu(display("requesting notification rules failed for [{username}]: \n{source}"))]
+    RequestNotificationRules {
+        source: crate::util::Error,
+        username: String,
+    },
 }
 
 impl http_error::Error for Error {
@@ -36,15 +132,164 @@ impl http_error::Error for Error {
             Error::NoOnCallPerson { .. } => StatusCode::IM_A_TEAPOT,
             Error::NoPhoneNumber { .. } => StatusCode::IM_A_TEAPOT,
             Error::RequestPhoneNumberForPerson { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+            Error::RequestSchedules { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+            Error::RequestSchedule { .. } => StatusCode::UNPROCESSABLE_ENTITY,
+            Error::RequestEscalation { .. } => StatusCode::UNPROCESSABLE_ENTITY,
+            Error::RequestRoutingRules { .. } => StatusCode::UNPROCESSABLE_ENTITY,
+            Error::RequestTimeline { .. } => StatusCode::UNPROCESSABLE_ENTITY,
+            Error::CreateOverride { .. } => StatusCode::UNPROCESSABLE_ENTITY,
+            Error::DeleteOverride { .. } => StatusCode::UNPROCESSABLE_ENTITY,
+            Error::CreateAlert { .. } => StatusCode::UNPROCESSABLE_ENTITY,
+            Error::UpdateAlert { .. } => StatusCode::UNPROCESSABLE_ENTITY,
+            Error::RequestAlert { .. } => StatusCode::UNPROCESSABLE_ENTITY,
+            Error::ForeignAlert { .. } => StatusCode::FORBIDDEN,
+            Error::PingHeartbeat { .. } => StatusCode::UNPROCESSABLE_ENTITY,
+            Error::RequestNotificationRules { .. } => StatusCode::UNPROCESSABLE_ENTITY,
         }
     }
 }
 
-#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq, Hash)]
+/// Everything a person on call can be reached through.
+///
+/// Serialized with an additional `phone` list of the numbers that get rung, which callers relied
+/// on before contacts were kept.
+#[derive(Clone, Deserialize, Debug, PartialEq, Eq, Hash)]
 #[serde(rename_all = "camelCase")]
-pub struct UserPhoneNumber {
+pub struct UserContacts {
     pub name: String,
-    pub phone: Vec<String>,
+    pub contacts: Vec<Contact>,
+    /// Name of the notification rule that decided which contacts are used, see
+    /// [`crate::notification`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub notification_rule: Option<String>,
+}
+
+impl Serialize for UserContacts {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut user = serializer.serialize_struct("UserContacts", 4)?;
+        user.serialize_field("name", &self.name)?;
+        user.serialize_field("phone", &self.phone_numbers())?;
+        user.serialize_field("contacts", &self.contacts)?;
+        match &self.notification_rule {
+            Some(notification_rule) => {
+                user.serialize_field("notificationRule", notification_rule)?
+            }
+            None => user.skip_field("notificationRule")?,
+        }
+        user.end()
+    }
+}
+
+impl UserContacts {
+    /// Addresses of the contacts with one of the given methods that are notified right away,
+    /// without duplicates
+    pub fn enabled_addresses(&self, methods: &[ContactMethod]) -> Vec<String> {
+        let mut addresses = self
+            .contacts
+            .iter()
+            .filter(|contact| {
+                contact.is_usable()
+                    && contact.send_after_seconds == 0
+                    && methods.contains(&contact.method)
+            })
+            .map(|contact| contact.address.clone())
+            .collect::<Vec<_>>();
+        // Sort to enable easier deduplication, voice and sms usually share the same number
+        addresses.sort();
+        addresses.dedup();
+        addresses
+    }
+
+    /// The numbers that get rung when alerting
+    pub fn phone_numbers(&self) -> Vec<String> {
+        self.enabled_addresses(&[ContactMethod::Voice, ContactMethod::Sms])
+    }
+
+    /// The numbers that notification rules only want rung after a while, with the number of
+    /// seconds to wait
+    pub fn delayed_phone_numbers(&self) -> Vec<(u64, String)> {
+        self.contacts
+            .iter()
+            .filter(|contact| {
+                contact.is_usable() && contact.send_after_seconds > 0 && contact.method.is_phone()
+            })
+            .map(|contact| (contact.send_after_seconds, contact.address.clone()))
+            .collect()
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq, Hash)]
+#[serde(rename_all = "camelCase")]
+pub struct Contact {
+    pub method: ContactMethod,
+    /// Phone number, email address or, for the mobile app, the OpsGenie username
+    pub address: String,
+    /// Disabled contacts are kept so that callers can see them, but nobody is notified through
+    /// them
+    pub enabled: bool,
+    pub source: ContactSource,
+    /// Why the address can't be used, e.g. an invalid phone number. Such contacts are skipped.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// The notification rule of the person doesn't use this contact for the alert at hand
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub quiet: bool,
+    /// Seconds the notification rule of the person waits before using this contact
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub send_after_seconds: u64,
+}
+
+impl Contact {
+    /// Whether anyone is notified through this contact at all
+    pub fn is_usable(&self) -> bool {
+        self.enabled && self.error.is_none() && !self.quiet
+    }
+}
+
+fn is_zero(seconds: &u64) -> bool {
+    *seconds == 0
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq, Hash)]
+#[serde(rename_all = "camelCase")]
+pub enum ContactMethod {
+    Voice,
+    Sms,
+    Email,
+    MobileApp,
+}
+
+impl ContactMethod {
+    /// The method as OpsGenie names it, e.g. `mobile` for the app
+    pub fn from_opsgenie(method: &str) -> Option<Self> {
+        match method {
+            "voice" => Some(ContactMethod::Voice),
+            "sms" => Some(ContactMethod::Sms),
+            "email" => Some(ContactMethod::Email),
+            "mobile" => Some(ContactMethod::MobileApp),
+            _ => None,
+        }
+    }
+
+    /// Phone numbers are masked, see [`crate::privacy`]
+    pub fn is_phone(&self) -> bool {
+        matches!(self, ContactMethod::Voice | ContactMethod::Sms)
+    }
+}
+
+/// Where a contact was looked up
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq, Hash)]
+#[serde(rename_all = "camelCase")]
+pub enum ContactSource {
+    Opsgenie,
+}
+
+/// Someone on call whose phone numbers couldn't be looked up
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq, Hash)]
+#[serde(rename_all = "camelCase")]
+pub struct FailedLookup {
+    pub username: String,
+    pub error: String,
 }
 
 #[derive(Clone, Deserialize, Debug)]
@@ -59,36 +304,200 @@ struct OnCallResultData {
     on_call_recipients: Vec<String>,
 }
 
-pub fn get_base_url() -> Result<Url, url::ParseError> {
-    Url::parse(OPSGENIE_BASEURL)
+#[derive(Clone, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct NextOnCallResult {
+    data: NextOnCallResultData,
+}
+
+#[derive(Clone, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct NextOnCallResultData {
+    /// The people on call in the very next shift
+    #[serde(default)]
+    exact_next_on_call_recipients: Vec<String>,
+    /// The next people on call of every rotation of the schedule
+    #[serde(default)]
+    next_on_call_recipients: Vec<String>,
+}
+
+/// One page of a list endpoint
+#[derive(Clone, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct PagedResult<T> {
+    data: Vec<T>,
+    /// Only set by endpoints that page their results
+    paging: Option<Paging>,
+}
+
+#[derive(Clone, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct Paging {
+    /// Absolute url of the next page, not set on the last page
+    next: Option<String>,
+}
+
+/// Fetch every page of a list endpoint and return the items of all of them
+async fn get_all_pages<T: DeserializeOwned>(
+    http: &Client,
+    url: Url,
+    headers: &HeaderMap,
+) -> Result<Vec<T>, crate::util::Error> {
+    let mut items = Vec::new();
+    let mut page_url = Some(url.clone());
+    let mut pages = 0;
+    while let Some(current) = page_url.take() {
+        if pages == MAX_PAGES {
+            tracing::warn!(%url, pages, "List has too many pages, ignoring the rest");
+            break;
+        }
+        let page = send_json_request_with_retries::<PagedResult<T>>(
+            http.get(current).headers(headers.clone()),
+        )
+        .await?;
+        pages += 1;
+        items.extend(page.data);
+        page_url = next_page(page.paging, &url);
+    }
+    Ok(items)
+}
+
+/// The headers contain the API key, so links to anywhere but OpsGenie itself are not followed
+fn next_page(paging: Option<Paging>, first_page: &Url) -> Option<Url> {
+    let next = paging?.next?;
+    match Url::parse(&next) {
+        Ok(next) if next.origin() == first_page.origin() => Some(next),
+        _ => {
+            tracing::warn!(next, "Ignoring link to the next page of a list");
+            None
+        }
+    }
+}
+
+fn auth_headers(opsgenie_config: &OpsgenieConfig) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        AUTHORIZATION,
+        opsgenie_config.credentials.expose_secret().clone().0,
+    );
+    headers
+}
+
+#[derive(Clone, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ScheduleListResultData {
+    pub id: String,
+    pub name: String,
+}
+
+/// Retrieve the names of all schedules that are visible with the configured credentials.
+///
+/// Mainly useful to check that the credentials are accepted by OpsGenie.
+pub(crate) async fn get_schedule_names(
+    http: &Client,
+    config: &Config,
+) -> Result<Vec<String>, Error> {
+    Ok(get_schedules(http, config)
+        .await?
+        .into_iter()
+        .map(|schedule| schedule.name)
+        .collect())
+}
+
+/// Retrieve id and name of all schedules that are visible with the configured credentials
+pub(crate) async fn get_schedules(
+    http: &Client,
+    config: &Config,
+) -> Result<Vec<ScheduleListResultData>, Error> {
+    let opsgenie_config = &config.opsgenie_config;
+    let url = opsgenie_config.base_url.join("schedules").unwrap();
+    tracing::debug!("Retrieving schedules from [{}]", url.to_string());
+
+    get_all_pages(http, url, &auth_headers(opsgenie_config))
+        .await
+        .context(RequestSchedulesSnafu)
+}
+
+#[derive(Clone, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct ScheduleResult {
+    data: ScheduleListResultData,
+}
+
+/// The id of a schedule, the ids of schedules that are requested by name are cached
+pub(crate) async fn get_schedule_id(
+    schedule: &Schedule,
+    http: &Client,
+    cache: &OpsgenieCache,
+    config: &Config,
+) -> Result<String, Error> {
+    let name = match schedule {
+        Schedule::ScheduleById(ScheduleRequestById { id }) => return Ok(id.clone()),
+        Schedule::ScheduleByName(ScheduleRequestByName { name }) => name,
+    };
+    let opsgenie_config = &config.opsgenie_config;
+    let url = opsgenie_config
+        .base_url
+        .join(&format!("schedules/{}", urlencoding::encode(name)))
+        .unwrap();
+    tracing::debug!("Retrieving schedule from [{}]", url.to_string());
+
+    let request = http
+        .get(url)
+        .headers(auth_headers(opsgenie_config))
+        .query(&[("identifierType", "name")]);
+    let lookup = cache
+        .schedule_ids
+        .get_or_fetch(name, opsgenie_config.cache_ttl, || async {
+            send_json_request_with_retries::<ScheduleResult>(request)
+                .await
+                .map(|schedule| schedule.data.id)
+        })
+        .await
+        .context(RequestScheduleSnafu {
+            schedule: schedule.clone(),
+        })?;
+    Ok(lookup.value)
 }
 
 pub(crate) async fn get_oncall_number(
     schedule: &Schedule,
     http: &Client,
+    cache: &OpsgenieCache,
+    config: &Config,
+) -> Result<AlertInfo, Error> {
+    get_shift_oncall_number(schedule, Shift::Current, http, cache, config).await
+}
+
+/// Look up who is on call in the given shift of a schedule.
+///
+/// For any shift but the current one the start and end of the shift are added from the timeline
+/// of the schedule. Failing to find them doesn't fail the lookup, the people on call are what
+/// matters.
+pub(crate) async fn get_shift_oncall_number(
+    schedule: &Schedule,
+    shift: Shift,
+    http: &Client,
+    cache: &OpsgenieCache,
     config: &Config,
 ) -> Result<AlertInfo, Error> {
-    let Config {
-        opsgenie_config,
-        slack_config,
-        ..
-    } = config;
+    let opsgenie_config = &config.opsgenie_config;
     let mut url_builder = opsgenie_config.base_url.clone();
 
-    let (schedule_identifier, schedule_identifier_type) = match schedule {
-        Schedule::ScheduleById(id) => (&id.id, "id"),
-        Schedule::ScheduleByName(name) => (&name.name, "name"),
-    };
+    let (schedule_identifier, schedule_identifier_type) = schedule.identifier();
 
+    let endpoint = match shift {
+        Shift::Current | Shift::At(_) => "on-calls",
+        Shift::Next(_) => "next-on-calls",
+    };
     url_builder = url_builder
-        .join(&format!("schedules/{schedule_identifier}/on-calls"))
+        .join(&format!(
+            "schedules/{}/{endpoint}",
+            urlencoding::encode(schedule_identifier)
+        ))
         .unwrap();
 
-    let mut outgoing_headers = HeaderMap::new();
-    outgoing_headers.insert(
-        AUTHORIZATION,
-        opsgenie_config.credentials.expose_secret().clone().0,
-    );
+    let outgoing_headers = auth_headers(opsgenie_config);
 
     tracing::debug!(
         "Retrieving on call person from [{}]",
@@ -96,54 +505,971 @@ pub(crate) async fn get_oncall_number(
     );
     tracing::debug!("Using headers: [{:?}]", outgoing_headers);
 
-    let persons_on_call = send_json_request::<OnCallResult>(
-        http.get(url_builder.clone())
-            .headers(outgoing_headers.clone())
+    let mut query = vec![
+        ("flat", "true".to_string()),
+        (
+            "scheduleIdentifierType",
+            schedule_identifier_type.to_string(),
+        ),
+    ];
+    if let Some(date) = shift.date() {
+        query.push(("date", date.to_rfc3339()));
+    }
+    let request = http
+        .get(url_builder.clone())
+        .headers(outgoing_headers.clone())
+        .query(&query);
+
+    // Only the current shift is cached, as that is who gets alerted
+    let mut stale = false;
+    let persons_on_call = match shift {
+        Shift::Current => {
+            let lookup = cache
+                .on_call
+                .get_or_fetch(schedule, opsgenie_config.cache_ttl, || async {
+                    send_json_request_with_retries::<OnCallResult>(request)
+                        .await
+                        .map(|on_call| on_call.data.on_call_recipients)
+                })
+                .await
+                .context(RequestOnCallPersonSnafu)?;
+            stale = lookup.stale;
+            lookup.value
+        }
+        Shift::At(_) => {
+            send_json_request_with_retries::<OnCallResult>(request)
+                .await
+                .context(RequestOnCallPersonSnafu)?
+                .data
+                .on_call_recipients
+        }
+        Shift::Next(_) => {
+            let next = send_json_request_with_retries::<NextOnCallResult>(request)
+                .await
+                .context(RequestOnCallPersonSnafu)?
+                .data;
+            if next.exact_next_on_call_recipients.is_empty() {
+                next.next_on_call_recipients
+            } else {
+                next.exact_next_on_call_recipients
+            }
+        }
+    };
+
+    // We don't need this value, this is just to check the response wasn't empty and no one is
+    // on call
+    persons_on_call.first().context(NoOnCallPersonSnafu)?;
+
+    let mut alert_info = get_alert_info_for_users(persons_on_call, http, cache, config).await?;
+    alert_info.stale |= stale;
+    if shift != Shift::Current {
+        add_shift_times(&mut alert_info, schedule, shift, http, config).await;
+    }
+    Ok(alert_info)
+}
+
+async fn add_shift_times(
+    alert_info: &mut AlertInfo,
+    schedule: &Schedule,
+    shift: Shift,
+    http: &Client,
+    config: &Config,
+) {
+    let now = Utc::now();
+    let from = shift.date().unwrap_or(now) - chrono::Duration::days(SHIFT_LOOKAROUND_DAYS);
+    match get_timeline(
+        schedule,
+        from,
+        2 * SHIFT_LOOKAROUND_DAYS as u32,
+        http,
+        config,
+    )
+    .await
+    {
+        Ok(periods) => {
+            if let Some(times) = find_shift(&periods, &alert_info.username, shift, now) {
+                alert_info.shift_start = Some(times.start);
+                alert_info.shift_end = Some(times.end);
+            }
+        }
+        Err(e) => tracing::warn!(
+            error = &e as &dyn std::error::Error,
+            %schedule,
+            "Failed to look up start and end of the shift"
+        ),
+    }
+}
+
+#[derive(Clone, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct TimelineResult {
+    data: TimelineResultData,
+}
+
+#[derive(Clone, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct TimelineResultData {
+    final_timeline: Timeline,
+}
+
+#[derive(Clone, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct Timeline {
+    #[serde(default)]
+    rotations: Vec<TimelineRotation>,
+}
+
+#[derive(Clone, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct TimelineRotation {
+    #[serde(default)]
+    periods: Vec<TimelinePeriod>,
+}
+
+/// A stretch of time in which one recipient is on call for a rotation, overrides already applied
+#[derive(Clone, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct TimelinePeriod {
+    pub start_date: DateTime<Utc>,
+    pub end_date: DateTime<Utc>,
+    /// `default`, `override`, `forwarding` or `historical`
+    #[serde(rename = "type")]
+    pub period_type: String,
+    pub recipient: Option<TimelineRecipient>,
+}
+
+impl TimelinePeriod {
+    /// The user that is on call in this period, periods without anyone on call don't have one
+    pub fn username(&self) -> Option<&str> {
+        match &self.recipient {
+            Some(TimelineRecipient::User { name }) => Some(name),
+            _ => None,
+        }
+    }
+
+    pub fn is_override(&self) -> bool {
+        self.period_type == "override"
+    }
+}
+
+#[derive(Clone, Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub(crate) enum TimelineRecipient {
+    User {
+        name: String,
+    },
+    #[serde(other)]
+    Unsupported,
+}
+
+/// Retrieve the final timeline of a schedule for `days` days starting at `from`, with the periods
+/// of all rotations in one list
+pub(crate) async fn get_timeline(
+    schedule: &Schedule,
+    from: DateTime<Utc>,
+    days: u32,
+    http: &Client,
+    config: &Config,
+) -> Result<Vec<TimelinePeriod>, Error> {
+    let opsgenie_config = &config.opsgenie_config;
+    let (identifier, identifier_type) = schedule.identifier();
+    let url = opsgenie_config
+        .base_url
+        .join(&format!(
+            "schedules/{}/timeline",
+            urlencoding::encode(identifier)
+        ))
+        .unwrap();
+    tracing::debug!("Retrieving schedule timeline from [{}]", url.to_string());
+
+    let timeline = send_json_request_with_retries::<TimelineResult>(
+        http.get(url)
+            .headers(auth_headers(opsgenie_config))
             .query(&[
-                ("flat", "true"),
-                ("scheduleIdentifierType", schedule_identifier_type),
+                ("identifierType", identifier_type.to_string()),
+                ("date", from.to_rfc3339()),
+                ("interval", days.to_string()),
+                ("intervalUnit", "days".to_string()),
             ]),
     )
     .await
-    .context(RequestOnCallPersonSnafu)?;
+    .context(RequestTimelineSnafu {
+        schedule: schedule.clone(),
+    })?;
 
-    // We don't need this value, this is just to check the response wasn't empty and no one is
-    // on call
-    persons_on_call
+    Ok(timeline
         .data
-        .on_call_recipients
-        .get(0)
-        .context(NoOnCallPersonSnafu)?;
-
-    let mut result_list: Vec<UserPhoneNumber> = Vec::new();
-
-    for user in persons_on_call.data.on_call_recipients {
-        tracing::debug!(user, "Looking up phone number");
-        let phone_number = get_phone_number(
-            http.clone(),
-            opsgenie_config.base_url.clone(),
-            &outgoing_headers,
-            &user,
-        )
-        .await
-        .context(RequestPhoneNumberForPersonSnafu { username: &user })?;
-        result_list.push(UserPhoneNumber {
-            name: user.to_string(),
-            phone: phone_number,
+        .final_timeline
+        .rotations
+        .into_iter()
+        .flat_map(|rotation| rotation.periods)
+        .collect())
+}
+
+/// Look up the phone numbers of the given users, the first user with a phone number is the one
+/// that is reported as the primary contact.
+///
+/// Users that can't be looked up or have no phone number are skipped, it is only an error if
+/// that leaves nobody to call. Phone numbers are cached, see [`crate::cache::OpsgenieCache`].
+pub(crate) async fn get_alert_info_for_users(
+    usernames: Vec<String>,
+    http: &Client,
+    cache: &OpsgenieCache,
+    config: &Config,
+) -> Result<AlertInfo, Error> {
+    let opsgenie_config = &config.opsgenie_config;
+    let outgoing_headers = &auth_headers(opsgenie_config);
+    let mut result_list: Vec<UserContacts> = Vec::new();
+    let mut failed_lookups: Vec<FailedLookup> = Vec::new();
+    let mut first_error = None;
+    let mut stale = false;
+
+    // buffered() keeps the order, so that the first user stays the primary contact
+    let lookups = stream::iter(usernames)
+        .map(|user| async move {
+            tracing::debug!(user, "Looking up contacts");
+            let contacts = cache
+                .contacts
+                .get_or_fetch(&user, opsgenie_config.cache_ttl, || {
+                    get_contacts(
+                        http.clone(),
+                        opsgenie_config.base_url.clone(),
+                        outgoing_headers,
+                        &user,
+                        opsgenie_config.default_phone_region,
+                    )
+                })
+                .await
+                .context(RequestPhoneNumberForPersonSnafu { username: &user });
+            (user, contacts)
         })
+        .buffered(MAX_CONCURRENT_USER_LOOKUPS)
+        .collect::<Vec<_>>()
+        .await;
+
+    for (user, contacts) in lookups {
+        match contacts {
+            Ok(contacts) => {
+                stale |= contacts.stale;
+                result_list.push(UserContacts {
+                    name: user,
+                    contacts: contacts.value,
+                    notification_rule: None,
+                })
+            }
+            // Everyone else can still be called
+            Err(e) => {
+                tracing::warn!(
+                    error = &e as &dyn std::error::Error,
+                    user,
+                    "Failed to look up phone number, this person won't be called"
+                );
+                failed_lookups.push(FailedLookup {
+                    username: user,
+                    error: e.to_string(),
+                });
+                first_error.get_or_insert(e);
+            }
+        }
     }
 
-    let user = result_list.get(0).context(NoOnCallPersonSnafu)?;
-    let username = &user.name;
-    let phone_number = user
-        .phone
-        .get(0)
-        .context(NoPhoneNumberSnafu { username: username })?;
+    // Only fail if there is nobody left to call
+    let Some((username, phone_number)) = result_list.iter().find_map(|user| {
+        let phone_number = user.phone_numbers().into_iter().next()?;
+        Some((user.name.clone(), phone_number))
+    }) else {
+        return match (first_error, result_list.first()) {
+            (Some(e), _) => Err(e),
+            (None, Some(user)) => NoPhoneNumberSnafu {
+                username: &user.name,
+            }
+            .fail(),
+            (None, None) => NoOnCallPersonSnafu.fail(),
+        };
+    };
 
     Ok(AlertInfo {
-        username: username.clone(),
-        phone_number: phone_number.clone(),
+        username,
+        phone_number,
         full_information: result_list,
+        shift_start: None,
+        shift_end: None,
+        stale,
+        failed_lookups,
+    })
+}
+
+#[derive(Clone, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct EscalationResult {
+    data: EscalationPolicy,
+}
+
+/// An OpsGenie escalation policy, only the parts that are needed to ring people are parsed
+#[derive(Clone, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct EscalationPolicy {
+    pub id: String,
+    pub name: String,
+    pub rules: Vec<EscalationRule>,
+}
+
+/// One step of an escalation policy.
+///
+/// The `condition` of a rule is ignored, `if-not-acked` and `if-not-closed` mean the same to us as
+/// we only know about acknowledgements.
+#[derive(Clone, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct EscalationRule {
+    /// Who in the recipient is notified, e.g. `next` on-call of a schedule, we only support
+    /// `default`
+    pub notify_type: String,
+    pub delay: EscalationDelay,
+    pub recipient: EscalationRecipient,
+}
+
+#[derive(Clone, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct EscalationDelay {
+    pub time_amount: u64,
+    pub time_unit: String,
+}
+
+impl EscalationDelay {
+    pub fn as_duration(&self) -> Duration {
+        let unit_seconds = match self.time_unit.as_str() {
+            "hours" => 60 * 60,
+            "days" => 24 * 60 * 60,
+            // OpsGenie documents minutes as the only unit for escalation delays
+            _ => 60,
+        };
+        Duration::from_secs(self.time_amount * unit_seconds)
+    }
+}
+
+#[derive(Clone, Deserialize, Debug)]
+#[serde(rename_all = "camelCase", tag = "type")]
+pub(crate) enum EscalationRecipient {
+    User {
+        username: String,
+    },
+    Schedule {
+        id: String,
+        name: String,
+    },
+    Team {
+        id: String,
+        name: String,
+    },
+    #[serde(other)]
+    Unsupported,
+}
+
+pub(crate) async fn get_escalation(
+    escalation: &Escalation,
+    http: &Client,
+    config: &Config,
+) -> Result<EscalationPolicy, Error> {
+    let opsgenie_config = &config.opsgenie_config;
+    let (identifier, identifier_type) = escalation.identifier();
+    let url = opsgenie_config
+        .base_url
+        .join(&format!("escalations/{}", urlencoding::encode(identifier)))
+        .unwrap();
+    tracing::debug!("Retrieving escalation policy from [{}]", url.to_string());
+
+    let escalation_policy = send_json_request_with_retries::<EscalationResult>(
+        http.get(url)
+            .headers(auth_headers(opsgenie_config))
+            .query(&[("identifierType", identifier_type)]),
+    )
+    .await
+    .context(RequestEscalationSnafu {
+        escalation: escalation.clone(),
+    })?;
+
+    Ok(escalation_policy.data)
+}
+
+#[derive(Clone, Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct CreateOverrideRequest<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    alias: Option<&'a str>,
+    user: OverrideUser<'a>,
+    start_date: DateTime<Utc>,
+    end_date: DateTime<Utc>,
+}
+
+#[derive(Clone, Serialize, Debug)]
+#[serde(tag = "type", rename_all = "camelCase")]
+enum OverrideUser<'a> {
+    User { username: &'a str },
+}
+
+#[derive(Clone, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct CreateOverrideResult {
+    data: CreateOverrideResultData,
+}
+
+#[derive(Clone, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct CreateOverrideResultData {
+    alias: String,
+}
+
+/// Let `username` take over all rotations of a schedule from `start` to `end`, returns the alias
+/// OpsGenie identifies the override by. Without an alias OpsGenie generates one.
+pub(crate) async fn create_override(
+    schedule: &Schedule,
+    username: &str,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+    alias: Option<&str>,
+    http: &Client,
+    config: &Config,
+) -> Result<String, Error> {
+    let opsgenie_config = &config.opsgenie_config;
+    let (identifier, identifier_type) = schedule.identifier();
+    let url = opsgenie_config
+        .base_url
+        .join(&format!(
+            "schedules/{}/overrides",
+            urlencoding::encode(identifier)
+        ))
+        .unwrap();
+    tracing::debug!("Creating override at [{}]", url.to_string());
+
+    let created = send_json_request_with_retries::<CreateOverrideResult>(
+        http.post(url)
+            .headers(auth_headers(opsgenie_config))
+            .query(&[("scheduleIdentifierType", identifier_type)])
+            .json(&CreateOverrideRequest {
+                alias,
+                user: OverrideUser::User { username },
+                start_date: start,
+                end_date: end,
+            }),
+    )
+    .await
+    .context(CreateOverrideSnafu {
+        schedule: schedule.clone(),
+    })?;
+
+    Ok(created.data.alias)
+}
+
+pub(crate) async fn delete_override(
+    schedule: &Schedule,
+    alias: &str,
+    http: &Client,
+    config: &Config,
+) -> Result<(), Error> {
+    let opsgenie_config = &config.opsgenie_config;
+    let (identifier, identifier_type) = schedule.identifier();
+    let url = opsgenie_config
+        .base_url
+        .join(&format!(
+            "schedules/{}/overrides/{}",
+            urlencoding::encode(identifier),
+            urlencoding::encode(alias)
+        ))
+        .unwrap();
+    tracing::debug!("Deleting override at [{}]", url.to_string());
+
+    send_request_with_retries(
+        http.delete(url)
+            .headers(auth_headers(opsgenie_config))
+            .query(&[("scheduleIdentifierType", identifier_type)]),
+    )
+    .await
+    .context(DeleteOverrideSnafu {
+        schedule: schedule.clone(),
+        alias,
+    })?;
+
+    Ok(())
+}
+
+/// Priority of an OpsGenie alert, `P1` is the most urgent
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq, Hash)]
+pub enum AlertPriority {
+    P1,
+    P2,
+    P3,
+    P4,
+    P5,
+}
+
+impl FromStr for AlertPriority {
+    type Err = String;
+
+    fn from_str(priority: &str) -> Result<Self, Self::Err> {
+        match priority {
+            "P1" => Ok(AlertPriority::P1),
+            "P2" => Ok(AlertPriority::P2),
+            "P3" => Ok(AlertPriority::P3),
+            "P4" => Ok(AlertPriority::P4),
+            "P5" => Ok(AlertPriority::P5),
+            other => Err(other.to_string()),
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct CreateAlertRequest<'a> {
+    /// Identifies the alert in later updates, OpsGenie also deduplicates open alerts by it
+    pub alias: &'a str,
+    pub message: &'a str,
+    pub priority: AlertPriority,
+    pub responders: Vec<Responder<'a>>,
+    /// Who triggered the alert
+    pub user: &'a str,
+    pub source: &'a str,
+}
+
+/// A team, schedule or escalation policy that an OpsGenie alert is assigned to
+#[derive(Clone, Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Responder<'a> {
+    #[serde(rename = "type")]
+    responder_type: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<&'a str>,
+}
+
+impl<'a> From<&'a AlertTarget> for Responder<'a> {
+    fn from(target: &'a AlertTarget) -> Self {
+        let (responder_type, (identifier, identifier_type)) = match target {
+            AlertTarget::Schedule(schedule) => ("schedule", schedule.identifier()),
+            AlertTarget::Escalation(escalation) => ("escalation", escalation.identifier()),
+            AlertTarget::Team(team) => ("team", team.identifier()),
+        };
+        let is_id = identifier_type == "id";
+        Responder {
+            responder_type,
+            id: is_id.then_some(identifier),
+            name: (!is_id).then_some(identifier),
+        }
+    }
+}
+
+/// Updates that can be made to an existing alert, each comes with a note
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum AlertUpdate {
+    AddNote,
+    Acknowledge,
+    Close,
+}
+
+impl AlertUpdate {
+    fn endpoint(&self) -> &'static str {
+        match self {
+            AlertUpdate::AddNote => "notes",
+            AlertUpdate::Acknowledge => "acknowledge",
+            AlertUpdate::Close => "close",
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct AlertUpdateRequest<'a> {
+    note: &'a str,
+    user: &'a str,
+    source: &'a str,
+}
+
+/// Create an alert. OpsGenie processes requests to the alert API asynchronously, so this only
+/// means that the request was accepted.
+pub(crate) async fn create_alert(
+    request: &CreateAlertRequest<'_>,
+    http: &Client,
+    config: &Config,
+) -> Result<(), Error> {
+    let opsgenie_config = &config.opsgenie_config;
+    let url = opsgenie_config.base_url.join("alerts").unwrap();
+    tracing::debug!(request.alias, "Creating alert at [{}]", url.to_string());
+
+    send_request_with_retries(
+        http.post(url)
+            .headers(auth_headers(opsgenie_config))
+            .json(request),
+    )
+    .await
+    .context(CreateAlertSnafu {
+        alias: request.alias,
+    })?;
+    Ok(())
+}
+
+#[derive(Clone, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct GetAlertResult {
+    data: AlertData,
+}
+
+#[derive(Clone, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct AlertData {
+    #[serde(default)]
+    source: String,
+}
+
+/// Fail unless the alert with the given alias was created by us
+pub(crate) async fn check_alert_source(
+    alias: &str,
+    http: &Client,
+    config: &Config,
+) -> Result<(), Error> {
+    let opsgenie_config = &config.opsgenie_config;
+    let url = opsgenie_config
+        .base_url
+        .join(&format!("alerts/{}", urlencoding::encode(alias)))
+        .unwrap();
+    tracing::debug!(alias, "Retrieving alert from [{}]", url.to_string());
+
+    let alert = send_json_request_with_retries::<GetAlertResult>(
+        http.get(url)
+            .headers(auth_headers(opsgenie_config))
+            .query(&[("identifierType", "alias")]),
+    )
+    .await
+    .context(RequestAlertSnafu { alias })?;
+    ensure!(
+        alert.data.source == ALERT_SOURCE,
+        ForeignAlertSnafu {
+            alias,
+            alert_source: alert.data.source,
+        }
+    );
+    Ok(())
+}
+
+/// Add a note to, acknowledge or close the alert with the given alias
+pub(crate) async fn update_alert(
+    alias: &str,
+    update: AlertUpdate,
+    note: &str,
+    user: &str,
+    http: &Client,
+    config: &Config,
+) -> Result<(), Error> {
+    let opsgenie_config = &config.opsgenie_config;
+    let url = opsgenie_config
+        .base_url
+        .join(&format!(
+            "alerts/{}/{}",
+            urlencoding::encode(alias),
+            update.endpoint()
+        ))
+        .unwrap();
+    tracing::debug!(alias, ?update, "Updating alert at [{}]", url.to_string());
+
+    send_request_with_retries(
+        http.post(url)
+            .headers(auth_headers(opsgenie_config))
+            .query(&[("identifierType", "alias")])
+            .json(&AlertUpdateRequest {
+                note,
+                user,
+                source: ALERT_SOURCE,
+            }),
+    )
+    .await
+    .context(UpdateAlertSnafu { alias })?;
+    Ok(())
+}
+
+#[derive(Clone, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct RoutingRulesResult {
+    data: Vec<RoutingRule>,
+}
+
+/// Decides where alerts for a team go, see [`crate::routing`] for how they are evaluated
+#[derive(Clone, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct RoutingRule {
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub is_default: bool,
+    pub order: u32,
+    pub criteria: RoutingCriteria,
+    /// IANA name of the timezone the time restriction is given in
+    pub timezone: Option<String>,
+    pub time_restriction: Option<TimeRestriction>,
+    pub notify: RoutingNotify,
+}
+
+#[derive(Clone, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct RoutingCriteria {
+    /// `match-all`, or one of the types that match on fields of an alert
+    #[serde(rename = "type")]
+    pub criteria_type: String,
+}
+
+#[derive(Clone, Deserialize, Debug)]
+#[serde(rename_all = "kebab-case", tag = "type")]
+pub(crate) enum TimeRestriction {
+    TimeOfDay {
+        restriction: TimeOfDayRestriction,
+    },
+    WeekdayAndTimeOfDay {
+        restrictions: Vec<WeekdayAndTimeOfDayRestriction>,
+    },
+}
+
+#[derive(Clone, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct TimeOfDayRestriction {
+    pub start_hour: u32,
+    pub start_min: u32,
+    pub end_hour: u32,
+    pub end_min: u32,
+}
+
+#[derive(Clone, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct WeekdayAndTimeOfDayRestriction {
+    /// Lowercase english name of the day, e.g. `monday`
+    pub start_day: String,
+    pub start_hour: u32,
+    pub start_min: u32,
+    pub end_day: String,
+    pub end_hour: u32,
+    pub end_min: u32,
+}
+
+#[derive(Clone, Deserialize, Debug)]
+#[serde(rename_all = "camelCase", tag = "type")]
+pub(crate) enum RoutingNotify {
+    Schedule {
+        id: String,
+    },
+    Escalation {
+        id: String,
+    },
+    /// The rule drops alerts instead of notifying anyone
+    None,
+    #[serde(other)]
+    Unsupported,
+}
+
+pub(crate) async fn get_routing_rules(
+    team: &Team,
+    http: &Client,
+    config: &Config,
+) -> Result<Vec<RoutingRule>, Error> {
+    let opsgenie_config = &config.opsgenie_config;
+    let (identifier, identifier_type) = team.identifier();
+    let url = opsgenie_config
+        .base_url
+        .join(&format!(
+            "teams/{}/routing-rules",
+            urlencoding::encode(identifier)
+        ))
+        .unwrap();
+    tracing::debug!("Retrieving routing rules from [{}]", url.to_string());
+
+    let routing_rules = send_json_request_with_retries::<RoutingRulesResult>(
+        http.get(url)
+            .headers(auth_headers(opsgenie_config))
+            .query(&[("teamIdentifierType", identifier_type)]),
+    )
+    .await
+    .context(RequestRoutingRulesSnafu { team: team.clone() })?;
+
+    Ok(routing_rules.data)
+}
+
+/// Tell OpsGenie that we are still alive, OpsGenie alerts if it doesn't hear from us for the
+/// interval configured on the heartbeat
+pub(crate) async fn ping_heartbeat(
+    name: &str,
+    http: &Client,
+    config: &Config,
+) -> Result<(), Error> {
+    let opsgenie_config = &config.opsgenie_config;
+    let url = opsgenie_config
+        .base_url
+        .join(&format!("heartbeats/{}/ping", urlencoding::encode(name)))
+        .unwrap();
+    tracing::debug!("Pinging heartbeat at [{}]", url.to_string());
+
+    send_request_with_retries(http.post(url).headers(auth_headers(opsgenie_config)))
+        .await
+        .context(PingHeartbeatSnafu { name })?;
+    Ok(())
+}
+
+/// The notification rules of a person that apply to new alerts, see [`crate::notification`]
+#[derive(Clone, Debug)]
+pub struct UserNotificationRules {
+    /// IANA name of the timezone of the person, time restrictions are given in it
+    pub timezone: Option<String>,
+    /// Only the enabled rules, by their order
+    pub rules: Vec<NotificationRule>,
+}
+
+#[derive(Clone, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct UserResult {
+    data: UserResultData,
+}
+
+#[derive(Clone, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct UserResultData {
+    time_zone: Option<String>,
+}
+
+#[derive(Clone, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct NotificationRuleSummary {
+    id: String,
+    action_type: String,
+    enabled: bool,
+}
+
+#[derive(Clone, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct NotificationRuleResult {
+    data: NotificationRule,
+}
+
+#[derive(Clone, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct NotificationRule {
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub order: u32,
+    pub criteria: NotificationCriteria,
+    pub time_restriction: Option<TimeRestriction>,
+    #[serde(default)]
+    pub steps: Vec<NotificationStep>,
+}
+
+#[derive(Clone, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct NotificationCriteria {
+    /// `match-all`, `match-any-condition` or `match-all-conditions`
+    #[serde(rename = "type")]
+    pub criteria_type: String,
+    #[serde(default)]
+    pub conditions: Vec<NotificationCondition>,
+}
+
+#[derive(Clone, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct NotificationCondition {
+    /// Field of the alert, e.g. `priority` or `message`
+    pub field: String,
+    pub operation: String,
+    #[serde(default)]
+    pub expected_value: String,
+    /// Negates the condition
+    #[serde(default)]
+    pub not: bool,
+}
+
+/// One contact that is notified by a notification rule
+#[derive(Clone, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct NotificationStep {
+    pub contact: NotificationStepContact,
+    /// Not set for steps that notify right away
+    pub send_after: Option<EscalationDelay>,
+    pub enabled: bool,
+}
+
+#[derive(Clone, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct NotificationStepContact {
+    /// Contact method as OpsGenie names it, see [`ContactMethod::from_opsgenie`]
+    pub method: String,
+    /// Address as it is entered in OpsGenie, phone numbers are not normalized
+    pub to: String,
+}
+
+/// Look up the notification rules of a person for new alerts.
+///
+/// They are cached the same way contacts are, see [`crate::cache::OpsgenieCache`].
+pub(crate) async fn get_notification_rules(
+    username: &str,
+    http: &Client,
+    cache: &OpsgenieCache,
+    config: &Config,
+) -> Result<Lookup<UserNotificationRules>, Error> {
+    let opsgenie_config = &config.opsgenie_config;
+    let headers = auth_headers(opsgenie_config);
+    cache
+        .notification_rules
+        .get_or_fetch(&username.to_string(), opsgenie_config.cache_ttl, || {
+            fetch_notification_rules(http, &opsgenie_config.base_url, &headers, username)
+        })
+        .await
+        .context(RequestNotificationRulesSnafu { username })
+}
+
+async fn fetch_notification_rules(
+    http: &Client,
+    base_url: &Url,
+    headers: &HeaderMap,
+    username: &str,
+) -> Result<UserNotificationRules, crate::util::Error> {
+    let user_path = format!("users/{}", urlencoding::encode(username));
+    let rules_url = base_url
+        .join(&format!("{user_path}/notification-rules"))
+        .unwrap();
+    tracing::debug!(
+        "Retrieving notification rules from [{}]",
+        rules_url.to_string()
+    );
+
+    // Time restrictions are evaluated in the timezone of the user, which the rules don't include
+    let user = send_json_request_with_retries::<UserResult>(
+        http.get(base_url.join(&user_path).unwrap())
+            .headers(headers.clone()),
+    )
+    .await?;
+    let summaries = get_all_pages::<NotificationRuleSummary>(http, rules_url, headers).await?;
+
+    // The list doesn't include the steps, so every rule has to be fetched on its own
+    let mut rules = Vec::new();
+    for summary in summaries
+        .into_iter()
+        .filter(|summary| summary.enabled && summary.action_type == CREATE_ALERT_ACTION)
+    {
+        let url = base_url
+            .join(&format!(
+                "{user_path}/notification-rules/{}",
+                urlencoding::encode(&summary.id)
+            ))
+            .unwrap();
+        let rule = send_json_request_with_retries::<NotificationRuleResult>(
+            http.get(url).headers(headers.clone()),
+        )
+        .await?;
+        rules.push(rule.data);
+    }
+    rules.sort_by_key(|rule| rule.order);
+    tracing::trace!(
+        username,
+        rules = rules.len(),
+        "Got notification rules from OpsGenie"
+    );
+
+    Ok(UserNotificationRules {
+        timezone: user.data.time_zone,
+        rules,
     })
 }
 
@@ -166,51 +1492,322 @@ struct ContactInformationResultData {
 #[serde(rename_all = "camelCase")]
 struct UserContact {
     to: String,
-    id: String,
     contact_method: String,
     enabled: bool,
 }
 
-async fn get_phone_number(
+async fn get_contacts(
     http: Client,
     base_url: Url,
     headers: &HeaderMap,
     username: &str,
-) -> Result<Vec<String>, crate::util::Error> {
+    default_phone_region: Option<country::Id>,
+) -> Result<Vec<Contact>, crate::util::Error> {
     let url_builder = base_url.clone();
-    let url_builder = url_builder.join(&format!("users/{username}")).unwrap();
+    let url_builder = url_builder
+        .join(&format!("users/{}", urlencoding::encode(username)))
+        .unwrap();
     tracing::debug!(
         "Retrieving contact information for [{}] information from [{}]",
         username,
         url_builder.to_string()
     );
     tracing::debug!("Using headers: [{:?}]", headers);
-    let contact_information = send_json_request::<ContactInformationResult>(
+    let contact_information = send_json_request_with_retries::<ContactInformationResult>(
         http.get(url_builder.clone())
             .headers(headers.clone())
             .query(&[("expand", "contact")]),
     )
     .await?;
-    tracing::trace!("Got data from opsgenie: [{:?}]", contact_information);
+    // Only log the number of contacts, the contacts themselves are personal data
+    tracing::trace!(
+        username,
+        contacts = contact_information.data.user_contacts.len(),
+        "Got contact information from OpsGenie"
+    );
 
-    let mut numbers = contact_information
+    let contacts = contact_information
         .data
         .user_contacts
-        .iter()
-        .filter(|user_contact| {
-            user_contact.contact_method.eq("voice") || user_contact.contact_method.eq("sms")
-        })
-        .map(|user_contact| format_phone_number(user_contact.to.clone()))
-        .collect::<Vec<String>>();
-
-    // Sort to enable easier deduplication and remove duplicate numbers
-    numbers.sort();
-    numbers.dedup();
+        .into_iter()
+        .filter_map(|user_contact| to_contact(user_contact, default_phone_region))
+        .collect::<Vec<_>>();
+    for contact in &contacts {
+        if let Some(error) = &contact.error {
+            tracing::warn!(
+                username,
+                ?contact.method,
+                error,
+                "Skipping contact with invalid phone number"
+            );
+        }
+    }
+    Ok(contacts)
+}
 
-    Ok(numbers)
+/// Contact methods we don't know are dropped
+fn to_contact(
+    user_contact: UserContact,
+    default_phone_region: Option<country::Id>,
+) -> Option<Contact> {
+    let method = ContactMethod::from_opsgenie(&user_contact.contact_method)?;
+    // Invalid numbers are kept as they are, so that it is visible what needs fixing in OpsGenie
+    let (address, error) = if method.is_phone() {
+        match normalize_phone_number(&user_contact.to, default_phone_region) {
+            Ok(number) => (number, None),
+            Err(e) => (user_contact.to, Some(e.to_string())),
+        }
+    } else {
+        (user_contact.to, None)
+    };
+    Some(Contact {
+        method,
+        address,
+        enabled: user_contact.enabled,
+        source: ContactSource::Opsgenie,
+        error,
+        quiet: false,
+        send_after_seconds: 0,
+    })
 }
 
-fn format_phone_number(number: String) -> String {
-    let number = number.replace("-", "");
-    format!("+{}", number)
+#[cfg(test)]
+mod test {
+    use super::{
+        get_alert_info_for_users, get_oncall_number, get_schedule_id, next_page, to_contact,
+        ContactMethod, Error, Paging, UserContact, UserContacts,
+    };
+    use crate::cache::OpsgenieCache;
+    use crate::fake_api::FakeApi;
+    use crate::{Schedule, ScheduleRequestByName};
+    use axum::http::{Method, StatusCode};
+    use reqwest::Url;
+    use rstest::rstest;
+    use serde_json::json;
+    use std::time::Duration;
+
+    #[rstest]
+    #[case(None, None)]
+    #[case(
+        Some("https://api.eu.opsgenie.com/v2/users?limit=100&offset=100"),
+        Some("https://api.eu.opsgenie.com/v2/users?limit=100&offset=100")
+    )]
+    #[case(Some("https://evil.example.com/v2/users?offset=100"), None)]
+    #[case(Some("not a url"), None)]
+    fn test_next_page(#[case] next: Option<&str>, #[case] expected: Option<&str>) {
+        let first_page = Url::parse("https://api.eu.opsgenie.com/v2/users").unwrap();
+        let paging = Some(Paging {
+            next: next.map(str::to_string),
+        });
+        assert_eq!(
+            next_page(paging, &first_page).as_ref().map(Url::as_str),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_contacts() {
+        let user_contacts: Vec<UserContact> = serde_json::from_value(json!([
+            {"id": "1", "to": "49-17612345678", "contactMethod": "voice", "enabled": true},
+            {"id": "2", "to": "49-17612345678", "contactMethod": "sms", "enabled": true},
+            {"id": "3", "to": "49-17612345679", "contactMethod": "voice", "enabled": false},
+            {"id": "4", "to": "0176 12345670", "contactMethod": "voice", "enabled": true},
+            {"id": "5", "to": "jane@example.com", "contactMethod": "email", "enabled": true},
+            {"id": "6", "to": "jane@example.com", "contactMethod": "mobile", "enabled": true},
+            {"id": "7", "to": "jane", "contactMethod": "carrier-pigeon", "enabled": true}
+        ]))
+        .unwrap();
+        let user = UserContacts {
+            name: "jane".to_string(),
+            contacts: user_contacts
+                .into_iter()
+                .filter_map(|user_contact| to_contact(user_contact, None))
+                .collect(),
+            notification_rule: None,
+        };
+
+        assert_eq!(
+            user.contacts
+                .iter()
+                .map(|contact| contact.method)
+                .collect::<Vec<_>>(),
+            vec![
+                ContactMethod::Voice,
+                ContactMethod::Sms,
+                ContactMethod::Voice,
+                ContactMethod::Voice,
+                ContactMethod::Email,
+                ContactMethod::MobileApp
+            ]
+        );
+        // Without a default region the national number can't be parsed, it is kept as it is
+        assert_eq!(user.contacts[3].address, "0176 12345670");
+        assert!(user.contacts[3].error.is_some());
+        // Neither disabled nor invalid numbers are called
+        assert_eq!(user.phone_numbers(), vec!["+4917612345678".to_string()]);
+        assert_eq!(
+            user.enabled_addresses(&[ContactMethod::Email]),
+            vec!["jane@example.com".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_serialize_user_contacts() {
+        let user: UserContacts = serde_json::from_value(json!({
+            "name": "jane",
+            "contacts": [
+                {"method": "voice", "address": "+4917612345678", "enabled": true, "source": "opsgenie"},
+                {"method": "voice", "address": "+4917612345679", "enabled": false, "source": "opsgenie"},
+                {"method": "email", "address": "jane@example.com", "enabled": true, "source": "opsgenie"}
+            ]
+        }))
+        .unwrap();
+
+        let serialized = serde_json::to_value(&user).unwrap();
+        assert_eq!(serialized["phone"], json!(["+4917612345678"]));
+        assert_eq!(serialized["contacts"].as_array().unwrap().len(), 3);
+        assert!(serialized.get("notificationRule").is_none());
+        // The extra field doesn't get in the way of reading it back
+        assert_eq!(
+            serde_json::from_value::<UserContacts>(serialized).unwrap(),
+            user
+        );
+    }
+
+    #[tokio::test]
+    async fn test_alert_info_keeps_order() {
+        let api = FakeApi::start().await;
+        let usernames = (0..8).map(|i| format!("user{i}")).collect::<Vec<_>>();
+        for (i, username) in usernames.iter().enumerate() {
+            api.contacts(username, &[&format!("+491511234567{i}")]);
+        }
+        // The first lookups finish last
+        api.delay("/v2/users/user0", Duration::from_millis(200));
+        api.delay("/v2/users/user1", Duration::from_millis(100));
+
+        let alert_info = get_alert_info_for_users(
+            usernames.clone(),
+            &reqwest::Client::new(),
+            &OpsgenieCache::default(),
+            &api.config(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(alert_info.username, "user0");
+        assert_eq!(alert_info.phone_number, "+4915112345670");
+        let names = alert_info
+            .full_information
+            .iter()
+            .map(|user| user.name.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(names, usernames);
+    }
+
+    #[tokio::test]
+    async fn test_alert_info_skips_unreachable_people() {
+        let api = FakeApi::start().await;
+        api.respond(
+            Method::GET,
+            "/v2/users/ann",
+            StatusCode::INTERNAL_SERVER_ERROR,
+            json!({"message": "internal error"}),
+        );
+        api.contacts("bob", &[]);
+        api.contacts("carl", &["+4915112345678"]);
+
+        let alert_info = get_alert_info_for_users(
+            vec!["ann".to_string(), "bob".to_string(), "carl".to_string()],
+            &reqwest::Client::new(),
+            &OpsgenieCache::default(),
+            &api.config(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(alert_info.username, "carl");
+        assert_eq!(alert_info.phone_number, "+4915112345678");
+        assert_eq!(alert_info.phone_numbers(), ["+4915112345678"]);
+        assert_eq!(
+            alert_info
+                .failed_lookups
+                .iter()
+                .map(|failed| failed.username.as_str())
+                .collect::<Vec<_>>(),
+            ["ann"]
+        );
+        assert_eq!(alert_info.full_information.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_alert_info_fails_without_anyone_to_call() {
+        let api = FakeApi::start().await;
+        api.contacts("bob", &[]);
+        let config = api.config();
+        let http = reqwest::Client::new();
+        let cache = OpsgenieCache::default();
+
+        let result =
+            get_alert_info_for_users(vec!["bob".to_string()], &http, &cache, &config).await;
+        assert!(matches!(result, Err(Error::NoPhoneNumber { .. })));
+
+        let result = get_alert_info_for_users(
+            vec!["ann".to_string(), "bob".to_string()],
+            &http,
+            &cache,
+            &config,
+        )
+        .await;
+        assert!(matches!(
+            result,
+            Err(Error::RequestPhoneNumberForPerson { username, .. }) if username == "ann"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_schedule_name_is_encoded() {
+        let api = FakeApi::start().await;
+        api.respond(
+            Method::GET,
+            "/v2/schedules/ops%2Fnight%20%232",
+            StatusCode::OK,
+            json!({"data": {"id": "4a2b", "name": "ops/night #2"}}),
+        );
+        let schedule = Schedule::ScheduleByName(ScheduleRequestByName {
+            name: "ops/night #2".to_string(),
+        });
+
+        let id = get_schedule_id(
+            &schedule,
+            &reqwest::Client::new(),
+            &OpsgenieCache::default(),
+            &api.config(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(id, "4a2b");
+    }
+
+    #[tokio::test]
+    async fn test_on_call_lookup_is_encoded() {
+        let api = FakeApi::start().await;
+        api.on_call(
+            "4a2b",
+            "ops/night #2",
+            "jane.doe@example.com",
+            "+4915112345678",
+        );
+        let schedule = Schedule::ScheduleByName(ScheduleRequestByName {
+            name: "ops/night #2".to_string(),
+        });
+
+        let alert_info = get_oncall_number(
+            &schedule,
+            &reqwest::Client::new(),
+            &OpsgenieCache::default(),
+            &api.config(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(alert_info.username, "jane.doe@example.com");
+        assert_eq!(alert_info.phone_number, "+4915112345678");
+    }
 }
@@ -0,0 +1,259 @@
+use crate::cache::OpsgenieCache;
+use crate::config::Config;
+use crate::opsgenie::{create_override, delete_override, get_shift_oncall_number};
+use crate::overrides::error::{CreateOverrideSnafu, DeleteOverrideSnafu, InvalidRangeSnafu};
+use crate::privacy::MaskPhoneNumbers;
+use crate::timeline::Shift;
+use crate::{http_error, opsgenie, AlertInfo, Schedule};
+use axum::http::StatusCode;
+use chrono::{DateTime, FixedOffset, Utc};
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use snafu::{ensure, ResultExt, Snafu};
+
+#[derive(Snafu, Debug)]
+#[snafu(module)]
+pub enum Error {
+    #[snafu(display("an override has to end after it starts, got {start} to {end}"))]
+    InvalidRange {
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    },
+
+    #[snafu(display("failed to create override: \n{source}"))]
+    CreateOverride { source: opsgenie::Error },
+
+    #[snafu(display("failed to delete override: \n{source}"))]
+    DeleteOverride { source: opsgenie::Error },
+}
+
+impl http_error::Error for Error {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            Error::InvalidRange { .. } => StatusCode::BAD_REQUEST,
+            Error::CreateOverride { source } => source.status_code(),
+            Error::DeleteOverride { source } => source.status_code(),
+        }
+    }
+}
+
+/// Body of `POST /schedules/<schedule>/overrides`
+#[derive(Debug, Deserialize, PartialEq, Eq, Hash, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct OverrideRequest {
+    /// OpsGenie username of whoever takes over
+    pub user: String,
+    /// RFC 3339 timestamp
+    pub start: DateTime<FixedOffset>,
+    /// RFC 3339 timestamp
+    pub end: DateTime<FixedOffset>,
+    /// Identifies the override for deleting it later, generated by OpsGenie if not set
+    pub alias: Option<String>,
+}
+
+#[derive(Debug, Serialize, PartialEq, Eq, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct OverrideResult {
+    pub alias: String,
+    /// Who is on call once the change is in place: at the start of a created override, or right
+    /// now after deleting one. Not set if that couldn't be looked up, the override itself was
+    /// still changed.
+    pub on_call: Option<AlertInfo>,
+}
+
+impl MaskPhoneNumbers for OverrideResult {
+    fn mask_phone_numbers(&mut self) {
+        if let Some(on_call) = &mut self.on_call {
+            on_call.mask_phone_numbers();
+        }
+    }
+}
+
+pub async fn create(
+    schedule: &Schedule,
+    request: &OverrideRequest,
+    http: &Client,
+    cache: &OpsgenieCache,
+    config: &Config,
+) -> Result<OverrideResult, Error> {
+    let start = request.start.with_timezone(&Utc);
+    let end = request.end.with_timezone(&Utc);
+    ensure!(start < end, InvalidRangeSnafu { start, end });
+
+    let alias = create_override(
+        schedule,
+        &request.user,
+        start,
+        end,
+        request.alias.as_deref(),
+        http,
+        config,
+    )
+    .await
+    .context(CreateOverrideSnafu)?;
+    tracing::info!(%schedule, alias, request.user, %start, %end, "Created override");
+
+    // An override that already started applies right away
+    let on_call = look_up_on_call(
+        schedule,
+        Shift::At(start.max(Utc::now())),
+        http,
+        cache,
+        config,
+    )
+    .await;
+    Ok(OverrideResult { alias, on_call })
+}
+
+pub async fn delete(
+    schedule: &Schedule,
+    alias: &str,
+    http: &Client,
+    cache: &OpsgenieCache,
+    config: &Config,
+) -> Result<OverrideResult, Error> {
+    delete_override(schedule, alias, http, config)
+        .await
+        .context(DeleteOverrideSnafu)?;
+    tracing::info!(%schedule, alias, "Deleted override");
+
+    let on_call = look_up_on_call(schedule, Shift::Current, http, cache, config).await;
+    Ok(OverrideResult {
+        alias: alias.to_string(),
+        on_call,
+    })
+}
+
+async fn look_up_on_call(
+    schedule: &Schedule,
+    shift: Shift,
+    http: &Client,
+    cache: &OpsgenieCache,
+    config: &Config,
+) -> Option<AlertInfo> {
+    match get_shift_oncall_number(schedule, shift, http, cache, config).await {
+        Ok(alert_info) => Some(alert_info),
+        Err(e) => {
+            tracing::warn!(
+                error = &e as &dyn std::error::Error,
+                %schedule,
+                "Failed to look up who is on call after changing an override"
+            );
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{create, delete, Error, OverrideRequest};
+    use crate::cache::OpsgenieCache;
+    use crate::fake_api::FakeApi;
+    use crate::{Schedule, ScheduleRequestById, ScheduleRequestByName};
+    use axum::http::{Method, StatusCode};
+    use chrono::DateTime;
+    use serde_json::{json, Value};
+
+    fn request(start: &str, end: &str) -> OverrideRequest {
+        OverrideRequest {
+            user: "jane".to_string(),
+            start: DateTime::parse_from_rfc3339(start).unwrap(),
+            end: DateTime::parse_from_rfc3339(end).unwrap(),
+            alias: Some("sick-day".to_string()),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_create_override() {
+        let api = FakeApi::start().await;
+        api.respond(
+            Method::POST,
+            "/v2/schedules/ops/overrides",
+            StatusCode::CREATED,
+            json!({"data": {"alias": "sick-day"}}),
+        );
+        let schedule = Schedule::ScheduleByName(ScheduleRequestByName {
+            name: "ops".to_string(),
+        });
+
+        let result = create(
+            &schedule,
+            &request("2030-06-01T09:00:00+02:00", "2030-06-01T17:00:00+02:00"),
+            &reqwest::Client::new(),
+            &OpsgenieCache::default(),
+            &api.config(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(result.alias, "sick-day");
+        // Nobody is on call according to the fake API
+        assert_eq!(result.on_call, None);
+
+        let requests = api.requests(Method::POST, "/v2/schedules/ops/overrides");
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].query, "scheduleIdentifierType=name");
+        let body: Value = serde_json::from_str(&requests[0].body).unwrap();
+        assert_eq!(
+            body,
+            json!({
+                "alias": "sick-day",
+                "user": {"type": "user", "username": "jane"},
+                "startDate": "2030-06-01T07:00:00Z",
+                "endDate": "2030-06-01T15:00:00Z",
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_create_override_rejects_empty_range() {
+        let api = FakeApi::start().await;
+        let schedule = Schedule::ScheduleByName(ScheduleRequestByName {
+            name: "ops".to_string(),
+        });
+
+        // Same instant in different timezones
+        let result = create(
+            &schedule,
+            &request("2030-06-01T09:00:00+02:00", "2030-06-01T07:00:00Z"),
+            &reqwest::Client::new(),
+            &OpsgenieCache::default(),
+            &api.config(),
+        )
+        .await;
+        assert!(matches!(result, Err(Error::InvalidRange { .. })));
+        assert!(api
+            .requests(Method::POST, "/v2/schedules/ops/overrides")
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_delete_override() {
+        let api = FakeApi::start().await;
+        api.respond(
+            Method::DELETE,
+            "/v2/schedules/4a2b/overrides/sick%20day",
+            StatusCode::OK,
+            json!({"result": "Deleted"}),
+        );
+        api.on_call("4a2b", "ops", "joe", "+4915187654321");
+        let schedule = Schedule::ScheduleById(ScheduleRequestById {
+            id: "4a2b".to_string(),
+        });
+
+        let result = delete(
+            &schedule,
+            "sick day",
+            &reqwest::Client::new(),
+            &OpsgenieCache::default(),
+            &api.config(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(result.alias, "sick day");
+        assert_eq!(result.on_call.unwrap().username, "joe");
+
+        let requests = api.requests(Method::DELETE, "/v2/schedules/4a2b/overrides/sick%20day");
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].query, "scheduleIdentifierType=id");
+    }
+}
@@ -0,0 +1,69 @@
+use crate::phone::error::{InvalidNumberSnafu, ParseNumberSnafu};
+use phonenumber::country;
+use phonenumber::Mode;
+use snafu::{ensure, ResultExt, Snafu};
+
+#[derive(Snafu, Debug)]
+#[snafu(module)]
+pub enum Error {
+    #[snafu(display("failed to parse phone number: {source}"))]
+    ParseNumber { source: phonenumber::ParseError },
+
+    #[snafu(display("not a valid phone number"))]
+    InvalidNumber,
+}
+
+/// Bring a phone number as entered in OpsGenie into E.164 format, e.g. `+4917612345678`.
+///
+/// OpsGenie stores numbers as `<country code>-<number>`, but numbers may also contain spaces,
+/// parentheses or a leading `+`. Numbers in national format, e.g. `0176 12345678`, can only be
+/// parsed with a `default_region`.
+pub fn normalize_phone_number(
+    number: &str,
+    default_region: Option<country::Id>,
+) -> Result<String, Error> {
+    let number = number.trim();
+    let number = match number.split_once('-') {
+        Some((country_code, _))
+            if (1..=3).contains(&country_code.len())
+                && !country_code.starts_with('0')
+                && country_code.chars().all(|c| c.is_ascii_digit()) =>
+        {
+            format!("+{number}")
+        }
+        _ => number.to_string(),
+    };
+
+    let parsed = phonenumber::parse(default_region, &number).context(ParseNumberSnafu)?;
+    ensure!(phonenumber::is_valid(&parsed), InvalidNumberSnafu);
+    Ok(parsed.format().mode(Mode::E164).to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::normalize_phone_number;
+    use phonenumber::country;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case::opsgenie("49-17612345678", None, Some("+4917612345678"))]
+    #[case::international("+49 176 12345678", None, Some("+4917612345678"))]
+    #[case::already_normalized("+4917612345678", None, Some("+4917612345678"))]
+    #[case::national("(0176) 1234-5678", Some(country::Id::DE), Some("+4917612345678"))]
+    #[case::foreign_with_region("+1 650-253-0000", Some(country::Id::DE), Some("+16502530000"))]
+    #[case::national_without_region("0176 12345678", None, None)]
+    #[case::too_short("49-123", None, None)]
+    #[case::garbage("call me maybe", Some(country::Id::DE), None)]
+    fn test_normalize_phone_number(
+        #[case] number: &str,
+        #[case] default_region: Option<country::Id>,
+        #[case] expected: Option<&str>,
+    ) {
+        assert_eq!(
+            normalize_phone_number(number, default_region)
+                .ok()
+                .as_deref(),
+            expected
+        );
+    }
+}
@@ -0,0 +1,146 @@
+use crate::auth::{Caller, Scope};
+use crate::config::Config;
+use crate::twilio::{AlertResult, DialNumberResult};
+use crate::AlertInfo;
+
+// Enough to tell the country and, for people with several numbers, which one was meant
+static UNMASKED_PREFIX_CHARS: usize = 3;
+static UNMASKED_SUFFIX_CHARS: usize = 3;
+
+/// Replace everything but the start and end of a phone number, e.g. `+4917612345123` becomes
+/// `+49********123`
+pub fn mask_phone_number(number: &str) -> String {
+    let chars = number.chars().collect::<Vec<_>>();
+    if chars.len() <= UNMASKED_PREFIX_CHARS + UNMASKED_SUFFIX_CHARS {
+        return "*".repeat(chars.len());
+    }
+
+    let masked_len = chars.len() - UNMASKED_PREFIX_CHARS - UNMASKED_SUFFIX_CHARS;
+    chars[..UNMASKED_PREFIX_CHARS]
+        .iter()
+        .chain(std::iter::repeat_n(&'*', masked_len))
+        .chain(&chars[chars.len() - UNMASKED_SUFFIX_CHARS..])
+        .collect()
+}
+
+/// Mask `number` wherever it appears in `text`, e.g. in an error message that echoes it, with or
+/// without its leading `+` (it is `%2B` when url encoded)
+pub fn mask_phone_number_in(text: &str, number: &str) -> String {
+    let digits = number.trim_start_matches('+');
+    if digits.is_empty() {
+        return text.to_string();
+    }
+    text.replace(number, &mask_phone_number(number))
+        .replace(digits, &mask_phone_number(digits))
+}
+
+/// Phone numbers are only shown in full if masking is disabled or the caller is explicitly
+/// allowed to see them
+pub fn should_mask(config: &Config, caller: &Caller) -> bool {
+    config.mask_phone_numbers && !caller.scopes.contains(&Scope::ReadPhoneNumbers)
+}
+
+/// Phone numbers as they should appear in logs
+pub fn loggable_numbers(config: &Config, numbers: &[String]) -> Vec<String> {
+    if config.mask_phone_numbers {
+        numbers
+            .iter()
+            .map(|number| mask_phone_number(number))
+            .collect()
+    } else {
+        numbers.to_vec()
+    }
+}
+
+pub trait MaskPhoneNumbers {
+    fn mask_phone_numbers(&mut self);
+}
+
+impl MaskPhoneNumbers for AlertInfo {
+    fn mask_phone_numbers(&mut self) {
+        self.phone_number = mask_phone_number(&self.phone_number);
+        for person in &mut self.full_information {
+            for contact in &mut person.contacts {
+                if contact.method.is_phone() {
+                    contact.address = mask_phone_number(&contact.address);
+                }
+            }
+        }
+    }
+}
+
+impl MaskPhoneNumbers for DialNumberResult {
+    fn mask_phone_numbers(&mut self) {
+        match self {
+            DialNumberResult::Failure { number, error } => {
+                // Twilio errors can contain the number that was dialed
+                *error = mask_phone_number_in(error, number);
+                *number = mask_phone_number(number);
+            }
+            DialNumberResult::Success { number } | DialNumberResult::Unknown { number, .. } => {
+                *number = mask_phone_number(number)
+            }
+        }
+    }
+}
+
+impl MaskPhoneNumbers for AlertResult {
+    fn mask_phone_numbers(&mut self) {
+        self.detailed_result
+            .iter_mut()
+            .for_each(MaskPhoneNumbers::mask_phone_numbers);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{mask_phone_number, mask_phone_number_in, MaskPhoneNumbers};
+    use crate::twilio::DialNumberResult;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case("+49123456123", "+49******123")]
+    #[case("+4917612345123", "+49********123")]
+    #[case("+1234567", "+12**567")]
+    #[case("+123456", "+12*456")]
+    #[case("123456", "******")]
+    #[case("", "")]
+    fn test_mask_phone_number(#[case] number: &str, #[case] expected: &str) {
+        assert_eq!(mask_phone_number(number), expected);
+    }
+
+    #[rstest]
+    #[case(
+        "The 'To' number +49123456123 is not valid",
+        "+49123456123",
+        "The 'To' number +49******123 is not valid"
+    )]
+    #[case(
+        "To=%2B49123456123 rejected",
+        "+49123456123",
+        "To=%2B491*****123 rejected"
+    )]
+    #[case("busy", "+49123456123", "busy")]
+    #[case("busy", "", "busy")]
+    fn test_mask_phone_number_in(#[case] text: &str, #[case] number: &str, #[case] expected: &str) {
+        assert_eq!(mask_phone_number_in(text, number), expected);
+    }
+
+    #[test]
+    fn test_mask_failure() {
+        let mut result = DialNumberResult::Failure {
+            number: "+49123456123".to_string(),
+            error: "http response 400 with response body \"+49123456123 is unreachable\""
+                .to_string(),
+        };
+        result.mask_phone_numbers();
+        assert_eq!(
+            result,
+            DialNumberResult::Failure {
+                number: "+49******123".to_string(),
+                error: "http response 400 with response body \"+49******123 is unreachable\""
+                    .to_string(),
+            }
+        );
+    }
+}
@@ -0,0 +1,217 @@
+use crate::config::RateLimitConfig;
+use crate::http_error;
+use crate::ratelimit::error::{CallerLimitExceededSnafu, TargetLimitExceededSnafu};
+use crate::AlertTarget;
+use axum::http::StatusCode;
+use snafu::Snafu;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+#[derive(Snafu, Debug)]
+#[snafu(module)]
+pub enum Error {
+    #[snafu(display(
+        "[{caller}] may trigger at most {limit} alerts within {window:?}, try again later"
+    ))]
+    CallerLimitExceeded {
+        caller: String,
+        limit: u32,
+        window: Duration,
+    },
+
+    #[snafu(display(
+        "at most {limit} alerts may be triggered for {target} within {window:?}, try again later"
+    ))]
+    TargetLimitExceeded {
+        target: AlertTarget,
+        limit: u32,
+        window: Duration,
+    },
+}
+
+impl http_error::Error for Error {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            Error::CallerLimitExceeded { .. } => StatusCode::TOO_MANY_REQUESTS,
+            Error::TargetLimitExceeded { .. } => StatusCode::TOO_MANY_REQUESTS,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum LimitKey {
+    Caller(String),
+    Target(AlertTarget),
+}
+
+impl From<&Error> for LimitKey {
+    fn from(error: &Error) -> Self {
+        match error {
+            Error::CallerLimitExceeded { caller, .. } => LimitKey::Caller(caller.clone()),
+            Error::TargetLimitExceeded { target, .. } => LimitKey::Target(target.clone()),
+        }
+    }
+}
+
+/// Counts triggered alerts per caller and per [`AlertTarget`] over a sliding window.
+///
+/// State is kept in memory, so every replica counts on its own and counters start from zero after
+/// a restart.
+#[derive(Debug, Clone, Default)]
+pub struct RateLimiter {
+    state: Arc<Mutex<RateLimiterState>>,
+}
+
+#[derive(Debug, Default)]
+struct RateLimiterState {
+    /// Points in time at which alerts were let through, oldest first
+    triggered: HashMap<LimitKey, VecDeque<Instant>>,
+    /// When we last told someone that a limit was hit
+    notified: HashMap<LimitKey, Instant>,
+}
+
+impl RateLimiter {
+    /// Record an alert for the caller and target, or reject it if either is over its limit.
+    ///
+    /// Rejected alerts are not counted, so a caller that keeps retrying is let through again once
+    /// its older alerts leave the window.
+    ///
+    /// Without a caller, the alert only counts against the limit of its target.
+    pub fn check(
+        &self,
+        caller: Option<&str>,
+        target: &AlertTarget,
+        config: &RateLimitConfig,
+    ) -> Result<(), Error> {
+        let now = Instant::now();
+        let window = config.window;
+        let caller_key = caller.map(|caller| LimitKey::Caller(caller.to_string()));
+        let target_key = LimitKey::Target(target.clone());
+
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        for timestamps in state.triggered.values_mut() {
+            while timestamps
+                .front()
+                .is_some_and(|triggered| now.duration_since(*triggered) >= window)
+            {
+                timestamps.pop_front();
+            }
+        }
+        state
+            .triggered
+            .retain(|_, timestamps| !timestamps.is_empty());
+
+        let count = |key: &LimitKey| state.triggered.get(key).map_or(0, VecDeque::len);
+        if let (Some(limit), Some(caller)) = (config.per_caller, caller) {
+            if count(&LimitKey::Caller(caller.to_string())) >= limit as usize {
+                return CallerLimitExceededSnafu {
+                    caller,
+                    limit,
+                    window,
+                }
+                .fail();
+            }
+        }
+        if let Some(limit) = config.per_target {
+            if count(&target_key) >= limit as usize {
+                return TargetLimitExceededSnafu {
+                    target: target.clone(),
+                    limit,
+                    window,
+                }
+                .fail();
+            }
+        }
+
+        for key in caller_key.into_iter().chain([target_key]) {
+            state.triggered.entry(key).or_default().push_back(now);
+        }
+        Ok(())
+    }
+
+    /// Whether a rejection should be reported, this is true at most once per limit and window so
+    /// a flood of requests doesn't turn into a flood of notifications
+    pub fn should_notify(&self, error: &Error, config: &RateLimitConfig) -> bool {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        state
+            .notified
+            .retain(|_, notified| now.duration_since(*notified) < config.window);
+        match state.notified.entry(LimitKey::from(error)) {
+            std::collections::hash_map::Entry::Occupied(_) => false,
+            std::collections::hash_map::Entry::Vacant(entry) => {
+                entry.insert(now);
+                true
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Error, RateLimiter};
+    use crate::config::RateLimitConfig;
+    use crate::{AlertTarget, Schedule, ScheduleRequestByName};
+    use std::time::Duration;
+
+    fn schedule(name: &str) -> AlertTarget {
+        AlertTarget::Schedule(Schedule::ScheduleByName(ScheduleRequestByName {
+            name: name.to_string(),
+        }))
+    }
+
+    #[test]
+    fn test_caller_and_schedule_limits() {
+        let config = RateLimitConfig {
+            window: Duration::from_secs(3600),
+            per_caller: Some(3),
+            per_target: Some(2),
+        };
+        let limiter = RateLimiter::default();
+
+        assert!(limiter
+            .check(Some("grafana"), &schedule("ops"), &config)
+            .is_ok());
+        assert!(limiter
+            .check(Some("grafana"), &schedule("ops"), &config)
+            .is_ok());
+        let rejected = limiter.check(Some("grafana"), &schedule("ops"), &config);
+        assert!(matches!(rejected, Err(Error::TargetLimitExceeded { .. })));
+
+        // Other schedules are still allowed until the caller limit is hit
+        assert!(limiter
+            .check(Some("grafana"), &schedule("dev"), &config)
+            .is_ok());
+        let rejected = limiter.check(Some("grafana"), &schedule("dev"), &config);
+        assert!(matches!(rejected, Err(Error::CallerLimitExceeded { .. })));
+        // Alerts without a caller are only limited per target
+        assert!(limiter.check(None, &schedule("db"), &config).is_ok());
+
+        // Only the first rejection for a limit is reported
+        let rejected = rejected.unwrap_err();
+        assert!(limiter.should_notify(&rejected, &config));
+        assert!(!limiter.should_notify(&rejected, &config));
+    }
+
+    #[test]
+    fn test_window_expiry() {
+        let config = RateLimitConfig {
+            window: Duration::from_millis(10),
+            per_caller: Some(1),
+            per_target: None,
+        };
+        let limiter = RateLimiter::default();
+
+        assert!(limiter
+            .check(Some("grafana"), &schedule("ops"), &config)
+            .is_ok());
+        assert!(limiter
+            .check(Some("grafana"), &schedule("ops"), &config)
+            .is_err());
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(limiter
+            .check(Some("grafana"), &schedule("ops"), &config)
+            .is_ok());
+    }
+}
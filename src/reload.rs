@@ -0,0 +1,213 @@
+use crate::cache::OpsgenieCache;
+use crate::config::{Config, ConfigError};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, PoisonError, RwLock};
+use tokio::time::{interval_at, Instant, MissedTickBehavior};
+
+/// Holds the currently active [`Config`] and allows replacing it at runtime.
+///
+/// Request handlers grab a snapshot via [`SharedConfig::current`] at the start of a request, so
+/// a reload never changes the config underneath a request that is already in flight.
+#[derive(Debug, Clone)]
+pub struct SharedConfig {
+    current: Arc<RwLock<Arc<Config>>>,
+    reload_failures: Arc<AtomicU64>,
+}
+
+impl SharedConfig {
+    pub fn new(config: Config) -> Self {
+        SharedConfig {
+            current: Arc::new(RwLock::new(Arc::new(config))),
+            reload_failures: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    pub fn current(&self) -> Arc<Config> {
+        // Nothing can panic while the lock is held, but if it ever happens the old config is
+        // still perfectly usable
+        self.current
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    /// Number of reload attempts that failed since startup
+    pub fn reload_failures(&self) -> u64 {
+        self.reload_failures.load(Ordering::Relaxed)
+    }
+
+    /// Parse the config again and swap it in if it is valid, otherwise keep the old one.
+    ///
+    /// Returns whether the new config talks to another OpsGenie account, in which case nothing
+    /// that was looked up with the old one may be used anymore.
+    pub fn reload(&self) -> Result<bool, ConfigError> {
+        self.replace(Config::new())
+    }
+
+    fn replace(&self, new_config: Result<Config, ConfigError>) -> Result<bool, ConfigError> {
+        let new_config = match new_config {
+            Ok(config) => config,
+            Err(e) => {
+                let reload_failures = self.reload_failures.fetch_add(1, Ordering::Relaxed) + 1;
+                tracing::error!(
+                    error = &e as &dyn std::error::Error,
+                    reload_failures,
+                    "Failed to reload config, keeping the currently active config"
+                );
+                return Err(e);
+            }
+        };
+
+        let old_config = self.current();
+        if (old_config.bind_address, old_config.bind_port)
+            != (new_config.bind_address, new_config.bind_port)
+        {
+            tracing::warn!(
+                old_bind_address = ?old_config.bind_address,
+                old_bind_port = old_config.bind_port,
+                new_bind_address = ?new_config.bind_address,
+                new_bind_port = new_config.bind_port,
+                "Bind address changed, this only takes effect after a restart"
+            );
+        }
+
+        let account_changed = !old_config
+            .opsgenie_config
+            .same_account(&new_config.opsgenie_config);
+
+        *self.current.write().unwrap_or_else(PoisonError::into_inner) = Arc::new(new_config);
+        tracing::debug!("Config reloaded");
+        Ok(account_changed)
+    }
+}
+
+/// Reload the config whenever the reload interval elapses or SIGHUP is received.
+///
+/// The config is read from environment variables, which can't change in a running process, so
+/// only secrets that are read from `*_FILE` files are ever picked up.
+///
+/// This runs until the process exits, failures are logged and counted by
+/// [`SharedConfig::reload`] and don't stop the loop. The cache is flushed when the OpsGenie base
+/// URL or API key change, as it may hold data of another account.
+pub async fn watch(shared_config: SharedConfig, opsgenie_cache: OpsgenieCache) {
+    let reload_interval = shared_config.current().reload_interval;
+
+    let mut ticker = reload_interval.map(|period| {
+        let mut ticker = interval_at(Instant::now() + period, period);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        ticker
+    });
+
+    #[cfg(unix)]
+    let mut sighup = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()) {
+        Ok(sighup) => Some(sighup),
+        Err(e) => {
+            tracing::warn!(
+                error = &e as &dyn std::error::Error,
+                "Failed to register SIGHUP handler, config can only be reloaded periodically"
+            );
+            None
+        }
+    };
+
+    tracing::info!(?reload_interval, "Watching for config changes");
+
+    loop {
+        let periodic = async {
+            match ticker.as_mut() {
+                Some(ticker) => {
+                    ticker.tick().await;
+                }
+                None => futures::future::pending().await,
+            }
+        };
+
+        #[cfg(unix)]
+        let signalled = async {
+            match sighup.as_mut() {
+                Some(sighup) => {
+                    sighup.recv().await;
+                }
+                None => futures::future::pending().await,
+            }
+        };
+        #[cfg(not(unix))]
+        let signalled = futures::future::pending::<()>();
+
+        tokio::select! {
+            _ = periodic => tracing::trace!("Reload interval elapsed"),
+            _ = signalled => tracing::info!("Received SIGHUP, reloading config"),
+        }
+
+        // Errors have already been logged by reload()
+        if let Ok(true) = shared_config.reload() {
+            tracing::info!("OpsGenie base URL or API key changed, flushing the cache");
+            opsgenie_cache.clear();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::SharedConfig;
+    use crate::config::{AuthHeader, Config, ConfigError};
+    use axum::http::HeaderValue;
+    use secrecy::Secret;
+    use std::net::Ipv4Addr;
+    use std::sync::Arc;
+    use url::Url;
+
+    fn config() -> Config {
+        Config::for_tests(&Url::parse("http://localhost:1234/").unwrap())
+    }
+
+    #[test]
+    fn test_replace_swaps_config() {
+        let shared_config = SharedConfig::new(config());
+        let in_flight = shared_config.current();
+
+        let mut new_config = config();
+        new_config.mask_phone_numbers = true;
+        shared_config.replace(Ok(new_config)).unwrap();
+
+        assert!(shared_config.current().mask_phone_numbers);
+        // Requests that already grabbed the old config keep using it
+        assert!(!in_flight.mask_phone_numbers);
+        assert_eq!(shared_config.reload_failures(), 0);
+    }
+
+    #[test]
+    fn test_replace_keeps_config_on_error() {
+        let shared_config = SharedConfig::new(config());
+        let before = shared_config.current();
+
+        for expected_failures in 1..=2 {
+            let result = shared_config.replace(Err(ConfigError::MissingRequiredValue {
+                envname: "WYGC_TWILIO_WORKFLOW".to_string(),
+            }));
+            assert!(result.is_err());
+            assert_eq!(shared_config.reload_failures(), expected_failures);
+        }
+        assert!(Arc::ptr_eq(&before, &shared_config.current()));
+    }
+
+    #[test]
+    fn test_replace_reports_account_change() {
+        let shared_config = SharedConfig::new(config());
+
+        let mut new_config = config();
+        // Only takes effect after a restart, but must not stop the rest from being reloaded
+        new_config.bind_address = Ipv4Addr::UNSPECIFIED.into();
+        assert!(!shared_config.replace(Ok(new_config)).unwrap());
+        assert_eq!(shared_config.current().bind_address, Ipv4Addr::UNSPECIFIED);
+
+        let mut new_config = config();
+        new_config.opsgenie_config.credentials = Secret::new(AuthHeader(HeaderValue::from_static(
+            "GenieKey 00000000-0000-0000-0000-000000000000",
+        )));
+        assert!(shared_config.replace(Ok(new_config)).unwrap());
+
+        let other_account = Config::for_tests(&Url::parse("http://localhost:5678/").unwrap());
+        assert!(shared_config.replace(Ok(other_account)).unwrap());
+    }
+}
@@ -0,0 +1,344 @@
+use crate::cache::OpsgenieCache;
+use crate::config::Config;
+use crate::escalation::resolve_first_step;
+use crate::opsgenie::{
+    get_routing_rules, get_shift_oncall_number, RoutingNotify, RoutingRule, TimeRestriction,
+};
+use crate::routing::error::{
+    EmptyEscalationSnafu, EscalationShiftSnafu, NoMatchingRuleSnafu, NobodyNotifiedSnafu,
+    RequestRoutingRulesSnafu, ResolveEscalationSnafu, ResolveScheduleSnafu,
+};
+use crate::timeline::Shift;
+use crate::{
+    escalation, http_error, opsgenie, AlertInfo, Escalation, EscalationRequestById, Schedule,
+    ScheduleRequestById, Team,
+};
+use axum::http::StatusCode;
+use chrono::{DateTime, Datelike, Timelike, Utc, Weekday};
+use chrono_tz::Tz;
+use reqwest::Client;
+use snafu::{OptionExt, ResultExt, Snafu};
+
+static MINUTES_PER_DAY: u32 = 24 * 60;
+
+// Routing rules can also match on fields of an alert like its priority or tags, we don't have an
+// alert to match against, so only rules that match everything are considered
+static MATCH_ALL_CRITERIA: &str = "match-all";
+
+#[derive(Snafu, Debug)]
+#[snafu(module)]
+pub enum Error {
+    #[snafu(display("failed to look up routing rules: \n{source}"))]
+    RequestRoutingRules { source: opsgenie::Error },
+
+    #[snafu(display("no routing rule of team {team} applies right now"))]
+    NoMatchingRule { team: Team },
+
+    #[snafu(display("routing rule [{rule}] of team {team} doesn't notify anyone"))]
+    NobodyNotified { team: Team, rule: String },
+
+    #[snafu(display("failed to look up who is on call for team {team}: \n{source}"))]
+    ResolveSchedule {
+        #[snafu(source(from(opsgenie::Error, Box::new)))]
+        source: Box<opsgenie::Error>,
+        team: Team,
+    },
+
+    #[snafu(display("failed to resolve escalation policy of team {team}: \n{source}"))]
+    ResolveEscalation {
+        #[snafu(source(from(escalation::Error, Box::new)))]
+        source: Box<escalation::Error>,
+        team: Team,
+    },
+
+    #[snafu(display("escalation policy of team {team} has no one to call"))]
+    EmptyEscalation { team: Team },
+
+    #[snafu(display(
+        "team {team} routes to an escalation policy at that time, only the current shift can be looked up for escalation policies"
+    ))]
+    EscalationShift { team: Team },
+}
+
+impl http_error::Error for Error {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            Error::RequestRoutingRules { source } => source.status_code(),
+            Error::NoMatchingRule { .. } => StatusCode::IM_A_TEAPOT,
+            Error::NobodyNotified { .. } => StatusCode::IM_A_TEAPOT,
+            Error::ResolveSchedule { source, .. } => source.status_code(),
+            Error::ResolveEscalation { source, .. } => source.status_code(),
+            Error::EmptyEscalation { .. } => StatusCode::IM_A_TEAPOT,
+            Error::EscalationShift { .. } => StatusCode::BAD_REQUEST,
+        }
+    }
+}
+
+/// Where the routing rules of a team send alerts to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RoutingTarget {
+    Schedule(Schedule),
+    Escalation(Escalation),
+}
+
+/// Evaluate the routing rules of a team for the given time
+pub async fn route_team(
+    team: &Team,
+    at: DateTime<Utc>,
+    http: &Client,
+    config: &Config,
+) -> Result<RoutingTarget, Error> {
+    let rules = get_routing_rules(team, http, config)
+        .await
+        .context(RequestRoutingRulesSnafu)?;
+    let rule = select_rule(&rules, at).context(NoMatchingRuleSnafu { team: team.clone() })?;
+    tracing::debug!(%team, rule.name, ?rule.notify, "Selected routing rule");
+
+    match &rule.notify {
+        RoutingNotify::Schedule { id, .. } => Ok(RoutingTarget::Schedule(Schedule::ScheduleById(
+            ScheduleRequestById { id: id.clone() },
+        ))),
+        RoutingNotify::Escalation { id, .. } => Ok(RoutingTarget::Escalation(
+            Escalation::EscalationById(EscalationRequestById { id: id.clone() }),
+        )),
+        RoutingNotify::None | RoutingNotify::Unsupported => NobodyNotifiedSnafu {
+            team: team.clone(),
+            rule: &rule.name,
+        }
+        .fail(),
+    }
+}
+
+/// Look up who is on call for a team in the given shift, the routing rules are evaluated for the
+/// time of the shift.
+///
+/// If the team routes to an escalation policy, this is whoever is called in the first step of
+/// it, the later steps are only called when the escalation itself is triggered. Escalation
+/// policies are always resolved for the current time, so they can't be looked up for other
+/// shifts.
+pub async fn get_team_oncall(
+    team: &Team,
+    shift: Shift,
+    http: &Client,
+    cache: &OpsgenieCache,
+    config: &Config,
+) -> Result<AlertInfo, Error> {
+    let at = shift.date().unwrap_or_else(Utc::now);
+    match route_team(team, at, http, config).await? {
+        RoutingTarget::Schedule(schedule) => {
+            get_shift_oncall_number(&schedule, shift, http, cache, config)
+                .await
+                .context(ResolveScheduleSnafu { team: team.clone() })
+        }
+        RoutingTarget::Escalation(_) if shift != Shift::Current => {
+            EscalationShiftSnafu { team: team.clone() }.fail()
+        }
+        RoutingTarget::Escalation(escalation) => {
+            resolve_first_step(&escalation, http, cache, config)
+                .await
+                .context(ResolveEscalationSnafu { team: team.clone() })?
+                .context(EmptyEscalationSnafu { team: team.clone() })
+        }
+    }
+}
+
+/// The first rule in order that applies at the given time, the default rule only applies if no
+/// other rule does
+fn select_rule(rules: &[RoutingRule], now: DateTime<Utc>) -> Option<&RoutingRule> {
+    let mut candidates = rules
+        .iter()
+        .filter(|rule| rule.criteria.criteria_type == MATCH_ALL_CRITERIA)
+        .collect::<Vec<_>>();
+    candidates.sort_by_key(|rule| (rule.is_default, rule.order));
+    candidates.into_iter().find(|rule| is_active(rule, now))
+}
+
+fn is_active(rule: &RoutingRule, now: DateTime<Utc>) -> bool {
+    is_within_time_restriction(
+        &rule.name,
+        rule.timezone.as_deref(),
+        rule.time_restriction.as_ref(),
+        now,
+    )
+}
+
+/// Whether a rule with the given time restriction applies at `now`, rules without a restriction
+/// always do. The restriction is evaluated in `timezone`, or UTC if there is none.
+///
+/// This is shared with notification rules, `rule` is only used for logging.
+pub(crate) fn is_within_time_restriction(
+    rule: &str,
+    timezone: Option<&str>,
+    time_restriction: Option<&TimeRestriction>,
+    now: DateTime<Utc>,
+) -> bool {
+    let Some(time_restriction) = time_restriction else {
+        return true;
+    };
+
+    let timezone = match timezone.map(str::parse::<Tz>) {
+        None => Tz::UTC,
+        Some(Ok(timezone)) => timezone,
+        Some(Err(e)) => {
+            tracing::warn!(
+                rule,
+                error = e.to_string(),
+                "Rule has an unknown timezone, evaluating it in UTC"
+            );
+            Tz::UTC
+        }
+    };
+    let now = now.with_timezone(&timezone);
+    let minute_of_day = now.hour() * 60 + now.minute();
+
+    match time_restriction {
+        TimeRestriction::TimeOfDay { restriction } => in_window(
+            minute_of_day,
+            restriction.start_hour * 60 + restriction.start_min,
+            restriction.end_hour * 60 + restriction.end_min,
+        ),
+        TimeRestriction::WeekdayAndTimeOfDay { restrictions } => {
+            let minute_of_week =
+                now.weekday().num_days_from_monday() * MINUTES_PER_DAY + minute_of_day;
+            restrictions.iter().any(|restriction| {
+                match (
+                    restriction.start_day.parse::<Weekday>(),
+                    restriction.end_day.parse::<Weekday>(),
+                ) {
+                    (Ok(start_day), Ok(end_day)) => in_window(
+                        minute_of_week,
+                        start_day.num_days_from_monday() * MINUTES_PER_DAY
+                            + restriction.start_hour * 60
+                            + restriction.start_min,
+                        end_day.num_days_from_monday() * MINUTES_PER_DAY
+                            + restriction.end_hour * 60
+                            + restriction.end_min,
+                    ),
+                    _ => {
+                        tracing::warn!(
+                            rule,
+                            restriction.start_day,
+                            restriction.end_day,
+                            "Rule has an unknown weekday, ignoring the restriction"
+                        );
+                        false
+                    }
+                }
+            })
+        }
+    }
+}
+
+/// Whether `now` lies within `[start, end)`, a window that ends before it starts wraps around
+/// midnight or the end of the week, and a window that ends where it starts covers everything
+fn in_window(now: u32, start: u32, end: u32) -> bool {
+    if start < end {
+        start <= now && now < end
+    } else if start > end {
+        now >= start || now < end
+    } else {
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{in_window, select_rule};
+    use crate::opsgenie::{RoutingNotify, RoutingRule};
+    use chrono::{DateTime, Utc};
+    use rstest::rstest;
+    use serde_json::{json, Value};
+
+    fn rules() -> Vec<RoutingRule> {
+        serde_json::from_value(json!([
+            {
+                "name": "Default Routing Rule",
+                "isDefault": true,
+                "order": 0,
+                "criteria": {"type": "match-all"},
+                "notify": {"type": "schedule", "id": "default-schedule", "name": "ops_schedule"}
+            },
+            {
+                "name": "Critical",
+                "order": 0,
+                "criteria": {
+                    "type": "match-any-condition",
+                    "conditions": [{"field": "priority", "operation": "equals", "expectedValue": "P1"}]
+                },
+                "notify": {"type": "escalation", "id": "critical-escalation", "name": "critical"}
+            },
+            {
+                "name": "Business hours",
+                "order": 1,
+                "criteria": {"type": "match-all"},
+                "timezone": "Europe/Berlin",
+                "timeRestriction": {
+                    "type": "weekday-and-time-of-day",
+                    "restrictions": [{
+                        "startDay": "monday", "startHour": 8, "startMin": 0,
+                        "endDay": "friday", "endHour": 18, "endMin": 0
+                    }]
+                },
+                "notify": {"type": "escalation", "id": "office-escalation", "name": "office"}
+            },
+            {
+                "name": "Nights",
+                "order": 2,
+                "criteria": {"type": "match-all"},
+                "timezone": "Europe/Berlin",
+                "timeRestriction": {
+                    "type": "time-of-day",
+                    "restriction": {"startHour": 22, "startMin": 0, "endHour": 6, "endMin": 0}
+                },
+                "notify": {"type": "none"}
+            }
+        ]))
+        .unwrap()
+    }
+
+    fn target_id(notify: &RoutingNotify) -> Value {
+        match notify {
+            RoutingNotify::Schedule { id, .. } | RoutingNotify::Escalation { id, .. } => {
+                json!(id)
+            }
+            RoutingNotify::None | RoutingNotify::Unsupported => Value::Null,
+        }
+    }
+
+    #[rstest]
+    // Wednesday 10:00 in Berlin
+    #[case("2024-10-02T08:00:00Z", json!("office-escalation"))]
+    // Friday 17:59 in Berlin
+    #[case("2024-10-04T15:59:00Z", json!("office-escalation"))]
+    // Friday 18:00 in Berlin
+    #[case("2024-10-04T16:00:00Z", json!("default-schedule"))]
+    // Saturday 23:00 in Berlin
+    #[case("2024-10-05T21:00:00Z", Value::Null)]
+    // Sunday 05:30 in Berlin
+    #[case("2024-10-06T03:30:00Z", Value::Null)]
+    // Sunday 07:00 in Berlin
+    #[case("2024-10-06T05:00:00Z", json!("default-schedule"))]
+    fn test_select_rule(#[case] now: &str, #[case] expected: Value) {
+        let rules = rules();
+        let now = now.parse::<DateTime<Utc>>().unwrap();
+        let rule = select_rule(&rules, now).unwrap();
+        assert_eq!(target_id(&rule.notify), expected);
+    }
+
+    #[rstest]
+    #[case(10, 5, 20, true)]
+    #[case(20, 5, 20, false)]
+    #[case(5, 5, 20, true)]
+    #[case(2, 20, 5, true)]
+    #[case(22, 20, 5, true)]
+    #[case(10, 20, 5, false)]
+    #[case(10, 5, 5, true)]
+    fn test_in_window(
+        #[case] now: u32,
+        #[case] start: u32,
+        #[case] end: u32,
+        #[case] expected: bool,
+    ) {
+        assert_eq!(in_window(now, start, end), expected);
+    }
+}
@@ -0,0 +1,60 @@
+use crate::config::{Config, SlackConfig};
+use crate::slack::error::SendMessageSnafu;
+use crate::util::send_request;
+use axum::http::header::AUTHORIZATION;
+use axum::http::HeaderMap;
+use reqwest::Client;
+use secrecy::ExposeSecret;
+use serde::Serialize;
+use snafu::{ResultExt, Snafu};
+
+#[derive(Snafu, Debug)]
+#[snafu(module)]
+pub enum Error {
+    #[snafu(display("failed to send message to Slack: \n{source}"))]
+    SendMessage { source: crate::util::Error },
+}
+
+#[derive(Serialize, Debug)]
+struct Message<'a> {
+    text: &'a str,
+}
+
+pub async fn send_message(
+    text: &str,
+    http: &Client,
+    slack_config: &SlackConfig,
+) -> Result<(), Error> {
+    let mut outgoing_headers = HeaderMap::new();
+    outgoing_headers.insert(AUTHORIZATION, slack_config.token.expose_secret().clone().0);
+
+    tracing::debug!(text, "Sending message to Slack");
+    send_request(
+        http.post(slack_config.url.clone())
+            .headers(outgoing_headers)
+            .json(&Message { text }),
+    )
+    .await
+    .context(SendMessageSnafu)?;
+    Ok(())
+}
+
+/// Send a message to Slack in the background if Slack is configured.
+///
+/// Slack messages are informational only, so failures are logged instead of being passed on to
+/// whoever triggered the message.
+pub fn notify(text: String, http: &Client, config: &Config) {
+    let Some(slack_config) = config.slack_config.clone() else {
+        tracing::debug!(text, "Slack is not configured, not sending message");
+        return;
+    };
+    let http = http.clone();
+    tokio::spawn(async move {
+        if let Err(e) = send_message(&text, &http, &slack_config).await {
+            tracing::warn!(
+                error = &e as &dyn std::error::Error,
+                "Failed to notify Slack"
+            );
+        }
+    });
+}
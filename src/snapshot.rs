@@ -0,0 +1,365 @@
+use crate::cache::OpsgenieCache;
+use crate::config::Config;
+use crate::opsgenie::{get_oncall_number, get_schedules, Contact};
+use crate::privacy::{mask_phone_number, MaskPhoneNumbers};
+use crate::reload::SharedConfig;
+use crate::snapshot::error::{
+    NotConfiguredSnafu, ParseSnapshotSnafu, ReadSnapshotSnafu, SerializeSnapshotSnafu,
+    WriteSnapshotSnafu,
+};
+use crate::{http_error, Schedule, ScheduleRequestById, ScheduleRequestByName};
+use axum::http::StatusCode;
+use chrono::{DateTime, Utc};
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use snafu::{OptionExt, ResultExt, Snafu};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::time::{interval, MissedTickBehavior};
+
+#[derive(Snafu, Debug)]
+#[snafu(module)]
+pub enum Error {
+    #[snafu(display("no snapshot file is configured"))]
+    NotConfigured,
+
+    #[snafu(display("failed to read snapshot from [{}]: \n{source}", path.display()))]
+    ReadSnapshot {
+        source: std::io::Error,
+        path: PathBuf,
+    },
+
+    #[snafu(display("failed to parse snapshot from [{}]: \n{source}", path.display()))]
+    ParseSnapshot {
+        source: serde_json::Error,
+        path: PathBuf,
+    },
+
+    #[snafu(display("failed to serialize snapshot: \n{source}"))]
+    SerializeSnapshot { source: serde_json::Error },
+
+    #[snafu(display("failed to write snapshot to [{}]: \n{source}", path.display()))]
+    WriteSnapshot {
+        source: std::io::Error,
+        path: PathBuf,
+    },
+}
+
+impl http_error::Error for Error {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            Error::NotConfigured => StatusCode::NOT_FOUND,
+            Error::ReadSnapshot { source, .. } if source.kind() == ErrorKind::NotFound => {
+                StatusCode::NOT_FOUND
+            }
+            Error::ReadSnapshot { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+            Error::ParseSnapshot { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+            Error::SerializeSnapshot { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+            Error::WriteSnapshot { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+/// Everything in the [`OpsgenieCache`], as it is written to `WYGC_SNAPSHOT_FILE`
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct Snapshot {
+    pub taken_at: DateTime<Utc>,
+    pub on_call: Vec<OnCallSnapshot>,
+    pub contacts: Vec<ContactsSnapshot>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct OnCallSnapshot {
+    pub schedule: Schedule,
+    pub usernames: Vec<String>,
+    pub fetched_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct ContactsSnapshot {
+    pub username: String,
+    pub contacts: Vec<Contact>,
+    pub fetched_at: DateTime<Utc>,
+}
+
+impl Snapshot {
+    pub fn of(cache: &OpsgenieCache, taken_at: DateTime<Utc>) -> Self {
+        let mut on_call = cache
+            .on_call
+            .entries()
+            .into_iter()
+            .map(|(schedule, usernames, fetched_at)| OnCallSnapshot {
+                schedule,
+                usernames,
+                fetched_at,
+            })
+            .collect::<Vec<_>>();
+        let mut contacts = cache
+            .contacts
+            .entries()
+            .into_iter()
+            .map(|(username, contacts, fetched_at)| ContactsSnapshot {
+                username,
+                contacts,
+                fetched_at,
+            })
+            .collect::<Vec<_>>();
+        // Keeps the file diffable, the cache has no order
+        on_call.sort_by(|a, b| a.schedule.identifier().cmp(&b.schedule.identifier()));
+        contacts.sort_by(|a, b| a.username.cmp(&b.username));
+
+        Snapshot {
+            taken_at,
+            on_call,
+            contacts,
+        }
+    }
+
+    /// Put everything into the cache that isn't there in a more recent version already
+    pub fn restore(self, cache: &OpsgenieCache) {
+        for entry in self.on_call {
+            cache
+                .on_call
+                .restore(entry.schedule, entry.usernames, entry.fetched_at);
+        }
+        for entry in self.contacts {
+            cache
+                .contacts
+                .restore(entry.username, entry.contacts, entry.fetched_at);
+        }
+    }
+}
+
+impl MaskPhoneNumbers for Snapshot {
+    fn mask_phone_numbers(&mut self) {
+        for entry in &mut self.contacts {
+            for contact in &mut entry.contacts {
+                if contact.method.is_phone() {
+                    contact.address = mask_phone_number(&contact.address);
+                }
+            }
+        }
+    }
+}
+
+/// Response of `/admin/snapshot`
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SnapshotInfo {
+    pub file: PathBuf,
+    /// Seconds since the snapshot was taken
+    pub age_seconds: i64,
+    pub snapshot: Snapshot,
+}
+
+impl MaskPhoneNumbers for SnapshotInfo {
+    fn mask_phone_numbers(&mut self) {
+        self.snapshot.mask_phone_numbers();
+    }
+}
+
+/// Read the snapshot file as it is on disk, which is what a restarted instance would load
+pub async fn read(config: &Config) -> Result<SnapshotInfo, Error> {
+    let path = config.snapshot_file.as_ref().context(NotConfiguredSnafu)?;
+    let snapshot = load(path).await?;
+    Ok(SnapshotInfo {
+        file: path.clone(),
+        age_seconds: (Utc::now() - snapshot.taken_at).num_seconds(),
+        snapshot,
+    })
+}
+
+/// Load the snapshot file into the cache and keep it up to date with all schedules in OpsGenie.
+///
+/// This runs until the process exits, failures are logged and retried on the next interval. The
+/// interval is only read on startup, the file is read from the current config every time.
+pub async fn run(shared_config: SharedConfig, http: Client, opsgenie_cache: OpsgenieCache) {
+    let startup_config = shared_config.current();
+    if let Some(path) = &startup_config.snapshot_file {
+        match load(path).await {
+            Ok(snapshot) => {
+                tracing::info!(
+                    path = %path.display(),
+                    %snapshot.taken_at,
+                    schedules = snapshot.on_call.len(),
+                    "Loaded snapshot"
+                );
+                snapshot.restore(&opsgenie_cache);
+            }
+            Err(e) => tracing::warn!(
+                error = &e as &dyn std::error::Error,
+                "Failed to load snapshot, starting without it"
+            ),
+        }
+    }
+
+    // A zero interval would make tokio panic
+    let mut ticker = interval(startup_config.snapshot_interval.max(Duration::from_secs(1)));
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        ticker.tick().await;
+        let config = shared_config.current();
+        if let Some(path) = &config.snapshot_file {
+            refresh(&http, &opsgenie_cache, &config).await;
+            let snapshot = Snapshot::of(&opsgenie_cache, Utc::now());
+            match write(path, &snapshot).await {
+                Ok(()) => tracing::debug!(path = %path.display(), "Wrote snapshot"),
+                Err(e) => tracing::error!(
+                    error = &e as &dyn std::error::Error,
+                    "Failed to write snapshot"
+                ),
+            }
+        }
+    }
+}
+
+/// Look up who is on call in every schedule, which puts them into the cache
+async fn refresh(http: &Client, cache: &OpsgenieCache, config: &Config) {
+    let schedules = match get_schedules(http, config).await {
+        Ok(schedules) => schedules,
+        Err(e) => {
+            tracing::warn!(
+                error = &e as &dyn std::error::Error,
+                "Failed to list schedules, the snapshot only contains what is cached"
+            );
+            return;
+        }
+    };
+
+    for schedule in schedules {
+        let by_id = Schedule::ScheduleById(ScheduleRequestById { id: schedule.id });
+        if let Err(e) = get_oncall_number(&by_id, http, cache, config).await {
+            tracing::warn!(
+                error = &e as &dyn std::error::Error,
+                schedule.name,
+                "Failed to look up schedule for snapshot"
+            );
+            continue;
+        }
+        // Lookups can name the schedule either way, both should find it after a restart
+        if let Some((usernames, fetched_at)) = cache.on_call.entry(&by_id) {
+            let by_name = Schedule::ScheduleByName(ScheduleRequestByName {
+                name: schedule.name,
+            });
+            cache.on_call.restore(by_name, usernames, fetched_at);
+        }
+    }
+}
+
+async fn load(path: &Path) -> Result<Snapshot, Error> {
+    let content = tokio::fs::read(path)
+        .await
+        .context(ReadSnapshotSnafu { path })?;
+    serde_json::from_slice(&content).context(ParseSnapshotSnafu { path })
+}
+
+/// Write to a temporary file first, so that a crash halfway through doesn't destroy the last
+/// snapshot
+async fn write(path: &Path, snapshot: &Snapshot) -> Result<(), Error> {
+    let content = serde_json::to_vec_pretty(snapshot).context(SerializeSnapshotSnafu)?;
+    let mut temporary_path = path.as_os_str().to_owned();
+    temporary_path.push(".tmp");
+    let temporary_path = PathBuf::from(temporary_path);
+    write_private(&temporary_path, &content)
+        .await
+        .context(WriteSnapshotSnafu {
+            path: &temporary_path,
+        })?;
+    tokio::fs::rename(&temporary_path, path)
+        .await
+        .context(WriteSnapshotSnafu { path })
+}
+
+/// The snapshot holds phone numbers, so only our own user may read it. A file that is left over
+/// from an earlier attempt may have other permissions, so it is replaced rather than reused.
+async fn write_private(path: &Path, content: &[u8]) -> std::io::Result<()> {
+    match tokio::fs::remove_file(path).await {
+        Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    let mut options = tokio::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let mut file = options.open(path).await?;
+    file.write_all(content).await?;
+    file.flush().await
+}
+
+#[cfg(test)]
+mod test {
+    use super::{write, Snapshot};
+    use crate::cache::OpsgenieCache;
+    use crate::opsgenie::{Contact, ContactMethod, ContactSource};
+    use crate::{Schedule, ScheduleRequestByName};
+    use chrono::{Duration, TimeZone, Utc};
+
+    #[test]
+    fn test_snapshot_roundtrip() {
+        let fetched_at = Utc.with_ymd_and_hms(2024, 10, 1, 12, 0, 0).unwrap();
+        let ops = Schedule::ScheduleByName(ScheduleRequestByName {
+            name: "ops".to_string(),
+        });
+        let contacts = vec![Contact {
+            method: ContactMethod::Voice,
+            address: "+49123456123".to_string(),
+            enabled: true,
+            source: ContactSource::Opsgenie,
+            error: None,
+            quiet: false,
+            send_after_seconds: 0,
+        }];
+        let cache = OpsgenieCache::default();
+        cache
+            .on_call
+            .restore(ops.clone(), vec!["jane".to_string()], fetched_at);
+        cache
+            .contacts
+            .restore("jane".to_string(), contacts.clone(), fetched_at);
+
+        let snapshot = Snapshot::of(&cache, fetched_at + Duration::minutes(5));
+        let parsed: Snapshot =
+            serde_json::from_str(&serde_json::to_string(&snapshot).unwrap()).unwrap();
+        assert_eq!(parsed, snapshot);
+
+        // A fresher lookup isn't replaced by an older snapshot
+        let restored = OpsgenieCache::default();
+        restored.on_call.restore(
+            ops.clone(),
+            vec!["john".to_string()],
+            fetched_at + Duration::minutes(1),
+        );
+        parsed.restore(&restored);
+        assert_eq!(
+            restored.on_call.entry(&ops),
+            Some((vec!["john".to_string()], fetched_at + Duration::minutes(1)))
+        );
+        assert_eq!(
+            restored.contacts.entry(&"jane".to_string()),
+            Some((contacts, fetched_at))
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_write_is_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let directory = std::env::temp_dir().join(format!("wygc-snapshot-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let path = directory.join("snapshot.json");
+        // Left over with the default permissions
+        std::fs::write(directory.join("snapshot.json.tmp"), "{}").unwrap();
+
+        let snapshot = Snapshot::of(&OpsgenieCache::default(), Utc::now());
+        write(&path, &snapshot).await.unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        std::fs::remove_dir_all(&directory).unwrap();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+}
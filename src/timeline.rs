@@ -0,0 +1,537 @@
+use crate::config::Config;
+use crate::opsgenie::{get_schedule_names, get_timeline, TimelinePeriod};
+use crate::timeline::error::{
+    InvalidRangeSnafu, RangeTooLongSnafu, RequestScheduleNamesSnafu, RequestTimelineSnafu,
+};
+use crate::{http_error, opsgenie, Schedule, ScheduleRequestByName};
+use axum::http::StatusCode;
+use chrono::{DateTime, Duration, Utc};
+use futures::future::try_join_all;
+use reqwest::Client;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use snafu::{ensure, ResultExt, Snafu};
+use std::collections::BTreeMap;
+
+/// Range of a timeline when no end is requested
+static DEFAULT_TIMELINE_DAYS: i64 = 28;
+
+/// Longest range a timeline can be requested for, so a single request can't make us page through
+/// years of OpsGenie history
+static MAX_TIMELINE_DAYS: i64 = 92;
+
+static SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+
+/// iCalendar lines longer than this many bytes have to be folded
+static ICAL_MAX_LINE_BYTES: usize = 75;
+
+static ICAL_PRODUCT_ID: &str = "-//Stackable//whoyougonnacall//EN";
+
+#[derive(Snafu, Debug)]
+#[snafu(module)]
+pub enum Error {
+    #[snafu(display("the timeline has to end after it starts, got {from} to {to}"))]
+    InvalidRange {
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    },
+
+    #[snafu(display("a timeline can cover at most {max_days} days"))]
+    RangeTooLong { max_days: i64 },
+
+    #[snafu(display("failed to look up the timeline of schedule {schedule}: \n{source}"))]
+    RequestTimeline {
+        #[snafu(source(from(opsgenie::Error, Box::new)))]
+        source: Box<opsgenie::Error>,
+        schedule: Schedule,
+    },
+
+    #[snafu(display("failed to look up the schedules: \n{source}"))]
+    RequestScheduleNames {
+        #[snafu(source(from(opsgenie::Error, Box::new)))]
+        source: Box<opsgenie::Error>,
+    },
+}
+
+impl http_error::Error for Error {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            Error::InvalidRange { .. } => StatusCode::BAD_REQUEST,
+            Error::RangeTooLong { .. } => StatusCode::BAD_REQUEST,
+            Error::RequestTimeline { source, .. } => source.status_code(),
+            Error::RequestScheduleNames { source } => source.status_code(),
+        }
+    }
+}
+
+/// Which shift of a schedule a lookup is about
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Shift {
+    /// Whoever is on call right now
+    Current,
+    /// Whoever is on call at the given time
+    At(DateTime<Utc>),
+    /// Whoever takes over after the shift that is active at the given time, or right now
+    Next(Option<DateTime<Utc>>),
+}
+
+impl Shift {
+    /// The point in time the shift is looked up for, `None` means now
+    pub fn date(&self) -> Option<DateTime<Utc>> {
+        match self {
+            Shift::Current => None,
+            Shift::At(date) => Some(*date),
+            Shift::Next(after) => *after,
+        }
+    }
+}
+
+/// Start and end of a single shift of one person
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ShiftTimes {
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+}
+
+/// Find the shift of a person in a schedule timeline.
+///
+/// OpsGenie splits shifts into several periods where rotations or restrictions meet, periods of
+/// the same person that follow each other directly are merged into one shift. For
+/// [`Shift::Next`] this is the first shift of the person that starts at or after the given time,
+/// otherwise it is the shift that covers it.
+pub fn find_shift(
+    periods: &[TimelinePeriod],
+    username: &str,
+    shift: Shift,
+    now: DateTime<Utc>,
+) -> Option<ShiftTimes> {
+    let date = shift.date().unwrap_or(now);
+    let merged = merge_adjacent(
+        periods
+            .iter()
+            .filter(|period| period.username() == Some(username))
+            .map(ShiftTimes::from)
+            .collect(),
+    );
+
+    match shift {
+        Shift::Current | Shift::At(_) => merged
+            .into_iter()
+            .find(|times| times.start <= date && date < times.end),
+        Shift::Next(_) => merged.into_iter().find(|times| times.start >= date),
+    }
+}
+
+impl From<&TimelinePeriod> for ShiftTimes {
+    fn from(period: &TimelinePeriod) -> Self {
+        ShiftTimes {
+            start: period.start_date,
+            end: period.end_date,
+        }
+    }
+}
+
+/// Sort shifts and join those that overlap or follow each other directly
+fn merge_adjacent(mut times: Vec<ShiftTimes>) -> Vec<ShiftTimes> {
+    times.sort_by_key(|times| times.start);
+
+    let mut merged: Vec<ShiftTimes> = Vec::new();
+    for period in times {
+        match merged.last_mut() {
+            Some(last) if period.start <= last.end => last.end = last.end.max(period.end),
+            _ => merged.push(period),
+        }
+    }
+    merged
+}
+
+/// Who is on call in which schedule over a range of time
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ScheduleTimeline {
+    pub from: DateTime<Utc>,
+    pub to: DateTime<Utc>,
+    /// Ordered by start
+    pub shifts: Vec<TimelineShift>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TimelineShift {
+    /// Name or id of the schedule, whichever it was requested by
+    pub schedule: String,
+    pub user: String,
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    /// Whether the shift comes from an override rather than the regular rotation
+    #[serde(rename = "override")]
+    pub is_override: bool,
+}
+
+/// The requested range, defaulting to the next [`DEFAULT_TIMELINE_DAYS`] days
+pub fn timeline_range(
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+    now: DateTime<Utc>,
+) -> Result<(DateTime<Utc>, DateTime<Utc>), Error> {
+    let from = from.unwrap_or(now);
+    let to = to.unwrap_or(from + Duration::days(DEFAULT_TIMELINE_DAYS));
+    ensure!(from < to, InvalidRangeSnafu { from, to });
+    ensure!(
+        to - from <= Duration::days(MAX_TIMELINE_DAYS),
+        RangeTooLongSnafu {
+            max_days: MAX_TIMELINE_DAYS
+        }
+    );
+    Ok((from, to))
+}
+
+/// Look up the shifts of a schedule, optionally only those of one user
+pub async fn get_schedule_timeline(
+    schedule: &Schedule,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+    user: Option<&str>,
+    http: &Client,
+    config: &Config,
+) -> Result<ScheduleTimeline, Error> {
+    let mut shifts = get_shifts(schedule, from, to, http, config).await?;
+    if let Some(user) = user {
+        shifts.retain(|shift| shift.user == user);
+    }
+    Ok(ScheduleTimeline { from, to, shifts })
+}
+
+/// Look up the shifts of a user in all schedules that are visible with the configured credentials
+pub async fn get_user_timeline(
+    user: &str,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+    http: &Client,
+    config: &Config,
+) -> Result<ScheduleTimeline, Error> {
+    let schedules = get_schedule_names(http, config)
+        .await
+        .context(RequestScheduleNamesSnafu)?
+        .into_iter()
+        .map(|name| Schedule::ScheduleByName(ScheduleRequestByName { name }))
+        .collect::<Vec<_>>();
+    let mut shifts = try_join_all(
+        schedules
+            .iter()
+            .map(|schedule| get_shifts(schedule, from, to, http, config)),
+    )
+    .await?
+    .into_iter()
+    .flatten()
+    .filter(|shift| shift.user == user)
+    .collect::<Vec<_>>();
+    shifts.sort_by_key(|shift| shift.start);
+    Ok(ScheduleTimeline { from, to, shifts })
+}
+
+async fn get_shifts(
+    schedule: &Schedule,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+    http: &Client,
+    config: &Config,
+) -> Result<Vec<TimelineShift>, Error> {
+    // OpsGenie only takes whole days, the extra part of the last day is cut off again below
+    let days = ((to - from).num_seconds() + SECONDS_PER_DAY - 1) / SECONDS_PER_DAY;
+    let periods = get_timeline(schedule, from, days as u32, http, config)
+        .await
+        .context(RequestTimelineSnafu {
+            schedule: schedule.clone(),
+        })?;
+    Ok(shifts_from_periods(
+        schedule.identifier().0,
+        &periods,
+        from,
+        to,
+    ))
+}
+
+/// Merge the periods of each user into shifts and cut them off at the edges of the range
+fn shifts_from_periods(
+    schedule: &str,
+    periods: &[TimelinePeriod],
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+) -> Vec<TimelineShift> {
+    let mut by_user: BTreeMap<(&str, bool), Vec<ShiftTimes>> = BTreeMap::new();
+    for period in periods {
+        if let Some(user) = period.username() {
+            by_user
+                .entry((user, period.is_override()))
+                .or_default()
+                .push(ShiftTimes::from(period));
+        }
+    }
+
+    let mut shifts = by_user
+        .into_iter()
+        .flat_map(|((user, is_override), times)| {
+            merge_adjacent(times)
+                .into_iter()
+                .filter(|times| times.start < to && times.end > from)
+                .map(move |times| TimelineShift {
+                    schedule: schedule.to_string(),
+                    user: user.to_string(),
+                    start: times.start.max(from),
+                    end: times.end.min(to),
+                    is_override,
+                })
+        })
+        .collect::<Vec<_>>();
+    shifts.sort_by(|a, b| (a.start, &a.user).cmp(&(b.start, &b.user)));
+    shifts
+}
+
+/// Render a timeline as an iCalendar feed with one event per shift
+pub fn to_icalendar(
+    timeline: &ScheduleTimeline,
+    calendar_name: &str,
+    now: DateTime<Utc>,
+) -> String {
+    let mut lines = vec![
+        "BEGIN:VCALENDAR".to_string(),
+        "VERSION:2.0".to_string(),
+        format!("PRODID:{ICAL_PRODUCT_ID}"),
+        "CALSCALE:GREGORIAN".to_string(),
+        "METHOD:PUBLISH".to_string(),
+        format!("X-WR-CALNAME:{}", escape_ical_text(calendar_name)),
+    ];
+    for shift in &timeline.shifts {
+        let kind = if shift.is_override {
+            "override"
+        } else {
+            "on call"
+        };
+        lines.extend([
+            "BEGIN:VEVENT".to_string(),
+            format!("UID:{}", ical_uid(shift)),
+            format!("DTSTAMP:{}", ical_timestamp(now)),
+            format!("DTSTART:{}", ical_timestamp(shift.start)),
+            format!("DTEND:{}", ical_timestamp(shift.end)),
+            format!(
+                "SUMMARY:{}",
+                escape_ical_text(&format!("{} {kind} for {}", shift.user, shift.schedule))
+            ),
+            "TRANSP:TRANSPARENT".to_string(),
+            "END:VEVENT".to_string(),
+        ]);
+    }
+    lines.push("END:VCALENDAR".to_string());
+
+    lines
+        .iter()
+        .map(|line| fold_ical_line(line))
+        .collect::<Vec<_>>()
+        .join("")
+}
+
+/// Stable across requests, so calendar apps update events instead of duplicating them
+fn ical_uid(shift: &TimelineShift) -> String {
+    let hash = Sha256::digest(format!(
+        "{}\n{}\n{}",
+        shift.schedule,
+        shift.user,
+        shift.start.to_rfc3339()
+    ));
+    format!("{}@whoyougonnacall", hex::encode(&hash[..16]))
+}
+
+fn ical_timestamp(timestamp: DateTime<Utc>) -> String {
+    timestamp.format("%Y%m%dT%H%M%SZ").to_string()
+}
+
+fn escape_ical_text(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace(';', "\\;")
+        .replace(',', "\\,")
+        .replace('\n', "\\n")
+}
+
+/// Terminate a content line, splitting it into continuation lines if it is too long
+fn fold_ical_line(line: &str) -> String {
+    let mut folded = String::new();
+    let mut line_bytes = 0;
+    for c in line.chars() {
+        if line_bytes + c.len_utf8() > ICAL_MAX_LINE_BYTES {
+            folded.push_str("\r\n ");
+            // The leading space counts towards the length of the continuation line
+            line_bytes = 1;
+        }
+        folded.push(c);
+        line_bytes += c.len_utf8();
+    }
+    folded.push_str("\r\n");
+    folded
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        find_shift, fold_ical_line, shifts_from_periods, timeline_range, to_icalendar,
+        ScheduleTimeline, Shift, ShiftTimes,
+    };
+    use crate::opsgenie::TimelinePeriod;
+    use chrono::{DateTime, Utc};
+    use rstest::rstest;
+    use serde_json::json;
+
+    fn periods() -> Vec<TimelinePeriod> {
+        serde_json::from_value(json!([
+            {
+                "startDate": "2024-10-07T08:00:00Z",
+                "endDate": "2024-10-08T00:00:00Z",
+                "type": "default",
+                "recipient": {"type": "user", "name": "jane@example.com"}
+            },
+            {
+                "startDate": "2024-10-08T00:00:00Z",
+                "endDate": "2024-10-14T08:00:00Z",
+                "type": "default",
+                "recipient": {"type": "user", "name": "jane@example.com"}
+            },
+            {
+                "startDate": "2024-10-14T08:00:00Z",
+                "endDate": "2024-10-21T08:00:00Z",
+                "type": "default",
+                "recipient": {"type": "user", "name": "john@example.com"}
+            },
+            {
+                "startDate": "2024-10-21T08:00:00Z",
+                "endDate": "2024-10-28T08:00:00Z",
+                "type": "default",
+                "recipient": {"type": "none"}
+            }
+        ]))
+        .unwrap()
+    }
+
+    fn date(date: &str) -> DateTime<Utc> {
+        date.parse().unwrap()
+    }
+
+    #[rstest]
+    #[case("jane@example.com", Shift::At(date("2024-10-09T12:00:00Z")), Some(("2024-10-07T08:00:00Z", "2024-10-14T08:00:00Z")))]
+    #[case("jane@example.com", Shift::At(date("2024-10-14T08:00:00Z")), None)]
+    #[case("john@example.com", Shift::At(date("2024-10-14T08:00:00Z")), Some(("2024-10-14T08:00:00Z", "2024-10-21T08:00:00Z")))]
+    #[case("john@example.com", Shift::Current, Some(("2024-10-14T08:00:00Z", "2024-10-21T08:00:00Z")))]
+    #[case("john@example.com", Shift::Next(None), None)]
+    #[case("john@example.com", Shift::Next(Some(date("2024-10-09T12:00:00Z"))), Some(("2024-10-14T08:00:00Z", "2024-10-21T08:00:00Z")))]
+    fn test_find_shift(
+        #[case] username: &str,
+        #[case] shift: Shift,
+        #[case] expected: Option<(&str, &str)>,
+    ) {
+        let now = date("2024-10-16T12:00:00Z");
+        let expected = expected.map(|(start, end)| ShiftTimes {
+            start: date(start),
+            end: date(end),
+        });
+        assert_eq!(find_shift(&periods(), username, shift, now), expected);
+    }
+
+    #[test]
+    fn test_shifts_from_periods() {
+        let mut periods = periods();
+        periods.push(
+            serde_json::from_value(json!({
+                "startDate": "2024-10-10T18:00:00Z",
+                "endDate": "2024-10-11T06:00:00Z",
+                "type": "override",
+                "recipient": {"type": "user", "name": "john@example.com"}
+            }))
+            .unwrap(),
+        );
+
+        let shifts = shifts_from_periods(
+            "ops",
+            &periods,
+            date("2024-10-09T00:00:00Z"),
+            date("2024-10-16T00:00:00Z"),
+        );
+        let shifts = shifts
+            .iter()
+            .map(|shift| {
+                (
+                    shift.user.as_str(),
+                    shift.start.to_rfc3339(),
+                    shift.end.to_rfc3339(),
+                    shift.is_override,
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            shifts,
+            [
+                (
+                    "jane@example.com",
+                    "2024-10-09T00:00:00+00:00".to_string(),
+                    "2024-10-14T08:00:00+00:00".to_string(),
+                    false
+                ),
+                (
+                    "john@example.com",
+                    "2024-10-10T18:00:00+00:00".to_string(),
+                    "2024-10-11T06:00:00+00:00".to_string(),
+                    true
+                ),
+                (
+                    "john@example.com",
+                    "2024-10-14T08:00:00+00:00".to_string(),
+                    "2024-10-16T00:00:00+00:00".to_string(),
+                    false
+                ),
+            ]
+        );
+    }
+
+    #[rstest]
+    #[case(None, None, Some(("2024-10-16T12:00:00Z", "2024-11-13T12:00:00Z")))]
+    #[case(Some("2024-10-01T00:00:00Z"), Some("2024-12-31T00:00:00Z"), Some(("2024-10-01T00:00:00Z", "2024-12-31T00:00:00Z")))]
+    #[case(Some("2024-10-01T00:00:00Z"), Some("2025-01-02T00:00:00Z"), None)]
+    #[case(Some("2024-10-01T00:00:00Z"), Some("2024-10-01T00:00:00Z"), None)]
+    fn test_timeline_range(
+        #[case] from: Option<&str>,
+        #[case] to: Option<&str>,
+        #[case] expected: Option<(&str, &str)>,
+    ) {
+        let now = date("2024-10-16T12:00:00Z");
+        let range = timeline_range(from.map(date), to.map(date), now).ok();
+        assert_eq!(range, expected.map(|(from, to)| (date(from), date(to))));
+    }
+
+    #[test]
+    fn test_icalendar() {
+        let from = date("2024-10-14T00:00:00Z");
+        let to = date("2024-10-21T00:00:00Z");
+        let timeline = ScheduleTimeline {
+            from,
+            to,
+            shifts: shifts_from_periods("ops", &periods(), from, to),
+        };
+        let calendar = to_icalendar(&timeline, "On call, ops", date("2024-10-16T12:00:00Z"));
+
+        assert!(calendar.starts_with("BEGIN:VCALENDAR\r\nVERSION:2.0\r\n"));
+        assert!(calendar.ends_with("END:VCALENDAR\r\n"));
+        assert!(calendar.contains("X-WR-CALNAME:On call\\, ops\r\n"));
+        assert_eq!(calendar.matches("BEGIN:VEVENT").count(), 2);
+        assert!(calendar.contains(
+            "DTSTART:20241014T080000Z\r\nDTEND:20241021T000000Z\r\nSUMMARY:john@example.com on call for ops\r\n"
+        ));
+    }
+
+    #[test]
+    fn test_fold_ical_line() {
+        let line = format!("SUMMARY:{}", "ä".repeat(40));
+        let folded = fold_ical_line(&line);
+        assert!(folded
+            .split("\r\n")
+            .all(|folded_line| folded_line.len() <= 75));
+        assert_eq!(folded.replace("\r\n ", ""), format!("{line}\r\n"));
+    }
+}
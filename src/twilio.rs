@@ -1,5 +1,6 @@
 use crate::config::{Config, TwilioConfig};
-use crate::twilio::error::BuildUrlSnafu;
+use crate::privacy::loggable_numbers;
+use crate::twilio::error::{BuildUrlSnafu, RequestWorkflowSnafu};
 use crate::util::send_json_request;
 use crate::{http_error, AlertInfo};
 use axum::http::header::AUTHORIZATION;
@@ -11,10 +12,8 @@ use serde::{Deserialize, Serialize};
 use snafu::{ResultExt, Snafu};
 use std::collections::HashMap;
 use tracing::instrument;
-use url::{ParseError, Url};
 use urlencoding::encode;
 
-static TWILIO_BASEURL: &str = "https://studio.twilio.com/v2/Flows/";
 #[derive(Snafu, Debug)]
 #[snafu(module)]
 pub(crate) enum Error {
@@ -22,6 +21,8 @@ pub(crate) enum Error {
     RunWorkflow { source: crate::util::Error },
     #[snafu(display("Error creating url for Twilio workflow"))]
     BuildUrl { source: url::ParseError },
+    #[snafu(display("Twilio reported error when retrieving the workflow"))]
+    RequestWorkflow { source: crate::util::Error },
 }
 
 #[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq, Hash)]
@@ -35,13 +36,48 @@ impl http_error::Error for crate::twilio::Error {
         match self {
             Error::RunWorkflow { .. } => StatusCode::INTERNAL_SERVER_ERROR,
             Error::BuildUrl { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+            Error::RequestWorkflow { .. } => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
 }
 
-#[instrument(name = "dial_outgoing")]
+// Twilio uses snake_case in its responses
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq, Hash)]
+pub struct TwilioWorkflow {
+    pub friendly_name: String,
+    pub status: String,
+}
+
+/// Retrieve the configured Studio workflow, this only succeeds if the configured credentials are
+/// valid and the workflow exists
+#[instrument(name = "get_workflow")]
+pub async fn get_workflow(
+    http: &Client,
+    config: &Config,
+) -> Result<TwilioWorkflow, crate::twilio::Error> {
+    let twilio_config = &config.twilio_config;
+    let url = twilio_config
+        .base_url
+        .join(&twilio_config.workflow_id)
+        .context(BuildUrlSnafu)?;
+    tracing::debug!(?url, "Retrieving Twilio workflow");
+
+    let mut outgoing_headers = HeaderMap::new();
+    outgoing_headers.insert(
+        AUTHORIZATION,
+        twilio_config.credentials.expose_secret().clone().0,
+    );
+
+    send_json_request::<TwilioWorkflow>(http.get(url).headers(outgoing_headers))
+        .await
+        .context(RequestWorkflowSnafu)
+}
+
+// The numbers are skipped here because they may need to be masked, they are logged below
+#[instrument(name = "dial_outgoing", skip(numbers))]
 pub async fn alert(
-    numbers: &Vec<String>,
+    numbers: &[String],
+    message: Option<&str>,
     http: &Client,
     config: &Config,
 ) -> Result<AlertResult, crate::twilio::Error> {
@@ -64,8 +100,16 @@ pub async fn alert(
     // .. no we won't, we are parallelizing here, so we clone
     let mut params = HashMap::new();
     params.insert("From", twilio_config.outgoing_number.clone());
+    // Additional data is passed to the workflow as a JSON object in "Parameters", the message is
+    // available as `{{flow.data.message}}` in the Studio flow
+    if let Some(message) = message {
+        params.insert(
+            "Parameters",
+            serde_json::json!({ "message": message }).to_string(),
+        );
+    }
     tracing::info!(
-        ?numbers,
+        numbers = ?loggable_numbers(config, numbers),
         ?url_builder,
         ?params,
         twilio_config.outgoing_number,
@@ -125,10 +169,6 @@ pub async fn alert(
     Ok(response)
 }
 
-pub fn get_base_url() -> Result<Url, url::ParseError> {
-    Url::parse(crate::twilio::TWILIO_BASEURL)
-}
-
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Hash, Clone)]
 #[serde(rename_all = "camelCase")]
 pub enum OverallResult {
@@ -185,8 +225,12 @@ impl AlertResult {
 
 #[cfg(test)]
 mod test {
-    use super::{AlertResult, DialNumberResult, OverallResult};
+    use super::{alert, AlertResult, DialNumberResult, OverallResult};
+    use crate::fake_api::FakeApi;
+    use axum::http::Method;
+    use reqwest::Client;
     use rstest::rstest;
+    use serde_json::Value;
     use stackable_operator::cluster_resources::ClusterResourceApplyStrategy::Default;
 
     #[rstest]
@@ -229,4 +273,36 @@ mod test {
         result.update_overall_result();
         assert_eq!(result.overall_result, expected);
     }
+
+    #[rstest]
+    #[case(None, None)]
+    #[case(Some("db01 is down"), Some(r#"{"message":"db01 is down"}"#))]
+    #[tokio::test]
+    async fn test_message_is_passed_to_flow(
+        #[case] message: Option<&str>,
+        #[case] expected: Option<&str>,
+    ) {
+        let api = FakeApi::start().await;
+        api.accept_calls();
+
+        alert(
+            &["+4915112345678".to_string()],
+            message,
+            &Client::new(),
+            &api.config(),
+        )
+        .await
+        .unwrap();
+        let requests = api.requests(
+            Method::POST,
+            "/Flows/FW0123456789abcdef0123456789abcdef/Executions/",
+        );
+        let parameters = url::form_urlencoded::parse(requests[0].body.as_bytes())
+            .find(|(key, _)| key == "Parameters")
+            .map(|(_, parameters)| serde_json::from_str::<Value>(&parameters).unwrap());
+        assert_eq!(
+            parameters,
+            expected.map(|expected| serde_json::from_str::<Value>(expected).unwrap())
+        );
+    }
 }
@@ -1,7 +1,19 @@
 use hyper::StatusCode;
+use reqwest::header::HeaderMap;
 use reqwest::{RequestBuilder, Response};
 use serde::de::DeserializeOwned;
 use snafu::{ResultExt, Snafu};
+use std::time::Duration;
+
+/// How often a request that was rejected with `429 Too Many Requests` is retried
+static MAX_RATE_LIMIT_RETRIES: u32 = 3;
+/// Doubled for every retry, unless the response says how long to wait
+static INITIAL_RATE_LIMIT_WAIT: Duration = Duration::from_secs(1);
+/// Someone may be waiting to be called, so we never wait for long
+static MAX_RATE_LIMIT_WAIT: Duration = Duration::from_secs(10);
+static RETRY_AFTER_HEADER: &str = "retry-after";
+/// OpsGenie doesn't send `Retry-After`, but the length of the period it throttles for
+static RATE_LIMIT_PERIOD_HEADER: &str = "x-ratelimit-period-in-sec";
 
 #[derive(Snafu, Debug)]
 pub enum Error {
@@ -26,17 +38,97 @@ pub enum Error {
     },
 }
 
+impl Error {
+    /// Whether the request may work when it is repeated later: the server couldn't be reached,
+    /// had an error of its own or kept throttling us
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Error::HttpRequest { .. } => true,
+            Error::ParseJson { .. } => false,
+            Error::HttpErrorResponse { status, .. }
+            | Error::HttpErrorResponseUndecodableText { status, .. } => {
+                status.is_server_error() || *status == StatusCode::TOO_MANY_REQUESTS
+            }
+        }
+    }
+}
+
 pub async fn send_json_request<T: DeserializeOwned>(req: RequestBuilder) -> Result<T, Error> {
-    // make the request
+    // make the request and check for client or server errors
+    let non_error_response = send_request(req).await?;
+    // parse the result
+    let result = non_error_response.json().await.context(ParseJsonSnafu)?;
+    Ok(result)
+}
+
+/// Like [`send_json_request`], for endpoints whose response body we don't care about
+pub async fn send_request(req: RequestBuilder) -> Result<Response, Error> {
     let response = req.send().await.context(HttpRequestSnafu)?;
     tracing::trace!("got response from server: [{:?}]", response);
-    // check for client or server errors
-    let non_error_response = error_for_status(response).await?;
-    // parse the result
+    error_for_status(response).await
+}
+
+/// Like [`send_json_request`], but see [`send_request_with_retries`]
+pub async fn send_json_request_with_retries<T: DeserializeOwned>(
+    req: RequestBuilder,
+) -> Result<T, Error> {
+    let non_error_response = send_request_with_retries(req).await?;
     let result = non_error_response.json().await.context(ParseJsonSnafu)?;
     Ok(result)
 }
 
+/// Like [`send_request`], but requests that are rate limited are retried a few times with backoff
+/// before the `429` is returned as error.
+///
+/// Only meant for OpsGenie, which throttles bursts of lookups. Nothing else is waited for, e.g. a
+/// throttled call is better reported right away.
+pub async fn send_request_with_retries(mut req: RequestBuilder) -> Result<Response, Error> {
+    let mut attempt = 0;
+    loop {
+        // Requests with a streaming body can't be repeated, they are never retried
+        let retry = req.try_clone();
+        let response = req.send().await.context(HttpRequestSnafu)?;
+        tracing::trace!("got response from server: [{:?}]", response);
+        match retry {
+            Some(retry)
+                if response.status() == StatusCode::TOO_MANY_REQUESTS
+                    && attempt < MAX_RATE_LIMIT_RETRIES =>
+            {
+                let wait = rate_limit_wait(response.headers(), attempt);
+                tracing::warn!(
+                    url = %response.url(),
+                    attempt,
+                    wait_seconds = wait.as_secs(),
+                    "Request was rate limited, retrying"
+                );
+                tokio::time::sleep(wait).await;
+                req = retry;
+                attempt += 1;
+            }
+            _ => return error_for_status(response).await,
+        }
+    }
+}
+
+/// How long to wait before retrying a rate limited request, as announced by the response or
+/// with exponential backoff
+fn rate_limit_wait(headers: &HeaderMap, attempt: u32) -> Duration {
+    [RETRY_AFTER_HEADER, RATE_LIMIT_PERIOD_HEADER]
+        .iter()
+        .find_map(|name| {
+            headers
+                .get(*name)?
+                .to_str()
+                .ok()?
+                .trim()
+                .parse::<u64>()
+                .ok()
+        })
+        .map(Duration::from_secs)
+        .unwrap_or(INITIAL_RATE_LIMIT_WAIT * 2u32.pow(attempt))
+        .min(MAX_RATE_LIMIT_WAIT)
+}
+
 /// Wraps a Response into a Result. If there is an HTTP Client or Server error,
 /// extract the HTTP body (if possible) to be used as context in the returned Err.
 /// This is done this because the `Response::error_for_status()` method Err variant
@@ -62,3 +154,68 @@ async fn error_for_status(response: Response) -> Result<Response, Error> {
     }
     Ok(response)
 }
+
+#[cfg(test)]
+mod test {
+    use super::{rate_limit_wait, send_request, send_request_with_retries, Error};
+    use crate::fake_api::FakeApi;
+    use reqwest::header::{HeaderMap, HeaderValue};
+    use reqwest::{Method, StatusCode};
+    use rstest::rstest;
+    use serde_json::json;
+    use std::time::Duration;
+
+    #[rstest]
+    #[case(&[], 0, 1)]
+    #[case(&[], 2, 4)]
+    #[case(&[], 5, 10)]
+    #[case(&[("retry-after", "3")], 0, 3)]
+    #[case(&[("x-ratelimit-period-in-sec", "5")], 2, 5)]
+    #[case(&[("x-ratelimit-period-in-sec", "60")], 0, 10)]
+    #[case(&[("retry-after", "Wed, 21 Oct 2015 07:28:00 GMT")], 1, 2)]
+    fn test_rate_limit_wait(
+        #[case] headers: &[(&'static str, &'static str)],
+        #[case] attempt: u32,
+        #[case] expected_seconds: u64,
+    ) {
+        let headers = headers
+            .iter()
+            .map(|(name, value)| (name.parse().unwrap(), HeaderValue::from_static(value)))
+            .collect::<HeaderMap>();
+        assert_eq!(
+            rate_limit_wait(&headers, attempt),
+            Duration::from_secs(expected_seconds)
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_only_retried_when_asked_to() {
+        let api = FakeApi::start().await;
+        let url = api.base_url.join("throttled").unwrap();
+        api.respond(Method::GET, "/throttled", StatusCode::OK, json!({}));
+        let http = reqwest::Client::new();
+        let throttle = || {
+            api.respond_once(
+                Method::GET,
+                "/throttled",
+                StatusCode::TOO_MANY_REQUESTS,
+                json!({"message": "slow down"}),
+            )
+        };
+
+        throttle();
+        let result = send_request(http.get(url.clone())).await;
+        assert!(matches!(
+            result,
+            Err(Error::HttpErrorResponse {
+                status: StatusCode::TOO_MANY_REQUESTS,
+                ..
+            })
+        ));
+        assert_eq!(api.requests(Method::GET, "/throttled").len(), 1);
+
+        throttle();
+        send_request_with_retries(http.get(url)).await.unwrap();
+        assert_eq!(api.requests(Method::GET, "/throttled").len(), 3);
+    }
+}
@@ -0,0 +1,658 @@
+use crate::audit::{AuditAction, AuditEvent};
+use crate::auth::{Caller, Scope};
+use crate::config::Config;
+use crate::escalation::EscalationStatus;
+use crate::opsgenie::{AlertPriority, AlertUpdate, ALERT_SOURCE};
+use crate::privacy::{should_mask, MaskPhoneNumbers};
+use crate::{
+    escalation_keys, request_error, start_escalation, trigger_alert, AlertParameters,
+    AlertResponse, AlertTarget, AppState, Escalation, EscalationParameters, EscalationRequestById,
+    OnCallRequest, OpsgenieAlert, RequestError, Schedule, ScheduleRequestById, Team,
+    TeamRequestById,
+};
+use axum::extract::ConnectInfo;
+use axum::http::HeaderMap;
+use serde::{Deserialize, Serialize};
+use snafu::ResultExt;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Display, Formatter};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// How long a called responder is remembered for alerts that are never acknowledged or closed
+static CALLED_RESPONDER_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Payload of the OpsGenie webhook integration, only the fields that we act on
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct WebhookPayload {
+    /// What happened to the alert, e.g. `Create`, `Acknowledge` or `AddNote`
+    pub action: String,
+    pub alert: WebhookAlert,
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct WebhookAlert {
+    pub alert_id: String,
+    #[serde(default)]
+    pub alias: String,
+    /// Passed on to the Twilio workflow
+    pub message: Option<String>,
+    pub priority: Option<AlertPriority>,
+    /// [`ALERT_SOURCE`] for alerts that we created ourselves
+    pub source: Option<String>,
+    #[serde(default)]
+    pub responders: Vec<WebhookResponder>,
+}
+
+impl WebhookAlert {
+    /// Calls are tracked under the alias of the alert, just like for alerts that we create
+    fn alias(&self) -> &str {
+        if self.alias.is_empty() {
+            &self.alert_id
+        } else {
+            &self.alias
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct WebhookResponder {
+    pub id: String,
+    /// `schedule`, `team`, `escalation` or `user`
+    #[serde(rename = "type")]
+    pub responder_type: String,
+    pub name: Option<String>,
+}
+
+impl WebhookResponder {
+    /// `None` for responders that can't be called, like single users
+    fn target(&self) -> Option<ResponderTarget> {
+        let id = self.id.clone();
+        match self.responder_type.as_str() {
+            "schedule" => Some(ResponderTarget::OnCall(OnCallRequest::Schedule(
+                Schedule::ScheduleById(ScheduleRequestById { id }),
+            ))),
+            "team" => Some(ResponderTarget::OnCall(OnCallRequest::Team(
+                Team::TeamById(TeamRequestById { team_id: id }),
+            ))),
+            "escalation" => Some(ResponderTarget::Escalation(Escalation::EscalationById(
+                EscalationRequestById { id },
+            ))),
+            _ => None,
+        }
+    }
+}
+
+impl Display for WebhookResponder {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {}",
+            self.responder_type,
+            self.name.as_deref().unwrap_or(&self.id)
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum ResponderTarget {
+    OnCall(OnCallRequest),
+    Escalation(Escalation),
+}
+
+/// What an action that OpsGenie sent makes us do
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum WebhookAction {
+    /// Call the responders of the alert
+    Call(AlertPriority),
+    /// Stop the escalation and delayed calls for the alert
+    Stop(AlertUpdate),
+    Ignore(String),
+}
+
+/// Response of `/opsgenie/webhook`
+#[derive(Debug, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct WebhookResult {
+    pub action: String,
+    pub opsgenie_alias: String,
+    /// Why nothing was done
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ignored: Option<String>,
+    /// Outcome of calling each responder of the alert
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub responders: Vec<ResponderResult>,
+    /// The escalations for the alert, which were stopped
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub escalations: Vec<EscalationStatus>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub delayed_calls_cancelled: bool,
+}
+
+#[derive(Debug, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ResponderResult {
+    /// Type and name of the responder
+    pub responder: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub alert: Option<AlertResponse>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub escalation: Option<EscalationStatus>,
+    /// Why the responder wasn't called
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// The responder was called for the same action on the alert before, e.g. because OpsGenie
+    /// delivered the webhook again
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub already_called: bool,
+}
+
+impl ResponderResult {
+    fn new(responder: &WebhookResponder) -> Self {
+        ResponderResult {
+            responder: responder.to_string(),
+            alert: None,
+            escalation: None,
+            error: None,
+            already_called: false,
+        }
+    }
+
+    fn failed(responder: &WebhookResponder, error: String) -> Self {
+        ResponderResult {
+            error: Some(error),
+            ..ResponderResult::new(responder)
+        }
+    }
+}
+
+/// Schedules and teams that were called for an action on an alert.
+///
+/// OpsGenie delivers a webhook again if it doesn't get an answer in time, which must not ring
+/// the same people twice. Escalation policies don't need this, an escalation that is running
+/// already is not started again. Alerts are forgotten once they are acknowledged or closed.
+#[derive(Debug, Clone, Default)]
+pub struct CalledResponders {
+    called: Arc<Mutex<HashMap<CalledResponder, Instant>>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct CalledResponder {
+    alert_id: String,
+    action: String,
+    responder_id: String,
+}
+
+impl CalledResponders {
+    /// Remember the responder as called, returns `false` if it was already
+    fn first_call(&self, responder: &CalledResponder) -> bool {
+        let now = Instant::now();
+        let mut called = self.lock();
+        called.retain(|_, called_at| now.duration_since(*called_at) < CALLED_RESPONDER_TTL);
+        match called.entry(responder.clone()) {
+            Entry::Occupied(_) => false,
+            Entry::Vacant(entry) => {
+                entry.insert(now);
+                true
+            }
+        }
+    }
+
+    /// Calling the responder failed, so the next delivery may try again
+    fn forget(&self, responder: &CalledResponder) {
+        self.lock().remove(responder);
+    }
+
+    fn forget_alert(&self, alert_id: &str) {
+        self.lock()
+            .retain(|responder, _| responder.alert_id != alert_id);
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<CalledResponder, Instant>> {
+        self.called.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Call the responders of alerts that OpsGenie created or escalated, and stop calling once they
+/// are acknowledged or closed.
+///
+/// The alert exists in OpsGenie already, so none is created and it is never updated, OpsGenie
+/// knows about acknowledgements before we do.
+pub async fn handle(
+    state: &AppState,
+    config: &Config,
+    caller: &Caller,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    headers: &HeaderMap,
+    payload: WebhookPayload,
+) -> Result<WebhookResult, RequestError> {
+    caller
+        .require(Scope::TriggerAlert)
+        .context(request_error::AuthSnafu)?;
+    let alert = &payload.alert;
+    let alias = alert.alias().to_string();
+    tracing::info!(
+        caller.name,
+        action = payload.action,
+        alias,
+        priority = ?alert.priority,
+        "Got OpsGenie webhook"
+    );
+
+    let mut result = WebhookResult {
+        action: payload.action.clone(),
+        opsgenie_alias: alias.clone(),
+        ignored: None,
+        responders: Vec::new(),
+        escalations: Vec::new(),
+        delayed_calls_cancelled: false,
+    };
+    match plan(&payload, &config.opsgenie_config.webhook_priorities) {
+        WebhookAction::Call(priority) => {
+            let opsgenie_alert = OpsgenieAlert::Existing(alias.clone());
+            for responder in &alert.responders {
+                let Some(target) = responder.target() else {
+                    result.responders.push(ResponderResult::failed(
+                        responder,
+                        "only schedules, teams and escalation policies can be called".to_string(),
+                    ));
+                    continue;
+                };
+                let called_responder = CalledResponder {
+                    alert_id: alert.alert_id.clone(),
+                    action: payload.action.clone(),
+                    responder_id: responder.id.clone(),
+                };
+                if matches!(target, ResponderTarget::OnCall(_))
+                    && !state.called_responders.first_call(&called_responder)
+                {
+                    tracing::info!(alias, %responder, "Responder was already called");
+                    result.responders.push(ResponderResult {
+                        already_called: true,
+                        ..ResponderResult::new(responder)
+                    });
+                    continue;
+                }
+
+                let (action, audit_target) = match &target {
+                    ResponderTarget::OnCall(request) => {
+                        (AuditAction::Alert, AlertTarget::from(request))
+                    }
+                    ResponderTarget::Escalation(escalation) => (
+                        AuditAction::Escalate,
+                        AlertTarget::Escalation(escalation.clone()),
+                    ),
+                };
+                let mut audit_event =
+                    AuditEvent::new(action, caller, connect_info, headers, &audit_target);
+                audit_event.opsgenie_alert(&alias);
+
+                let mut responder_result = ResponderResult::new(responder);
+                let call_result = match target {
+                    ResponderTarget::OnCall(request) => {
+                        let parameters = AlertParameters {
+                            message: alert.message.clone(),
+                            dedup_key: None,
+                            priority: Some(priority),
+                        };
+                        trigger_alert(
+                            state,
+                            config,
+                            caller,
+                            &request,
+                            &parameters,
+                            &opsgenie_alert,
+                            &mut audit_event,
+                        )
+                        .await
+                        .map(|response| responder_result.alert = Some(response))
+                    }
+                    ResponderTarget::Escalation(escalation) => {
+                        let parameters = EscalationParameters {
+                            message: alert.message.clone(),
+                            dedup_key: None,
+                            priority: Some(priority),
+                        };
+                        start_escalation(
+                            state,
+                            config,
+                            caller,
+                            &escalation,
+                            parameters,
+                            &opsgenie_alert,
+                            &mut audit_event,
+                        )
+                        .await
+                        .map(|status| responder_result.escalation = Some(status))
+                    }
+                };
+
+                audit_event.finish(&call_result);
+                state.audit_log.record(audit_event, config).await;
+                // One responder that can't be called shouldn't keep the others from being called
+                if let Err(e) = call_result {
+                    state.called_responders.forget(&called_responder);
+                    responder_result.error = Some(e.to_string());
+                }
+                result.responders.push(responder_result);
+            }
+        }
+        WebhookAction::Stop(update) => {
+            let escalations = state.escalations.acknowledge_alert(&alias);
+            let delayed_calls_cancelled = state.delayed_calls.cancel_alert(&alias);
+            state.called_responders.forget_alert(&alert.alert_id);
+            // OpsGenie sends every acknowledgement, only those that stopped calls are recorded
+            if !escalations.is_empty() || delayed_calls_cancelled {
+                tracing::info!(alias, ?update, "Stopped calls for OpsGenie alert");
+                let action = match update {
+                    AlertUpdate::Close => AuditAction::Close,
+                    _ => AuditAction::Acknowledge,
+                };
+                let mut audit_event =
+                    AuditEvent::for_opsgenie_alert(action, caller, connect_info, headers, &alias);
+                if !escalations.is_empty() {
+                    audit_event.escalation(&escalation_keys(&escalations));
+                }
+                audit_event.finish(&Ok::<_, RequestError>(()));
+                state.audit_log.record(audit_event, config).await;
+            }
+
+            result.escalations = escalations;
+            result.delayed_calls_cancelled = delayed_calls_cancelled;
+            if should_mask(config, caller) {
+                result
+                    .escalations
+                    .iter_mut()
+                    .for_each(MaskPhoneNumbers::mask_phone_numbers);
+            }
+        }
+        WebhookAction::Ignore(reason) => {
+            tracing::info!(alias, reason, "Ignoring OpsGenie webhook");
+            result.ignored = Some(reason);
+        }
+    }
+    Ok(result)
+}
+
+fn plan(payload: &WebhookPayload, priorities: &HashSet<AlertPriority>) -> WebhookAction {
+    let alert = &payload.alert;
+    match payload.action.as_str() {
+        "Create" | "Escalate" | "EscalateNext" => {
+            // People are already being called for alerts that we created, the webhook would ring
+            // them a second time
+            if alert.source.as_deref() == Some(ALERT_SOURCE) {
+                return WebhookAction::Ignore(format!("alert was created by {ALERT_SOURCE}"));
+            }
+            let priority = match alert.priority {
+                Some(priority) if priorities.contains(&priority) => priority,
+                Some(priority) => {
+                    return WebhookAction::Ignore(format!(
+                        "priority {priority:?} is not in WYGC_OPSGENIE_WEBHOOK_PRIORITIES"
+                    ))
+                }
+                None => return WebhookAction::Ignore("alert has no priority".to_string()),
+            };
+            if alert.responders.is_empty() {
+                return WebhookAction::Ignore("alert has no responders".to_string());
+            }
+            WebhookAction::Call(priority)
+        }
+        "Acknowledge" => WebhookAction::Stop(AlertUpdate::Acknowledge),
+        "Close" => WebhookAction::Stop(AlertUpdate::Close),
+        other => WebhookAction::Ignore(format!("action {other} is not acted upon")),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{handle, plan, ResponderTarget, WebhookAction, WebhookPayload, WebhookResult};
+    use crate::auth::{AuthMethod, Caller, Scope};
+    use crate::fake_api::FakeApi;
+    use crate::opsgenie::{AlertPriority, AlertUpdate};
+    use crate::{
+        AppState, Escalation, EscalationRequestById, OnCallRequest, Team, TeamRequestById,
+    };
+    use axum::http::{HeaderMap, Method, StatusCode};
+    use rstest::rstest;
+    use serde_json::{json, Value};
+    use std::collections::HashSet;
+
+    fn payload(action: &str, priority: &str, source: &str) -> WebhookPayload {
+        serde_json::from_value(serde_json::json!({
+            "action": action,
+            "alert": {
+                "alertId": "70413a06-38d6-4c85-92b8-5ebc900d42e2-1489678337011",
+                "alias": "disk-full-db01",
+                "message": "Disk of db01 is full",
+                "tinyId": "1791",
+                "priority": priority,
+                "source": source,
+                "responders": [
+                    {"id": "4513b7ea-3b91-438f-b7e4-e3e54af9147c", "type": "team", "name": "ops"},
+                    {"id": "9f2c4d1e-0b3a-4c5d-8e7f-1a2b3c4d5e6f", "type": "escalation", "name": "ops_escalation"},
+                    {"id": "1c9e8f2a-7b6d-4e5c-9a8b-3f2e1d0c9b8a", "type": "user", "name": "jane@example.com"}
+                ]
+            },
+            "source": {"name": "", "type": "API"},
+            "integrationName": "whoyougonnacall",
+            "integrationType": "Webhook"
+        }))
+        .unwrap()
+    }
+
+    #[rstest]
+    #[case("Create", "P1", "Prometheus", WebhookAction::Call(AlertPriority::P1))]
+    #[case("Escalate", "P1", "Prometheus", WebhookAction::Call(AlertPriority::P1))]
+    #[case("Create", "P3", "Prometheus", WebhookAction::Ignore("priority P3 is not in WYGC_OPSGENIE_WEBHOOK_PRIORITIES".to_string()))]
+    #[case("Create", "P1", "whoyougonnacall", WebhookAction::Ignore("alert was created by whoyougonnacall".to_string()))]
+    #[case(
+        "Acknowledge",
+        "P3",
+        "whoyougonnacall",
+        WebhookAction::Stop(AlertUpdate::Acknowledge)
+    )]
+    #[case("Close", "P1", "Prometheus", WebhookAction::Stop(AlertUpdate::Close))]
+    #[case("AddNote", "P1", "Prometheus", WebhookAction::Ignore("action AddNote is not acted upon".to_string()))]
+    fn test_plan(
+        #[case] action: &str,
+        #[case] priority: &str,
+        #[case] source: &str,
+        #[case] expected: WebhookAction,
+    ) {
+        let priorities = HashSet::from([AlertPriority::P1, AlertPriority::P2]);
+        assert_eq!(
+            plan(&payload(action, priority, source), &priorities),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_plan_without_responders() {
+        let mut payload = payload("Create", "P1", "Prometheus");
+        payload.alert.responders.clear();
+        assert_eq!(
+            plan(&payload, &HashSet::from([AlertPriority::P1])),
+            WebhookAction::Ignore("alert has no responders".to_string())
+        );
+    }
+
+    #[test]
+    fn test_responder_targets() {
+        let payload = payload("Create", "P1", "Prometheus");
+        let targets = payload
+            .alert
+            .responders
+            .iter()
+            .map(|responder| responder.target())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            targets,
+            vec![
+                Some(ResponderTarget::OnCall(OnCallRequest::Team(
+                    Team::TeamById(TeamRequestById {
+                        team_id: "4513b7ea-3b91-438f-b7e4-e3e54af9147c".to_string()
+                    })
+                ))),
+                Some(ResponderTarget::Escalation(Escalation::EscalationById(
+                    EscalationRequestById {
+                        id: "9f2c4d1e-0b3a-4c5d-8e7f-1a2b3c4d5e6f".to_string()
+                    }
+                ))),
+                None,
+            ]
+        );
+        assert_eq!(payload.alert.alias(), "disk-full-db01");
+        assert_eq!(
+            payload.alert.responders[2].to_string(),
+            "user jane@example.com"
+        );
+    }
+
+    fn webhook(action: &str, alert_id: &str, responders: Value) -> WebhookPayload {
+        serde_json::from_value(json!({
+            "action": action,
+            "alert": {
+                "alertId": alert_id,
+                "alias": format!("alias-{alert_id}"),
+                "priority": "P1",
+                "source": "Prometheus",
+                "responders": responders,
+            },
+        }))
+        .unwrap()
+    }
+
+    async fn deliver(state: &AppState, payload: WebhookPayload) -> WebhookResult {
+        let caller = Caller {
+            name: "opsgenie".to_string(),
+            method: AuthMethod::StaticToken,
+            scopes: Scope::all(),
+        };
+        let config = state.config.current();
+        handle(state, &config, &caller, None, &HeaderMap::new(), payload)
+            .await
+            .unwrap()
+    }
+
+    /// Calls the schedule right away and again after an hour
+    fn policy(api: &FakeApi, id: &str) {
+        let rule = |minutes: u64| {
+            json!({
+                "condition": "if-not-acked",
+                "notifyType": "default",
+                "delay": {"timeAmount": minutes, "timeUnit": "minutes"},
+                "recipient": {"type": "schedule", "id": "1c2d", "name": "ops"},
+            })
+        };
+        api.respond(
+            Method::GET,
+            &format!("/v2/escalations/{id}"),
+            StatusCode::OK,
+            json!({"data": {"id": id, "name": id, "rules": [rule(0), rule(60)]}}),
+        );
+    }
+
+    #[tokio::test]
+    async fn test_responders_are_called_once_per_action() {
+        let api = FakeApi::start().await;
+        api.on_call("1c2d", "ops", "jane", "+4915112345678");
+        api.accept_calls();
+        let state = AppState::for_tests(api.config());
+        let responders = json!([{"id": "1c2d", "type": "schedule", "name": "ops"}]);
+
+        let first = deliver(&state, webhook("Create", "a1", responders.clone())).await;
+        assert!(first.responders[0].alert.is_some());
+        let redelivered = deliver(&state, webhook("Create", "a1", responders.clone())).await;
+        assert!(redelivered.responders[0].already_called);
+        assert_eq!(api.dialed(), ["+4915112345678"]);
+
+        // Escalating the alert is meant to ring them again, once
+        deliver(&state, webhook("EscalateNext", "a1", responders.clone())).await;
+        deliver(&state, webhook("EscalateNext", "a1", responders.clone())).await;
+        assert_eq!(api.dialed().len(), 2);
+
+        // Once it was acknowledged, the alert may be escalated again
+        deliver(&state, webhook("Acknowledge", "a1", responders.clone())).await;
+        deliver(&state, webhook("EscalateNext", "a1", responders)).await;
+        assert_eq!(api.dialed().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_failed_calls_are_tried_again() {
+        let api = FakeApi::start().await;
+        api.on_call("1c2d", "ops", "jane", "+4915112345678");
+        api.respond_once(
+            Method::GET,
+            "/v2/schedules/1c2d/on-calls",
+            StatusCode::INTERNAL_SERVER_ERROR,
+            json!({"message": "internal error"}),
+        );
+        api.accept_calls();
+        let state = AppState::for_tests(api.config());
+        let responders = json!([{"id": "1c2d", "type": "schedule", "name": "ops"}]);
+
+        let failed = deliver(&state, webhook("Create", "a1", responders.clone())).await;
+        assert!(failed.responders[0].error.is_some());
+        let retried = deliver(&state, webhook("Create", "a1", responders)).await;
+        assert!(retried.responders[0].alert.is_some());
+        assert_eq!(api.dialed(), ["+4915112345678"]);
+    }
+
+    #[tokio::test]
+    async fn test_acknowledge_stops_every_escalation() {
+        let api = FakeApi::start().await;
+        policy(&api, "9a3f");
+        policy(&api, "7b1c");
+        api.on_call("1c2d", "ops", "jane", "+4915112345678");
+        api.accept_calls();
+        let state = AppState::for_tests(api.config());
+        let responders = json!([
+            {"id": "9a3f", "type": "escalation", "name": "ops_escalation"},
+            {"id": "7b1c", "type": "escalation", "name": "db_escalation"}
+        ]);
+
+        let created = deliver(&state, webhook("Create", "a1", responders.clone())).await;
+        let keys = created
+            .responders
+            .iter()
+            .map(|responder| responder.escalation.as_ref().unwrap().key.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(keys, ["alias-a1:9a3f", "alias-a1:7b1c"]);
+        // Delivering it again doesn't start anything new
+        let redelivered = deliver(&state, webhook("Create", "a1", responders.clone())).await;
+        assert!(redelivered
+            .responders
+            .iter()
+            .all(|responder| responder.error.is_none()));
+
+        let acknowledged = deliver(&state, webhook("Acknowledge", "a1", responders)).await;
+        assert_eq!(acknowledged.escalations.len(), 2);
+        assert!(state.escalations.status("alias-a1:9a3f").is_err());
+        assert!(state.escalations.status("alias-a1:7b1c").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_caller_limit_does_not_apply() {
+        let api = FakeApi::start().await;
+        api.on_call("1c2d", "ops", "jane", "+4915112345678");
+        api.on_call("5e6f", "db", "joe", "+4915187654321");
+        api.accept_calls();
+        let mut config = api.config();
+        config.rate_limit_config.per_caller = Some(1);
+        config.rate_limit_config.per_target = Some(1);
+        let state = AppState::for_tests(config);
+
+        for (alert_id, schedule) in [("a1", "1c2d"), ("a2", "5e6f"), ("a3", "1c2d")] {
+            let responders = json!([{"id": schedule, "type": "schedule"}]);
+            deliver(&state, webhook("Create", alert_id, responders)).await;
+        }
+        // Only the limit of the schedule applies
+        assert_eq!(api.dialed(), ["+4915112345678", "+4915187654321"]);
+    }
+}